@@ -1,7 +1,7 @@
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
-use quote::{quote, format_ident};
+use quote::{quote, format_ident, ToTokens};
 use syn::DeriveInput;
 use std::vec::Vec;
 use proc_macro2::{
@@ -13,17 +13,33 @@ const DATA_STR: &str = "data";
 const COLLECTED_STR: &str = "current";
 const TOTAL_STR: &str = "total";
 
+// every key that's allowed inside `#[command(...)]`
+const COMMAND_KEYS: [&str; 10] = [
+	"subdir", "rest", "socket", "data_return", "multipart", "files", "return_type", "no_main",
+	"fallback", "idempotent"
+];
+
 #[proc_macro_derive(Commands, attributes(parameters, data, command))]
 pub fn commands_derive(input: TokenStream) -> TokenStream {
-	let ast: DeriveInput = syn::parse(input).unwrap();
+	let ast = syn::parse_macro_input!(input as DeriveInput);
+
+	// any mistake in the attributes is reported as a compile error pointing
+	// at the offending tokens, instead of panicking inside the macro
+	expand_commands(&ast)
+		.unwrap_or_else(|err| err.to_compile_error())
+		.into()
+}
 
+fn expand_commands(ast: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
 	// only allowed to be used on enums
 	let name = &ast.ident;
 	let variants = match &ast.data {
 		syn::Data::Enum(enm) => {
 			&enm.variants
 		},
-		_ => panic!("Please only use this on enums")
+		_ => return Err(syn::Error::new_spanned(
+			&ast.ident, "`Commands` can only be derived for enums"
+		))
 	};
 
 	// use this config to track the parameters that were defined for each
@@ -37,8 +53,13 @@ pub fn commands_derive(input: TokenStream) -> TokenStream {
 		files_key: None,
 		return_type: None,
 		no_main: false,
+		fallback: false,
+		idempotent: false,
 	};
 
+	// the variant that holds any command string that isn't recognized
+	let mut fallback: Option<&Ident> = None;
+
 	// the `${Command}Notification` structs that are being made
 	let mut structs = Vec::new();
 	// the functions that are being built to convert a SocketResponse
@@ -50,7 +71,14 @@ pub fn commands_derive(input: TokenStream) -> TokenStream {
 	// to easily communicate with both rest api and socket
 	let mut main_fns = Vec::new();
 
+	// the `${Command}Request` structs, with their builder functions and
+	// the implementation that sends them through the APIClient
+	let mut requests = Vec::new();
+
 	let mut matches = Vec::new();
+	// the opposite of `matches`, to get the variant from a command string
+	let mut from_matches = Vec::new();
+	let mut command_strs = Vec::new();
 	let mut sock_fns = Vec::new();
 
 	for var in variants {
@@ -59,6 +87,10 @@ pub fn commands_derive(input: TokenStream) -> TokenStream {
 		// for every command
 		config.reset();
 
+		// `command` only affects the attributes that come after it,
+		// so keep track of whether we've already generated anything
+		let mut generated = false;
+
 		let ident = &var.ident;
 		let id = var.ident.to_string();
 
@@ -77,36 +109,46 @@ pub fn commands_derive(input: TokenStream) -> TokenStream {
 					s
 			});
 
-		// generates the command_string for this variant
-		// e.g. `RequestCommand::GetChats => "get-chats".to_owned()`
-		matches.push(quote!{
-			#name::#ident => #parsed.to_owned()
-		});
-
 		// this just changes the get-chats to get_chats so it can be used
 		// as the name of a function
 		let fn_name = parsed.replace("-", "_");
 		let struct_name =
 			format_ident!("{}Notification", ident.to_string());
+		let request_name =
+			format_ident!("{}Request", ident.to_string());
 
 		for i in var.attrs.iter() {
 			// the name, either `parameters`, `data`, or `command`
 			let path_name = i.path.segments[0].ident.to_string();
 
+			// skip over all the attributes that aren't ours (e.g. `serde`)
+			if !["parameters", "data", "command"].contains(&path_name.as_str()) {
+				continue;
+			}
+
 			// meta is simply a data structure which makes it easier
 			// for us to build the fns and structs we want
-			let meta = i.parse_meta()
-				.expect("Unable to parse params as meta");
+			let meta = i.parse_meta()?;
 
 			match path_name.as_str() {
 				// `parameters` defines the parameters that will be used
 				// when sending to the socket/REST API
-				"parameters" => if config.socket || config.rest {
+				"parameters" => {
+					generated = true;
+
+					// check the parameters even if nothing gets generated
+					// from them, so that typos don't go unnoticed
+					get_name_val_list(&meta)?;
+
+					if !(config.socket || config.rest) {
+						continue;
+					}
+
 					// get the function to send this to the socket, if
 					// the command didn't forbid creating it
 					if config.socket {
 						let sock_fn =
-							get_sock_fn(&meta, &fn_name, ident, name);
+							get_sock_fn(&meta, &fn_name, ident, name)?;
 						sock_fns.push(sock_fn);
 					}
 
@@ -114,38 +156,107 @@ pub fn commands_derive(input: TokenStream) -> TokenStream {
 					// if the command didn't forbid creating it
 					if config.rest {
 						let rest_fn =
-							get_rest_fn(&meta, &fn_name, &config);
+							get_rest_fn(&meta, &fn_name, &config)?;
 						rest_fns.push(rest_fn);
 					}
 
 
 					if !config.no_main {
-						let main_cmd = main_cmd(&meta, &fn_name, &config);
+						let (request, main_cmd) =
+							main_cmd(&meta, &fn_name, &request_name, &config)?;
+						requests.push(request);
 						main_fns.push(main_cmd);
 					}
 				},
 				// `data` defines the parameters of the data that
 				// will be sent from the socket as a notification
-				"data" if config.socket => {
+				"data" => {
+					generated = true;
+
+					get_name_val_list(&meta)?;
+
+					if !config.socket {
+						continue;
+					}
+
 					// generate the struct and the function to turn a
 					// SocketResponse into that struct
 					let (struct_gen, impl_gen) =
 						parse_data(
 							meta, &fn_name, &struct_name, name, &config
-						);
+						)?;
 					structs.push(struct_gen);
 					impls.push(impl_gen);
 				},
 				// `command` simply sets options for what the macro should
 				// do with the `data` and `parameters` attributes
-				"command" => {
-					config.set_from_meta(&meta);
+				_ => {
+					if generated {
+						return Err(syn::Error::new_spanned(
+							i,
+							"`command` must come before the `parameters` and `data` attributes"
+						));
+					}
+
+					config.set_from_meta(&meta)?;
 				},
-				&_ => ()
 			}
 		}
+
+		if config.fallback {
+			// the fallback holds the command string itself, so it needs
+			// exactly one field and can't be sent or received like the others
+			if !matches!(&var.fields, syn::Fields::Unnamed(f) if f.unnamed.len() == 1) {
+				return Err(syn::Error::new_spanned(
+					var, "the `fallback` variant must hold a single `String`, e.g. `Unknown(String)`"
+				));
+			}
+
+			if generated {
+				return Err(syn::Error::new_spanned(
+					var, "the `fallback` variant can't have `parameters` or `data`"
+				));
+			}
+
+			if fallback.replace(ident).is_some() {
+				return Err(syn::Error::new_spanned(
+					var, "only one variant can be the `fallback`"
+				));
+			}
+
+			continue;
+		}
+
+		// the generated `match`es name each variant without fields
+		if !matches!(var.fields, syn::Fields::Unit) {
+			return Err(syn::Error::new_spanned(
+				&var.fields, "`Commands` variants can't have fields"
+			));
+		}
+
+		// generates the command_string for this variant
+		// e.g. `RequestCommand::GetChats => "get-chats".to_owned()`
+		matches.push(quote!{
+			#name::#ident => #parsed.to_owned()
+		});
+
+		from_matches.push(quote!{
+			#parsed => Some(#name::#ident)
+		});
+
+		command_strs.push(parsed);
 	}
 
+	// unrecognized command strings are kept in the fallback variant if there
+	// is one, so that newer hosts don't break deserializing a SocketResponse
+	let (fallback_match, fallback_from) = match fallback {
+		Some(fb) => (
+			quote!{ #name::#fb(cmd) => cmd.to_owned(), },
+			quote!{ Some(#name::#fb(cmd.to_owned())) }
+		),
+		None => (quote!{}, quote!{ None })
+	};
+
 	// build the final thing
 	let gen = quote!{
 		use crate::error::*;
@@ -153,11 +264,41 @@ pub fn commands_derive(input: TokenStream) -> TokenStream {
 		impl #name {
 			pub fn command_string(&self) -> String {
 				match self {
-					#(#matches),*
+					#(#matches,)*
+					#fallback_match
+				}
+			}
+
+			pub fn from_command_string(cmd: &str) -> Option<#name> {
+				match cmd {
+					#(#from_matches,)*
+					_ => #fallback_from
 				}
 			}
 		}
 
+		// the command strings are (de)serialized with the same functions as above,
+		// so that they're only defined in one place
+		impl serde::Serialize for #name {
+			fn serialize<S: serde::Serializer>(
+				&self, serializer: S
+			) -> ::std::result::Result<S::Ok, S::Error> {
+				serializer.serialize_str(&self.command_string())
+			}
+		}
+
+		impl<'de> serde::Deserialize<'de> for #name {
+			fn deserialize<D: serde::Deserializer<'de>>(
+				deserializer: D
+			) -> ::std::result::Result<Self, D::Error> {
+				let cmd = <String as serde::Deserialize>::deserialize(deserializer)?;
+
+				#name::from_command_string(&cmd).ok_or_else(|| {
+					<D::Error as serde::de::Error>::unknown_variant(&cmd, &[#(#command_strs),*])
+				})
+			}
+		}
+
 		// we impl it for crate::...::SocketHandler since it's the one
 		// with the send_command function and the SplitSink to send it with.
 
@@ -169,6 +310,8 @@ pub fn commands_derive(input: TokenStream) -> TokenStream {
 
 		#(#structs)*
 
+		#(#requests)*
+
 		impl crate::socket::SocketResponse {
 			#(#impls)*
 		}
@@ -182,22 +325,22 @@ pub fn commands_derive(input: TokenStream) -> TokenStream {
 		}
 	};
 
-	gen.into()
+	Ok(gen)
 }
 
 fn get_sock_fn(
 	params: &syn::Meta, fn_name: &str, ident: &Ident, name: &Ident
-) -> proc_macro2::TokenStream {
+) -> syn::Result<proc_macro2::TokenStream> {
 	// `list` should be a list of all the key-value pairs in the
 	// parameter parentheses
-	let nvs = get_name_val_list(params);
+	let nvs = get_name_val_list(params)?;
 
 	let (values, inserts): (Vec<proc_macro2::TokenStream>, Vec<proc_macro2::TokenStream>) =
 		nvs.iter().map(|(path, param_type)| {
 			// path is the `chat` in `chat = "me"`
 			let path_str = path.to_string();
 
-			let fn_quote = if param_type.starts_with("Option<") {
+			let fn_quote = if param_type.value().starts_with("Option<") {
 				// do the `if let Some(_) = _` so that we only insert
 				// this value to the map if it is included
 				quote!{
@@ -217,23 +360,26 @@ fn get_sock_fn(
 
 			// since the type may be like `Option<String>`, we can't do it as
 			// an ident, we have to do it as a TokenStream
-			let type_stream: proc_macro2::TokenStream = param_type.parse()
-				.unwrap_or_else(|_| panic!("Unable to parse {} as TStream", param_type));
+			let type_stream = parse_type(param_type)?;
 
 			let val_quote = quote!{
 				#path: #type_stream
 			};
 
-			(val_quote, fn_quote)
-		}).unzip();
+			Ok((val_quote, fn_quote))
+		}).collect::<syn::Result<Vec<_>>>()?
+		.into_iter()
+		.unzip();
 
 	// fn_ident is an ident for the functionname
 	let fn_ident = Ident::new(fn_name, Span::call_site());
+	let request_ident = Ident::new(&format!("request_{}", fn_name), Span::call_site());
 
-	// the function itself
-	quote!{
+	// the function itself, and one that waits for the response (e.g.
+	// `request_get_messages`), which the `APIClient` functions use
+	Ok(quote!{
 		pub async fn #fn_ident(
-			&mut self,
+			&self,
 			#(#values),*
 		) -> ::std::result::Result<
 			::std::string::String,
@@ -244,19 +390,32 @@ fn get_sock_fn(
 
 			self.send_command(#name::#ident, map.into()).await
 		}
-	}
+
+		pub async fn #request_ident(
+			&self,
+			#(#values),*
+		) -> ::std::result::Result<
+			crate::socket::PendingReply,
+			tokio_tungstenite::tungstenite::Error
+		> {
+			let mut map = serde_json::Map::new();
+			#(#inserts);*
+
+			self.request(#name::#ident, map.into()).await
+		}
+	})
 }
 
 fn get_rest_fn(
 	params: &syn::Meta,
 	fn_name: &str,
 	config: &CommandConfig
-) -> proc_macro2::TokenStream {
+) -> syn::Result<proc_macro2::TokenStream> {
 	// this builds the function for the REST API to communicate with whatever part
 	// of the API is specified in the parameters
 
 	// this contains the name-value pairs of the attribute, easily parseable
-	let mut nvs = get_name_val_list(params);
+	let mut nvs = get_name_val_list(params)?;
 	let fn_ident = Ident::new(fn_name, Span::call_site());
 
 	// the functions for a multipart form and a GET request look dramatically
@@ -264,12 +423,14 @@ fn get_rest_fn(
 	if config.multipart {
 		// if they specified a key to be used as the files in the multipart...
 		if let Some(ref fs_key) = config.files_key {
-			// check if it exists in the parameters
-			let pos = nvs.iter().position(|p| p.0.to_string() == *fs_key);
-			// if it does, remove it so that we don't set it as another parameter
-			// for the generated function
-			if let Some(p) = pos {
-				nvs.remove(p);
+			// check if it exists in the parameters, and if it does, remove it
+			// so that we don't set it as another parameter for the generated function
+			match nvs.iter().position(|p| *p.0 == fs_key.value()) {
+				Some(p) => { nvs.remove(p); },
+				None => return Err(syn::Error::new_spanned(
+					fs_key,
+					format!("`{}` is not one of this command's parameters", fs_key.value())
+				)),
 			}
 		}
 
@@ -279,8 +440,8 @@ fn get_rest_fn(
 			quote!{
 				let mut form = reqwest::multipart::Form::new();
 
-				if let Some(fil) = files.map(|fil| 
-					fil.into_iter().filter_map(|f| std::fs::read(f).ok()).collect::<Vec<Vec<u8>>>()
+				if let Some(fil) = files.as_ref().map(|fil|
+					fil.iter().filter_map(|f| std::fs::read(f).ok()).collect::<Vec<Vec<u8>>>()
 				) {
 					for data in fil {
 						let part = reqwest::multipart::Part::bytes(data);
@@ -304,29 +465,31 @@ fn get_rest_fn(
 				let key_str = key.to_string();
 
 				// once again, do special parsing to accomodate for
-				// Options since I use them so much
-				let push = if typ.starts_with("Option<") {
+				// Options since I use them so much. These are only borrowed,
+				// since the form may have to be built again to retry it
+				let push = if typ.value().starts_with("Option<") {
 					quote!{
-						if let Some(val) = #key {
-							form = form.text(#key_str, val);
+						if let Some(val) = &#key {
+							form = form.text(#key_str, val.to_string());
 						}
 					}
 				} else {
 					quote!{
-						form = form.text(#key_str, #key);
+						form = form.text(#key_str, #key.to_string());
 					}
 				};
 
-				let type_ident: proc_macro2::TokenStream =
-					typ.parse().unwrap();
+				let type_ident = parse_type(typ)?;
 
 				// make the code that shows the type
 				let type_quote = quote!{
 					#key: #type_ident
 				};
 
-				(push, type_quote)
+				Ok((push, type_quote))
 		})
+		.collect::<syn::Result<Vec<_>>>()?
+		.into_iter()
 		.unzip();
 
 		// also make the parameter that defines the files, if we didn't already
@@ -351,31 +514,32 @@ fn get_rest_fn(
 		};
 
 		// the result!
-		quote!{
+		Ok(quote!{
 			pub async fn #fn_ident(
-				&mut self,
+				&self,
 				#(#values),*
 			) -> anyhow::Result<()> {
-				self.check_auth().await?;
-
 				// the code that creates the request url
 				#req_str
 
-				// the code that creates the multipart form
-				// and adds the data from the included files into it
-				#form_quote
+				// this builds the request, and may be called again if we
+				// have to authenticate again before retrying it
+				let build = |client: &reqwest::Client| {
+					// the code that creates the multipart form
+					// and adds the data from the included files into it
+					#form_quote
 
-				// the code that adds the data into the form
-				#(#add_quotes);*
+					// the code that adds the data into the form
+					#(#add_quotes);*
 
-				self.client.post(&req_str)
-					.multipart(form)
-					.send()
-					.await?;
+					client.post(&req_str).multipart(form)
+				};
+
+				self.send_authed(build).await?;
 
 				Ok(())
 			}
-		}
+		})
 
 	} else {
 		// this part is for sending a GET request using a URL Query string
@@ -396,16 +560,19 @@ fn get_rest_fn(
 				};
 
 				// this makes the code that actually adds them to the
-				// query string.
+				// query string. The values are percent-encoded, since e.g. a
+				// search for `a&b #1` would otherwise end the value early.
 				// Once again, special parsing for options
-				let fn_quote = if param_type.starts_with("Option<") {
+				let fn_quote = if param_type.value().starts_with("Option<") {
 					quote!{
 						query_string = format!("{}{}{}{}",
 							query_string,
 							#add_char,
 							#path_str,
 							if let Some(v) = #path {
-								format!("={}", v.to_string())
+								format!("={}", percent_encoding::utf8_percent_encode(
+									&v.to_string(), percent_encoding::NON_ALPHANUMERIC
+								))
 							} else {
 								"".to_owned()
 							});
@@ -417,7 +584,9 @@ fn get_rest_fn(
 							#add_char,
 							#path_str,
 							if #path.to_string().len() > 0 {
-								format!("={}", #path.to_string())
+								format!("={}", percent_encoding::utf8_percent_encode(
+									&#path.to_string(), percent_encoding::NON_ALPHANUMERIC
+								))
 							} else {
 								"".to_string()
 							});
@@ -425,15 +594,16 @@ fn get_rest_fn(
 				};
 
 				// get the type, since it can't be ident, etc
-				let type_stream: proc_macro2::TokenStream = param_type.parse()
-					.unwrap_or_else(|_| panic!("Can't parse {} as TStream", param_type));
+				let type_stream = parse_type(param_type)?;
 
 				let val_quote = quote!{
 					#path: #type_stream
 				};
 
-				(val_quote, fn_quote)
-			}).unzip();
+				Ok((val_quote, fn_quote))
+			}).collect::<syn::Result<Vec<_>>>()?
+			.into_iter()
+			.unzip();
 
 		let fn_ident = Ident::new(fn_name, Span::call_site());
 
@@ -446,8 +616,8 @@ fn get_rest_fn(
 				self.get_url_data(&query_string).await
 			};
 
-			// just say that it'll return a Vec<u8>, the easiest built-in way to handle that data
-			let ret_type = quote!{ Vec<u8> };
+			// the data along with whatever the response headers said about it
+			let ret_type = quote!{ crate::payload::BinaryPayload };
 
 			(get_quote, ret_type)
 		} else if let Some(typ) = &config.return_type {
@@ -458,7 +628,7 @@ fn get_rest_fn(
 				Ok(serde_json::from_value(val)?)
 			};
 
-			let ret_type = typ.parse().unwrap();
+			let ret_type = parse_type(typ)?;
 
 			(get_quote, ret_type)
 		} else {
@@ -482,9 +652,9 @@ fn get_rest_fn(
 			.unwrap_or_default();
 
 		// final result!
-		quote!{
+		Ok(quote!{
 			pub async fn #fn_ident(
-				&mut self,
+				&self,
 				#(#values),*
 			) -> anyhow::Result<#ret_type> {
 				self.check_auth().await?;
@@ -494,13 +664,13 @@ fn get_rest_fn(
 
 				#get_quote
 			}
-		}
+		})
 	}
 }
 
 fn parse_data(
 	data: syn::Meta, fn_name: &str, struct_name: &Ident, enum_name: &Ident, config: &CommandConfig
-) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+) -> syn::Result<(proc_macro2::TokenStream, proc_macro2::TokenStream)> {
 	// this function manages parsing a JSON message from the websocket
 	// into a specified struct that we can use within the SDK
 
@@ -512,12 +682,12 @@ fn parse_data(
 
 	let nvs = if config.data_return {
 		vec![
-			(&data_ident, "String".to_owned()),
-			(&collect_ident, "u32".to_owned()),
-			(&total_ident, "u32".to_owned())
+			(&data_ident, syn::LitStr::new("String", Span::call_site())),
+			(&collect_ident, syn::LitStr::new("u32", Span::call_site())),
+			(&total_ident, syn::LitStr::new("u32", Span::call_site()))
 		]
 	} else {
-		get_name_val_list(&data)
+		get_name_val_list(&data)?
 	};
 
 	let nvs_len = nvs.len();
@@ -526,8 +696,7 @@ fn parse_data(
 	// `serials` contains a vector of the 
 	let (values, serials): (Vec<proc_macro2::TokenStream>, Vec<proc_macro2::TokenStream>) =
 		nvs.into_iter().map(|(path, param_type)| {
-			let type_stream: proc_macro2::TokenStream = param_type.parse()
-				.unwrap_or_else(|_| panic!("Unable to parse {} as TStream", param_type));
+			let type_stream = parse_type(&param_type)?;
 
 			// e.g. `percentage: String`
 			let type_quote = quote!{
@@ -567,8 +736,10 @@ fn parse_data(
 
 			paths.push(path);
 
-			(val_quote, ser_quote)
-		}).unzip();
+			Ok((val_quote, ser_quote))
+		}).collect::<syn::Result<Vec<_>>>()?
+		.into_iter()
+		.unzip();
 
 	// the TokenStream for the struct definition
 	let struct_quote = quote!{
@@ -597,18 +768,18 @@ fn parse_data(
 		}
 	};
 
-	(struct_quote, impl_quote)
+	Ok((struct_quote, impl_quote))
 }
 
 fn main_cmd(
-	meta: &syn::Meta, fn_name: &str, config: &CommandConfig
-) -> proc_macro2::TokenStream {
-	let nvs = get_name_val_list(meta);
+	meta: &syn::Meta, fn_name: &str, request_name: &Ident, config: &CommandConfig
+) -> syn::Result<(proc_macro2::TokenStream, proc_macro2::TokenStream)> {
+	let nvs = get_name_val_list(meta)?;
 
 	let types = nvs.iter().map(|(i, v)| {
-		let typ: proc_macro2::TokenStream = v.parse().unwrap();
-		quote!{ #i: #typ }
-	});
+		let typ = parse_type(v)?;
+		Ok(quote!{ #i: #typ })
+	}).collect::<syn::Result<Vec<_>>>()?;
 
 	let names: Vec<&Ident> = nvs.iter().map(|p| p.0).collect();
 
@@ -616,8 +787,8 @@ fn main_cmd(
 
 	let rest_section = match config.rest {
 		true => quote!{
-			self.rest_client.check_auth().await?;
-			return self.rest_client.#fn_ident(#(#names),*).await;
+			client.rest_client.check_auth().await?;
+			return client.rest_client.#fn_ident(#(#names),*).await;
 		},
 		_ => quote!{
 			return Err(SDKError::ConfigBlocked.into())
@@ -634,9 +805,9 @@ fn main_cmd(
 					Err(err) => Err(err.into())
 				}
 			},
-			match config.data_return {
-				true => quote!{ Vec<u8> },
-				_ => config.return_type.as_ref().unwrap().parse().unwrap(),
+			match (config.data_return, &config.return_type) {
+				(false, Some(typ)) => parse_type(typ)?,
+				_ => quote!{ crate::payload::BinaryPayload },
 			}
 		),
 		_ => (
@@ -645,75 +816,39 @@ fn main_cmd(
 		)
 	};
 
-	let receiving_section = match config.data_return {
-		true => quote!{
-			let mut current = 0;
-			let mut strings: Vec<String> = Vec::new();
-
-			while let Ok(msg) = receiver.recv() {
-				let json = match msg.data.as_object() {
-					Some(val) => val,
-					None => {
-						current = -1;
-						break;
-					}
-				};
-
-				let mut new_str = json[#DATA_STR].as_str()
-					.unwrap()
-					.to_string();
-
-				strings.push(new_str);
-
-				current += 1;
-				let total = match json[#TOTAL_STR].as_i64() {
-					Some(val) => val,
-					None => {
-						current = -1;
-						break;
-					}
-				};
-
-				if current == total {
-					break;
-				}
-			}
-
-			match current {
-				-1 => Err(SDKError::ImproperDataFormat.into()),
-				0 => Err(SDKError::MangledReceive.into()),
-				_ => match base64::decode(strings.join("")) {
-					Ok(data) => Ok(data),
-					Err(err) => Err(err.into())
-				}
-			}
-		},
-		_ => quote!{
-			match receiver.recv() {
-				Ok(msg) => #response,
-				_ => Err(SDKError::MangledReceive.into())
-			}
-		}
+	let (reply_binding, receiving_section) = match config.data_return {
+		true => (quote!{ reply }, quote!{
+			crate::api::APIClient::receive_data(reply).await
+		}),
+		_ => (quote!{ mut reply }, quote!{
+			let msg = reply.recv().await?;
+			#response
+		})
 	};
 
+	let request_ident = Ident::new(&format!("request_{}", fn_name), Span::call_site());
+
 	let sock_section = match config.socket {
 		true => if config.data_return || config.return_type.is_some() {
 			quote!{
-				let id = match self.socket.#fn_ident(#(#names),*).await {
-					Ok(id) => id,
+				// this is registered before the command is sent, and removes
+				// itself once it's dropped, whether it got a response or not
+				let #reply_binding = match client.socket.#request_ident(#(#names),*).await {
+					Ok(reply) => reply,
 					Err(err) => return Err(err.into())
 				};
 
-				let (sender, receiver) = crossbeam_channel::unbounded();
-
-				self.sock_msgs.insert(id, sender);
+				tracing::Span::current().record("id", reply.id.as_str());
 
 				#receiving_section
 			}
 		} else {
 			quote!{
-				match self.socket.#fn_ident(#(#names),*).await {
-					Ok(_) => Ok(()),
+				match client.socket.#fn_ident(#(#names),*).await {
+					Ok(id) => {
+						tracing::Span::current().record("id", id.as_str());
+						Ok(())
+					},
 					Err(err) => Err(err.into())
 				}
 			}
@@ -723,49 +858,278 @@ fn main_cmd(
 		}
 	};
 
-	quote!{
+	let request = request_struct(&nvs, request_name, fn_name)?;
+	let command_str = fn_name.replace('_', "-");
+
+	// only commands that are safe to send more than once are retried
+	let send_quote = match config.idempotent {
+		true => quote!{
+			async {
+				let policy = client.retry_policy.clone();
+				let mut attempt = 1;
+
+				loop {
+					match self.clone().send(client).await {
+						Err(err) => match policy.retry_delay(attempt, &err) {
+							Some(delay) => {
+								tracing::info!(
+									attempt, error = %err, delay_ms = delay.as_millis() as u64,
+									"retrying command"
+								);
+								tokio::time::sleep(delay).await;
+								attempt += 1;
+							},
+							None => break Err(err),
+						},
+						res => break res,
+					}
+				}
+			}
+		},
+		_ => quote!{ self.send(client) }
+	};
+
+	// the owned values that the positional function has to put in the request
+	let owned = nvs.iter().map(|(i, v)| match is_str_ref(v) {
+		true => quote!{ #i: #i.to_owned() },
+		_ => quote!{ #i }
+	});
+
+	// and the borrows that the request makes to call the socket & rest
+	// functions, since those take `&str` instead of `String`
+	let borrows = nvs.iter().filter(|(_, v)| is_str_ref(v)).map(|(i, _)| {
+		quote!{ let #i: &str = &#i; }
+	});
+
+	let request_quote = quote!{
+		#request
+
+		impl #request_name {
+			async fn send(
+				self,
+				client: &crate::api::APIClient
+			) -> anyhow::Result<#res_type> {
+				let #request_name { #(#names),* } = self;
+				#(#borrows)*
+
+				if client.uses_rest {
+					#rest_section
+				}
+
+				#sock_section
+			}
+		}
+
+		impl crate::api::APIRequest for #request_name {
+			type Response = #res_type;
+
+			async fn execute(
+				self,
+				client: &crate::api::APIClient
+			) -> anyhow::Result<#res_type> {
+				// the id is filled in once it's sent over the socket
+				let span = tracing::debug_span!(
+					"command",
+					command = #command_str,
+					rest = client.uses_rest,
+					id = tracing::field::Empty
+				);
+
+				let res = tracing::Instrument::instrument(#send_quote, span).await;
+
+				if let Err(err) = &res {
+					tracing::warn!(command = #command_str, error = %err, "command failed");
+				}
+
+				res
+			}
+		}
+	};
+
+	// the positional function is kept around as a shorthand for building
+	// the request and executing it
+	let main_quote = quote!{
 		pub async fn #fn_ident(
-			&mut self,
+			&self,
 			#(#types),*
 		) -> anyhow::Result<#res_type> {
-			if self.uses_rest {
-				#rest_section
-			}
+			self.execute(#request_name {
+				#(#owned),*
+			}).await
+		}
+	};
 
-			#sock_section
+	Ok((request_quote, main_quote))
+}
+
+fn request_struct(
+	nvs: &[(&Ident, syn::LitStr)], request_name: &Ident, fn_name: &str
+) -> syn::Result<proc_macro2::TokenStream> {
+	// the struct owns all of its values, so any `&str` parameter is
+	// turned into a `String`
+	let mut fields = Vec::new();
+	// the required parameters, which have to be passed into `new`
+	let mut new_params = Vec::new();
+	let mut new_values = Vec::new();
+	// the `with_${param}` functions to set each optional parameter
+	let mut setters = Vec::new();
+
+	for (name, typ) in nvs {
+		let parsed = typ.parse::<syn::Type>()?;
+
+		if is_str_ref(typ) {
+			fields.push(quote!{ pub #name: String });
+			new_params.push(quote!{ #name: impl Into<String> });
+			new_values.push(quote!{ #name: #name.into() });
+		} else if let Some(inner) = option_inner(&parsed) {
+			fields.push(quote!{ pub #name: #parsed });
+			new_values.push(quote!{ #name: None });
+
+			// only take `impl Into` for strings, since it would break
+			// type inference on integer literals
+			let inner_param = match inner.to_token_stream().to_string().as_str() {
+				"String" => quote!{ impl Into<String> },
+				_ => quote!{ #inner }
+			};
+
+			let setter = format_ident!("with_{}", name);
+			setters.push(quote!{
+				pub fn #setter(mut self, #name: #inner_param) -> Self {
+					self.#name = Some(#name.into());
+					self
+				}
+			});
+		} else {
+			fields.push(quote!{ pub #name: #parsed });
+			new_params.push(quote!{ #name: #parsed });
+			new_values.push(quote!{ #name });
 		}
 	}
+
+	let doc = format!(
+		"The parameters for the `{}` command, to be sent with `APIClient::execute`",
+		fn_name
+	);
+
+	Ok(quote!{
+		#[doc = #doc]
+		#[derive(Debug, Clone)]
+		pub struct #request_name {
+			#(#fields),*
+		}
+
+		impl #request_name {
+			pub fn new(#(#new_params),*) -> Self {
+				#request_name {
+					#(#new_values),*
+				}
+			}
+
+			#(#setters)*
+		}
+	})
 }
 
-fn get_name_val_list(data: &syn::Meta) -> Vec<(&Ident, String)> {
-	get_name_vals(data).iter()
-		.map(|p| {
-			let val = match p.1 {
-				syn::Lit::Str(v) => v.value(),
-				_ => panic!("Please only use on strings")
-			};
+// whether this parameter is a `&str`, which is stored as a `String` in the
+// generated request structs
+fn is_str_ref(typ: &syn::LitStr) -> bool {
+	matches!(
+		typ.parse::<syn::Type>(),
+		Ok(syn::Type::Reference(r)) if r.elem.to_token_stream().to_string() == "str"
+	)
+}
 
-			(p.0, val)
+// gets the `T` in `Option<T>`, if this type is an `Option`
+fn option_inner(typ: &syn::Type) -> Option<&syn::Type> {
+	let seg = match typ {
+		syn::Type::Path(path) => path.path.segments.last()?,
+		_ => return None
+	};
+
+	if seg.ident != "Option" {
+		return None;
+	}
+
+	match &seg.arguments {
+		syn::PathArguments::AngleBracketed(args) => match args.args.first()? {
+			syn::GenericArgument::Type(inner) => Some(inner),
+			_ => None
+		},
+		_ => None
+	}
+}
+
+fn get_name_val_list(data: &syn::Meta) -> syn::Result<Vec<(&Ident, syn::LitStr)>> {
+	get_name_vals(data)?.into_iter()
+		.map(|(id, lit)| match lit {
+			syn::Lit::Str(v) => Ok((id, v.clone())),
+			_ => Err(syn::Error::new_spanned(
+				lit, format!("expected the type of `{}` as a string, e.g. `{} = \"String\"`", id, id)
+			))
 		})
 		.collect()
 }
 
-fn get_name_vals(data: &syn::Meta) -> Vec<(&Ident, &syn::Lit)> {
-	if let syn::Meta::List(list) = data {
-		list.nested.iter()
-			.filter_map(|nv| {
-				if let syn::NestedMeta::Meta(syn::Meta::NameValue(pair)) = nv {
-					let id = &pair.path.segments.first()
-						.expect("Can't get first segment :(")
-						.ident;
+fn get_name_vals(data: &syn::Meta) -> syn::Result<Vec<(&Ident, &syn::Lit)>> {
+	let list = match data {
+		syn::Meta::List(list) => list,
+		_ => return Err(syn::Error::new_spanned(
+			data, "expected a list of `key = value` pairs"
+		))
+	};
 
-					Some((id, &pair.lit))
-				} else {
-					None
-				}
-			}).collect()
-	} else {
-		panic!("Only send meta lists to this function");
+	let mut pairs: Vec<(&Ident, &syn::Lit)> = Vec::new();
+
+	for nv in list.nested.iter() {
+		let pair = match nv {
+			syn::NestedMeta::Meta(syn::Meta::NameValue(pair)) => pair,
+			_ => return Err(syn::Error::new_spanned(
+				nv, "expected a `key = value` pair"
+			))
+		};
+
+		let id = pair.path.get_ident()
+			.ok_or_else(|| syn::Error::new_spanned(
+				&pair.path, "expected a single identifier as the key"
+			))?;
+
+		if pairs.iter().any(|(p, _)| *p == id) {
+			return Err(syn::Error::new_spanned(
+				id, format!("`{}` is specified more than once", id)
+			));
+		}
+
+		pairs.push((id, &pair.lit));
+	}
+
+	Ok(pairs)
+}
+
+// parses the string in e.g. `chats = "Option<u32>"` as a type, so that
+// an invalid type shows an error on the string instead of in the generated code
+fn parse_type(lit: &syn::LitStr) -> syn::Result<proc_macro2::TokenStream> {
+	lit.parse::<syn::Type>()
+		.map(|typ| typ.into_token_stream())
+		.map_err(|err| syn::Error::new_spanned(
+			lit, format!("`{}` is not a valid type: {}", lit.value(), err)
+		))
+}
+
+fn expect_str(key: &Ident, lit: &syn::Lit) -> syn::Result<syn::LitStr> {
+	match lit {
+		syn::Lit::Str(val) => Ok(val.clone()),
+		_ => Err(syn::Error::new(
+			lit.span(), format!("expected a string for `{}`", key)
+		))
+	}
+}
+
+fn expect_bool(key: &Ident, lit: &syn::Lit) -> syn::Result<bool> {
+	match lit {
+		syn::Lit::Bool(val) => Ok(val.value),
+		_ => Err(syn::Error::new(
+			lit.span(), format!("expected `true` or `false` for `{}`", key)
+		))
 	}
 }
 
@@ -775,9 +1139,11 @@ struct CommandConfig {
 	pub socket: bool,
 	pub data_return: bool,
 	pub multipart: bool,
-	pub files_key: Option<String>,
-	pub return_type: Option<String>,
+	pub files_key: Option<syn::LitStr>,
+	pub return_type: Option<syn::LitStr>,
 	pub no_main: bool,
+	pub fallback: bool,
+	pub idempotent: bool,
 }
 
 impl CommandConfig {
@@ -789,39 +1155,65 @@ impl CommandConfig {
 		self.files_key = None;
 		self.return_type = None;
 		self.no_main = false;
+		self.fallback = false;
+		self.idempotent = false;
 	}
 
-	pub fn set_from_meta(&mut self, meta: &syn::Meta) {
-		let nvs = get_name_vals(meta);
-
-		for (path_str, lit) in nvs.iter() {
-			match path_str.to_string().as_str() {
-				"subdir" => if let syn::Lit::Str(dir) = lit {
-					self.subdir = Some(dir.value());
-				},
-				"rest" => if let syn::Lit::Bool(rest) = lit {
-					self.rest = rest.value;
-				},
-				"socket" => if let syn::Lit::Bool(sock) = lit {
-					self.socket = sock.value;
-				},
-				"data_return" => if let syn::Lit::Bool(data) = lit {
-					self.data_return = data.value;
+	pub fn set_from_meta(&mut self, meta: &syn::Meta) -> syn::Result<()> {
+		let nvs = get_name_vals(meta)?;
+
+		for (key, lit) in nvs.into_iter() {
+			match key.to_string().as_str() {
+				"subdir" => self.subdir = Some(expect_str(key, lit)?.value()),
+				"rest" => self.rest = expect_bool(key, lit)?,
+				"socket" => self.socket = expect_bool(key, lit)?,
+				"data_return" => self.data_return = expect_bool(key, lit)?,
+				"multipart" => self.multipart = expect_bool(key, lit)?,
+				"files" => self.files_key = Some(expect_str(key, lit)?),
+				"return_type" => {
+					let typ = expect_str(key, lit)?;
+					parse_type(&typ)?;
+					self.return_type = Some(typ);
 				},
-				"multipart" => if let syn::Lit::Bool(mult) = lit {
-					self.multipart = mult.value;
-				},
-				"files" => if let syn::Lit::Str(fil) = lit {
-					self.files_key = Some(fil.value());
-				},
-				"return_type" => if let syn::Lit::Str(res) = lit {
-					self.return_type = Some(res.value())
-				},
-				"no_main" => if let syn::Lit::Bool(main) = lit {
-					self.no_main = main.value
-				},
-				&_ => ()
+				"no_main" => self.no_main = expect_bool(key, lit)?,
+				"fallback" => self.fallback = expect_bool(key, lit)?,
+				"idempotent" => self.idempotent = expect_bool(key, lit)?,
+				other => return Err(syn::Error::new_spanned(
+					key,
+					format!("unknown `command` key `{}`, expected one of: {}",
+						other, COMMAND_KEYS.join(", "))
+				))
 			}
 		}
+
+		self.validate(meta)
+	}
+
+	// makes sure that the options don't contradict each other, since
+	// otherwise one of them would just be silently ignored
+	fn validate(&self, meta: &syn::Meta) -> syn::Result<()> {
+		let err = |msg: &str| Err(syn::Error::new_spanned(meta, msg));
+
+		if self.files_key.is_some() && !self.multipart {
+			return err("`files` can only be used with `multipart = true`");
+		}
+
+		if self.data_return && self.return_type.is_some() {
+			return err("`data_return` and `return_type` can't be used together");
+		}
+
+		if self.multipart && (self.data_return || self.return_type.is_some()) {
+			return err("`multipart` commands can't return anything");
+		}
+
+		if self.idempotent && self.no_main {
+			return err("`idempotent` only applies to the APIClient function, but `no_main` is true");
+		}
+
+		if self.multipart && !self.rest {
+			return err("`multipart` only applies to the REST API, but `rest` is false");
+		}
+
+		Ok(())
 	}
 }
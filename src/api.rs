@@ -1,18 +1,45 @@
-use std::sync::Arc;
+use std::{
+	sync::Arc,
+	time::Duration,
+};
 use dashmap::DashMap;
 use crate::{
 	rest_api::RestAPIClient,
+	poller::Poller,
+	raw_command::*,
+	retry::RetryPolicy,
+	payload::BinaryPayload,
+	contacts::ContactResolver,
+	search::HostSearch,
 	socket::*,
 	config::*,
+	error::*,
 };
 use serde_json::json;
+use std::future::Future;
+use futures_util::StreamExt;
+
+// implemented by each `${Command}Request` struct that the `Commands` macro
+// generates, so that they can all be sent with `APIClient::execute`
+pub trait APIRequest {
+	type Response;
+
+	fn execute(
+		self, client: &APIClient
+	) -> impl Future<Output = anyhow::Result<Self::Response>> + Send;
+}
 
 pub struct APIClient {
 	pub rest_client: RestAPIClient,
 	pub socket: SocketHandler,
-	pub sock_msgs: Arc<DashMap<String, crossbeam_channel::Sender<SocketResponse>>>,
+	pub sock_msgs: Arc<DashMap<String, tokio::sync::mpsc::UnboundedSender<SocketResponse>>>,
 	pub uses_rest: bool,
 	pub chunk_size: usize,
+	pub poller: Option<tokio::task::JoinHandle<()>>,
+	pub retry_policy: RetryPolicy,
+	pub contacts: ContactResolver,
+	pub batch_concurrency: usize,
+	pub host_search: HostSearch,
 }
 
 impl APIClient {
@@ -20,10 +47,13 @@ impl APIClient {
 	// sock_msgs hashmap & notif_rec receiver into the spawn_receiver function
 	// of the socket handler.
 	//
-	// Then, every time the socket handler receives a new message,
-	// it automatically grabs the crossbeam_channel::Sender that relates to the id of the msg.
-	// It sends the socket response through the sender, which is received by
-	// the receiver who is awaiting a message.
+	// Before a command is sent, a `PendingReply` puts a
+	// tokio::sync::mpsc::UnboundedSender for its id into sock_msgs. Then, every
+	// time the socket handler receives a new message, it grabs the sender that
+	// relates to the id of the msg and sends the socket response through it,
+	// which is received by the `PendingReply` that's awaiting a message. The
+	// `PendingReply` takes the sender back out when it's dropped, so a request
+	// that times out doesn't leave it behind.
 	//
 	// If there is no sender, it just sends the data through an crossbeam_channel::Sender that
 	// the user will have passed in when they created this
@@ -32,42 +62,58 @@ impl APIClient {
 	// in this struct will look.
 	/*
 	pub async fn do_command(
-		&mut self, param: String
+		&self, param: String
 	) -> anyhow::Result<DoCommandResponse> {
 		if self.uses_rest {
 			return self.rest_client.do_command(param);
 		}
 
-		let id = self.socket.do_command(param).await?;
-		let (sender, receiver) = crossbeam_channel::unbounded();
+		let mut reply = self.socket.request_do_command(param).await?;
 
-		self.sock_msgs.insert(id, sender);
-
-		Ok(receiver.recv()?.do_command_data())
-
-		if let Ok(msg) = receiver.recv() {
-			let parsed_res: DoCommandResponse = msg.do_command_data();
-			Ok(parsed_res)
-			// or Ok(msg.do_command_data())
-		}
-
-		Err(SDKError::MangledReceive.into())
+		// errors if the host doesn't respond within `SDKConfig::timeout`
+		let msg = reply.recv().await?;
+		Ok(msg.do_command_data())
 	}
 	*/
 
 	pub async fn new(
 		config: SDKConfig, sender: crossbeam_channel::Sender<SocketResponse>
 	) -> anyhow::Result<APIClient> {
+		config.validate()?;
+
 		let chunk_size = config.chunk_size;
 		let uses_rest = config.use_rest;
 		let base_url = config.sock_base_url.to_owned();
+		let ping_interval = match config.ping_interval {
+			0 => None,
+			secs => Some(Duration::from_secs(secs as u64)),
+		};
+		let ping_timeout = Duration::from_secs(config.ping_timeout as u64);
+		let reply_timeout = Duration::from_secs(config.timeout as u64);
+
+		tracing::info!(rest = uses_rest, "connecting to SMServer");
 
 		// for now, we create the RestAPIClient even if we're not using rest.
 		// Should probably fix that up sooner or later.
 
-		let mut rest_client = RestAPIClient::new(config);
+		let poll_interval = config.poll_interval;
+		let retry_policy = config.retry.clone();
+		let contacts = ContactResolver::new(&config.contacts);
+		let batch_concurrency = config.batch_concurrency;
+		let rest_client = RestAPIClient::new(config.clone());
 		let sock_msgs = Arc::new(DashMap::new());
 
+		// the poller gets its own client so that it doesn't have to share this
+		// one while it runs in the background, but they share the same session
+		let poller = match (uses_rest, poll_interval) {
+			(true, Some(secs)) => Some(Poller::new(
+				RestAPIClient::with_session(config, rest_client.session.clone()),
+				sender.clone(),
+				Duration::from_secs(secs as u64)
+			)),
+			_ => None,
+		};
+
 		// parse the url since we need that for settings up the socket
 		let url = url::Url::parse(&base_url)?;
 
@@ -75,25 +121,141 @@ impl APIClient {
 			rest_client.check_auth().await?;
 		}
 
-		let socket = SocketHandler::new(url, sender, sock_msgs.clone()).await?;
+		let socket = SocketHandler::new(
+			url, sender, sock_msgs.clone(), ping_interval, ping_timeout, reply_timeout
+		).await?;
 
 		Ok(APIClient{
 			rest_client,
 			socket,
 			sock_msgs,
 			uses_rest,
-			chunk_size
+			chunk_size,
+			poller: poller.map(Poller::spawn),
+			retry_policy,
+			contacts,
+			batch_concurrency,
+			host_search: HostSearch::default(),
 		})
 	}
 
-	pub async fn authenticate(&mut self) -> anyhow::Result<bool> {
+	pub async fn authenticate(&self) -> anyhow::Result<bool> {
 		self.rest_client.authenticate().await
 	}
 
+	// sends a request built from one of the `${Command}Request` structs, e.g.
+	// `client.execute(GetMessagesRequest::new("chat").with_num_messages(50))`
+	pub async fn execute<R: APIRequest>(
+		&self, req: R
+	) -> anyhow::Result<R::Response> {
+		req.execute(self).await
+	}
+
+	// the state of the socket connection, which can be watched for changes
+	pub fn connection_state(&self) -> tokio::sync::watch::Receiver<ConnectionState> {
+		self.socket.state.clone()
+	}
+
+	// opens the socket again after it was disconnected
+	pub async fn reconnect(&mut self) -> anyhow::Result<()> {
+		self.socket.reconnect().await
+	}
+
+	// sends a command that the SDK may not know about yet, and returns whatever
+	// the host sent back. See `RawCommand` for more options, like which REST
+	// subdirectory to use or whether it returns data
+	pub async fn raw_command(
+		&self, name: impl Into<String>, params: serde_json::Value
+	) -> anyhow::Result<RawResponse> {
+		self.execute(RawCommand::new(name, params)).await
+	}
+
+	// the same as `raw_command`, but deserializes the JSON that was returned
+	// into whichever type the caller wants
+	pub async fn raw_command_as<T: serde::de::DeserializeOwned>(
+		&self, name: impl Into<String>, params: serde_json::Value
+	) -> anyhow::Result<T> {
+		self.raw_command(name, params).await?.parse()
+	}
+
+	// sends all of `requests`, `batch_concurrency` at a time, and returns each of
+	// their results in the same order that they were given in. One failing
+	// doesn't stop the rest from being sent. To mix different commands in one
+	// batch, use `RawCommand`s.
+	pub async fn batch<R: APIRequest>(
+		&self, requests: impl IntoIterator<Item = R>
+	) -> Vec<anyhow::Result<R::Response>> {
+		self.batch_with_concurrency(requests, self.batch_concurrency).await
+	}
+
+	pub async fn batch_with_concurrency<R: APIRequest>(
+		&self, requests: impl IntoIterator<Item = R>, concurrency: usize
+	) -> Vec<anyhow::Result<R::Response>> {
+		let requests: Vec<R> = requests.into_iter().collect();
+
+		tracing::debug!(count = requests.len(), concurrency, "sending batch");
+
+		// `buffered` (as opposed to `buffer_unordered`) keeps them in order
+		futures_util::stream::iter(requests)
+			.map(|req| req.execute(self))
+			.buffered(concurrency.max(1))
+			.collect()
+			.await
+	}
+
+	// `get_name`, but cached with `SDKConfig::contacts`. Concurrent lookups of
+	// the same address only send one request.
+	pub async fn resolve_name(&self, address: &str) -> anyhow::Result<String> {
+		self.contacts.name(address, self.get_name(address)).await
+	}
+
+	// `get_icon`, but cached the same way as `resolve_name`
+	pub async fn resolve_icon(&self, chat_id: &str) -> anyhow::Result<BinaryPayload> {
+		self.contacts.icon(chat_id, self.get_icon(chat_id)).await
+	}
+
+	// forgets the cached name and icon for this address or chat
+	pub fn invalidate_contact(&self, address: &str) {
+		self.contacts.invalidate(address);
+	}
+
+	// collects all the chunks of a data response (e.g. for get_attachment) that
+	// come through the socket, and decodes them into the original data
+	pub(crate) async fn receive_data(
+		mut reply: PendingReply
+	) -> anyhow::Result<BinaryPayload> {
+		let mut current = 0;
+		let mut chunks: Vec<serde_json::Value> = Vec::new();
+
+		// each chunk has `SDKConfig::timeout` to come in, rather than the
+		// whole thing, since big attachments can take a while
+		loop {
+			let msg = reply.recv().await?;
+
+			if !msg.data.is_object() {
+				return Err(SDKError::ImproperDataFormat.into());
+			}
+
+			current += 1;
+			let total = match msg.data.get("total").and_then(|t| t.as_i64()) {
+				Some(val) => val,
+				None => return Err(SDKError::ImproperDataFormat.into()),
+			};
+
+			chunks.push(msg.data);
+
+			tracing::debug!(chunk = current, total, "received data chunk");
+
+			if current >= total {
+				return BinaryPayload::from_chunks(&chunks);
+			}
+		}
+	}
+
 	// I custom-wrote a function for this since it's so complicated to send it
 	// over a socket
 	pub async fn send_message(
-		&mut self,
+		&self,
 		chat: String,
 		text: Option<String>,
 		subject: Option<String>,
@@ -153,7 +315,7 @@ impl APIClient {
 					json!({
 						"size": i.0,
 						"id": i.1,
-						"filename": a.split('/').last().unwrap_or(&a)
+						"filename": a.split('/').next_back().unwrap_or(&a)
 					})
 			})
 			.collect();
@@ -177,7 +339,7 @@ impl APIClient {
 			let id = &(i.0).1;
 
 			// iterate over how many messages will be needed to send the data
-			for idx in 0..=(len-1) {
+			for idx in 0..len {
 				// get the chunk. Drain it from the b64 vector so that we end up
 				// with an empty vector once we've sent the data
 				let chunk: String = data.drain(
@@ -186,10 +348,16 @@ impl APIClient {
 
 				// the chunk is already base64-encoded, so just
 				// send the data for this chunk
-				if let Err(_err) = self.socket.attachment_data(
+				match self.socket.attachment_data(
 					id, &msg_id, idx, &chunk
 				).await {
-					// Do something, I guess?? Well, maybe just suffer.
+					Ok(_) => tracing::debug!(
+						attachment = %id, chunk = idx + 1, total = len, "sent attachment chunk"
+					),
+					Err(err) => tracing::warn!(
+						attachment = %id, chunk = idx + 1, total = len, error = %err,
+						"failed to send attachment chunk"
+					),
 				}
 			}
 		}
@@ -197,3 +365,11 @@ impl APIClient {
 		Ok(())
 	}
 }
+
+impl Drop for APIClient {
+	fn drop(&mut self) {
+		if let Some(poller) = self.poller.take() {
+			poller.abort();
+		}
+	}
+}
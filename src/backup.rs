@@ -0,0 +1,606 @@
+use std::{
+	collections::{BTreeMap, HashSet},
+	io::{BufRead, Write},
+	path::{Path, PathBuf},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::{
+	api::{APIClient, APIRequest},
+	commands::{GetChatsRequest, GetMessagesRequest, GetPhotosRequest},
+	downloads::{DirLayout, DownloadItem, DownloadManager},
+	models::{Conversation, Message},
+};
+
+// the version of the archive layout that this SDK writes. Archives from newer
+// versions are refused, instead of being half-understood and overwritten.
+pub const ARCHIVE_VERSION: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+const CONVERSATIONS: &str = "conversations.json";
+const PAGE_SIZE: u32 = 100;
+
+// An archive is a directory like
+//
+// manifest.json           what's in the archive, and the checksum of every file
+// conversations.json      the result of `get_chats`, as of the last backup
+// messages/${chat}.jsonl  every message in the chat, oldest first, one per line
+// attachments/${chat}/    what `DownloadManager` saves with `DirLayout::PerChat`
+// photos/                 the photos from `get_photos`
+//
+// where `${chat}` in `messages/` is the hex-encoded chat identifier.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Manifest {
+	pub version: u32,
+	pub created_at: DateTime<Utc>,
+	pub updated_at: DateTime<Utc>,
+	pub runs: Vec<BackupRun>,
+	pub chats: BTreeMap<String, ChatState>,
+	// the path of each file (relative to the archive) to its checksum
+	pub files: BTreeMap<String, FileEntry>,
+	// the url of each photo that's been saved, to where it was saved
+	pub photos: BTreeMap<String, String>,
+	// attachments that couldn't be downloaded, to be tried again next time
+	pub pending: Vec<DownloadItem>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ChatState {
+	// the date (as in `Message::date`) of the newest message that's been saved
+	pub newest_date: i64,
+	// the guids of the saved messages with that date, since more than one
+	// message can be sent at the same time
+	pub newest_guids: Vec<String>,
+	pub message_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FileEntry {
+	pub sha256: String,
+	pub size: u64,
+}
+
+// what a single backup did
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BackupRun {
+	pub started_at: DateTime<Utc>,
+	pub finished_at: DateTime<Utc>,
+	pub new_messages: u64,
+	pub new_attachments: u64,
+	pub new_photos: u64,
+	pub failed: u64,
+}
+
+// Backs up every conversation to an archive directory. The first run saves
+// everything; after that, only messages newer than the ones already saved
+// (and their attachments) are fetched, so it's cheap to run on a schedule.
+pub struct Backup<'a> {
+	client: &'a APIClient,
+	dir: PathBuf,
+	attachments: bool,
+	photos: bool,
+}
+
+// the contents of a finished archive
+pub struct Archive {
+	dir: PathBuf,
+	pub manifest: Manifest,
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+	pub checked: usize,
+	// files that are in the manifest but not in the archive
+	pub missing: Vec<String>,
+	// files whose checksum or size doesn't match the manifest
+	pub corrupt: Vec<String>,
+}
+
+impl VerifyReport {
+	pub fn is_ok(&self) -> bool {
+		self.missing.is_empty() && self.corrupt.is_empty()
+	}
+}
+
+impl<'a> Backup<'a> {
+	pub fn new(client: &'a APIClient, dir: impl Into<PathBuf>) -> Self {
+		Backup {
+			client,
+			dir: dir.into(),
+			attachments: true,
+			photos: true,
+		}
+	}
+
+	pub fn with_attachments(mut self, attachments: bool) -> Self {
+		self.attachments = attachments;
+		self
+	}
+
+	pub fn with_photos(mut self, photos: bool) -> Self {
+		self.photos = photos;
+		self
+	}
+
+	// The files are written, synced and hashed on the blocking thread pool, so
+	// that a big attachment doesn't hold up everything else on the runtime.
+	pub async fn run(&self) -> anyhow::Result<BackupRun> {
+		let started_at = Utc::now();
+		tokio::fs::create_dir_all(&self.dir).await?;
+
+		let mut manifest = match tokio::fs::try_exists(self.dir.join(MANIFEST)).await? {
+			true => {
+				let dir = self.dir.clone();
+				blocking(move || Archive::open(dir)).await?.manifest
+			},
+			false => Manifest::new(started_at),
+		};
+
+		tracing::info!(dir = ?self.dir, runs = manifest.runs.len(), "starting backup");
+
+		let chats = self.fetch_chats().await?;
+		let data = serde_json::to_vec_pretty(&chats)?;
+		self.write_file(&mut manifest, CONVERSATIONS, data).await?;
+
+		let mut new_messages = 0;
+		let mut to_download = manifest.pending.clone();
+
+		for chat in chats.iter() {
+			let id = &chat.chat_identifier;
+			let state = manifest.chats.get(id).cloned().unwrap_or_default();
+			let messages = self.fetch_new_messages(id, &state).await?;
+
+			if messages.is_empty() {
+				continue;
+			}
+
+			tracing::debug!(chat = %id, count = messages.len(), "backing up new messages");
+
+			new_messages += messages.len() as u64;
+
+			if self.attachments {
+				to_download.extend(messages.iter()
+					.flat_map(DownloadItem::from_message)
+					.map(|item| item.with_chat(id.to_owned())));
+			}
+
+			// saved after each chat, so that if the backup fails partway through,
+			// the next one doesn't fetch this chat's messages again. If it fails
+			// before this, the next one fetches them again, and `append_messages`
+			// cuts off the copy that this one appended.
+			let (dir, chat) = (self.dir.clone(), id.to_owned());
+			manifest = blocking(move || {
+				append_messages(&dir, &mut manifest, &chat, &messages)?;
+				save_manifest(&dir, &manifest)?;
+				Ok(manifest)
+			}).await?;
+		}
+
+		let (new_attachments, failed) = self.download_attachments(&mut manifest, to_download).await?;
+
+		let (new_photos, failed_photos) = match self.photos {
+			true => self.backup_photos(&mut manifest).await?,
+			false => (0, 0),
+		};
+
+		let run = BackupRun {
+			started_at,
+			finished_at: Utc::now(),
+			new_messages,
+			new_attachments,
+			new_photos,
+			failed: failed + failed_photos,
+		};
+
+		manifest.updated_at = run.finished_at;
+		manifest.runs.push(run.clone());
+
+		let dir = self.dir.clone();
+		blocking(move || save_manifest(&dir, &manifest)).await?;
+
+		tracing::info!(
+			new_messages, new_attachments, new_photos, failed = run.failed, "finished backup"
+		);
+
+		Ok(run)
+	}
+
+	async fn fetch_chats(&self) -> anyhow::Result<Vec<Conversation>> {
+		let mut chats = Vec::new();
+
+		loop {
+			let page = GetChatsRequest::new()
+				.with_chats(PAGE_SIZE)
+				.with_chats_offset(chats.len() as u32)
+				.execute(self.client)
+				.await?;
+
+			let done = (page.len() as u32) < PAGE_SIZE;
+			chats.extend(page);
+
+			if done {
+				return Ok(chats);
+			}
+		}
+	}
+
+	// the messages that have been sent since the last backup, oldest first
+	async fn fetch_new_messages(&self, chat: &str, state: &ChatState) -> anyhow::Result<Vec<Message>> {
+		let mut messages = Vec::new();
+		let mut offset = 0;
+
+		let is_new = |m: &Message| m.date > state.newest_date
+			|| (m.date == state.newest_date && !state.newest_guids.contains(&m.guid));
+
+		loop {
+			let page = GetMessagesRequest::new(chat)
+				.with_num_messages(PAGE_SIZE)
+				.with_messages_offset(offset)
+				.with_read_messages(false)
+				.execute(self.client)
+				.await?;
+
+			offset += page.len() as u32;
+
+			// they're newest first, so once one is older than what we already
+			// have, the rest are too
+			let done = (page.len() as u32) < PAGE_SIZE
+				|| page.iter().any(|m| m.date < state.newest_date);
+
+			messages.extend(page.into_iter().filter(|m| is_new(m)));
+
+			if done {
+				break;
+			}
+		}
+
+		// a message could show up on two pages if one was sent while paging
+		let mut seen = HashSet::new();
+		messages.retain(|m| seen.insert(m.guid.to_owned()));
+
+		messages.sort_by_key(|m| m.date);
+		Ok(messages)
+	}
+
+	async fn download_attachments(
+		&self, manifest: &mut Manifest, items: Vec<DownloadItem>
+	) -> anyhow::Result<(u64, u64)> {
+		if items.is_empty() {
+			return Ok((0, 0));
+		}
+
+		let report = DownloadManager::new(self.client, self.dir.join("attachments"))
+			.with_layout(DirLayout::PerChat)
+			.with_concurrency(self.client.batch_concurrency)
+			.download(items)
+			.await;
+
+		let unhashed: Vec<(String, PathBuf)> = report.downloaded.iter()
+			.chain(report.skipped.iter())
+			.map(|path| (self.relative(path), path.to_owned()))
+			.filter(|(rel, _)| !manifest.files.contains_key(rel))
+			.collect();
+
+		let hashed = blocking(move || unhashed.into_iter()
+			.map(|(rel, path)| Ok((rel, hash_file(&path)?)))
+			.collect::<anyhow::Result<Vec<_>>>()
+		).await?;
+
+		manifest.files.extend(hashed);
+
+		let failed = report.failed.len() as u64;
+		manifest.pending = report.failed.into_iter().map(|(item, _)| item).collect();
+
+		Ok((report.downloaded.len() as u64, failed))
+	}
+
+	async fn backup_photos(&self, manifest: &mut Manifest) -> anyhow::Result<(u64, u64)> {
+		let mut urls = Vec::new();
+
+		loop {
+			let page = GetPhotosRequest::new()
+				.with_photos(PAGE_SIZE)
+				.with_photos_offset(urls.len() as u32)
+				.execute(self.client)
+				.await?;
+
+			let done = (page.len() as u32) < PAGE_SIZE;
+			urls.extend(page.into_iter().map(|p| p.url));
+
+			if done {
+				break;
+			}
+		}
+
+		urls.retain(|u| !manifest.photos.contains_key(u));
+		let (mut saved, mut failed) = (0, 0);
+
+		for url in urls {
+			let photo = match self.client.get_photo(&url).await {
+				Ok(photo) => photo,
+				Err(err) => {
+					tracing::warn!(url = %url, error = %err, "failed to back up photo");
+					failed += 1;
+					continue;
+				},
+			};
+
+			// the urls are paths on the phone, which could be anything, so the
+			// file is named after a hash of it instead
+			let mut name = hex(&Sha256::digest(url.as_bytes())[..8]);
+
+			if let Some(ext) = photo.extension() {
+				name = format!("{}.{}", name, ext);
+			}
+
+			let rel = format!("photos/{}", name);
+			self.write_file(manifest, &rel, photo.bytes).await?;
+			manifest.photos.insert(url, rel);
+			saved += 1;
+		}
+
+		Ok((saved, failed))
+	}
+
+	async fn write_file(&self, manifest: &mut Manifest, rel: &str, data: Vec<u8>) -> anyhow::Result<()> {
+		let path = self.dir.join(rel);
+
+		let entry = blocking(move || {
+			if let Some(parent) = path.parent() {
+				std::fs::create_dir_all(parent)?;
+			}
+
+			std::fs::write(&path, &data)?;
+
+			Ok(FileEntry {
+				sha256: hex(&Sha256::digest(&data)),
+				size: data.len() as u64,
+			})
+		}).await?;
+
+		manifest.files.insert(rel.to_owned(), entry);
+		Ok(())
+	}
+
+	// paths in the manifest always use `/`, so that archives can be moved between systems
+	fn relative(&self, path: &Path) -> String {
+		path.strip_prefix(&self.dir)
+			.unwrap_or(path)
+			.components()
+			.map(|c| c.as_os_str().to_string_lossy())
+			.collect::<Vec<_>>()
+			.join("/")
+	}
+}
+
+impl Manifest {
+	fn new(now: DateTime<Utc>) -> Manifest {
+		Manifest {
+			version: ARCHIVE_VERSION,
+			created_at: now,
+			updated_at: now,
+			runs: Vec::new(),
+			chats: BTreeMap::new(),
+			files: BTreeMap::new(),
+			photos: BTreeMap::new(),
+			pending: Vec::new(),
+		}
+	}
+}
+
+impl Archive {
+	pub fn open(dir: impl Into<PathBuf>) -> anyhow::Result<Archive> {
+		let dir = dir.into();
+		let manifest: Manifest = serde_json::from_slice(&std::fs::read(dir.join(MANIFEST))?)?;
+
+		if manifest.version > ARCHIVE_VERSION {
+			anyhow::bail!(
+				"archive is version {}, but only up to version {} is supported",
+				manifest.version, ARCHIVE_VERSION
+			);
+		}
+
+		Ok(Archive { dir, manifest })
+	}
+
+	// unpacks an archive that was packed with `pack` into `dir`, and opens it
+	pub fn unpack(tarball: impl AsRef<Path>, dir: impl Into<PathBuf>) -> anyhow::Result<Archive> {
+		let dir = dir.into();
+		let file = std::fs::File::open(tarball)?;
+
+		tar::Archive::new(flate2::read::GzDecoder::new(file)).unpack(&dir)?;
+
+		Archive::open(dir)
+	}
+
+	// packs the whole archive into a `.tar.gz`
+	pub fn pack(&self, tarball: impl AsRef<Path>) -> anyhow::Result<()> {
+		let file = std::fs::File::create(tarball)?;
+		let mut builder = tar::Builder::new(
+			flate2::write::GzEncoder::new(file, flate2::Compression::default())
+		);
+
+		builder.append_dir_all(".", &self.dir)?;
+		builder.into_inner()?.finish()?.sync_all()?;
+
+		Ok(())
+	}
+
+	pub fn dir(&self) -> &Path {
+		&self.dir
+	}
+
+	pub fn conversations(&self) -> anyhow::Result<Vec<Conversation>> {
+		Ok(serde_json::from_slice(&std::fs::read(self.dir.join(CONVERSATIONS))?)?)
+	}
+
+	// every message that's been backed up from this chat, oldest first
+	pub fn messages(&self, chat: &str) -> anyhow::Result<Vec<Message>> {
+		let path = self.dir.join(messages_path(chat));
+
+		if !path.exists() {
+			return Ok(Vec::new());
+		}
+
+		std::io::BufReader::new(std::fs::File::open(path)?)
+			.lines()
+			.map(|line| Ok(serde_json::from_str(&line?)?))
+			.collect()
+	}
+
+	// checks that every file in the manifest is there and hasn't changed
+	pub fn verify(&self) -> anyhow::Result<VerifyReport> {
+		let mut report = VerifyReport::default();
+
+		for (rel, expected) in self.manifest.files.iter() {
+			let path = self.dir.join(rel);
+			report.checked += 1;
+
+			if !path.exists() {
+				report.missing.push(rel.to_owned());
+				continue;
+			}
+
+			if &hash_file(&path)? != expected {
+				report.corrupt.push(rel.to_owned());
+			}
+		}
+
+		if !report.is_ok() {
+			tracing::warn!(
+				missing = report.missing.len(), corrupt = report.corrupt.len(), "archive failed verification"
+			);
+		}
+
+		Ok(report)
+	}
+}
+
+fn messages_path(chat: &str) -> String {
+	format!("messages/{}.jsonl", hex(chat.as_bytes()))
+}
+
+// Appends `messages` to the chat's file and updates the manifest to match. The
+// manifest has the size the file was when it was last saved, so anything after
+// that was appended by a backup that didn't get to save the manifest, and is
+// cut off before appending, instead of ending up in the file twice.
+fn append_messages(
+	dir: &Path, manifest: &mut Manifest, chat: &str, messages: &[Message]
+) -> anyhow::Result<()> {
+	let rel = messages_path(chat);
+	let path = dir.join(&rel);
+
+	if let Some(parent) = path.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+
+	let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+	let saved = manifest.files.get(&rel).map(|f| f.size).unwrap_or(0);
+
+	if file.metadata()?.len() > saved {
+		tracing::warn!(chat, "discarding messages that the last backup didn't finish saving");
+		file.set_len(saved)?;
+	}
+
+	for msg in messages {
+		serde_json::to_writer(&mut file, msg)?;
+		file.write_all(b"\n")?;
+	}
+
+	file.sync_all()?;
+	manifest.files.insert(rel, hash_file(&path)?);
+
+	let state = manifest.chats.entry(chat.to_owned()).or_default();
+	state.message_count += messages.len() as u64;
+
+	if let Some(newest) = messages.last().map(|m| m.date) {
+		if newest > state.newest_date {
+			state.newest_guids.clear();
+		}
+
+		state.newest_date = state.newest_date.max(newest);
+		state.newest_guids.extend(messages.iter()
+			.filter(|m| m.date == newest)
+			.map(|m| m.guid.to_owned()));
+	}
+
+	Ok(())
+}
+
+fn save_manifest(dir: &Path, manifest: &Manifest) -> anyhow::Result<()> {
+	// written to a temporary file first, so that a crash can't leave a half-written manifest
+	let tmp = dir.join(format!("{}.tmp", MANIFEST));
+	std::fs::write(&tmp, serde_json::to_vec_pretty(manifest)?)?;
+	std::fs::rename(tmp, dir.join(MANIFEST))?;
+
+	Ok(())
+}
+
+// runs `f` on the blocking thread pool
+async fn blocking<T: Send + 'static>(
+	f: impl FnOnce() -> anyhow::Result<T> + Send + 'static
+) -> anyhow::Result<T> {
+	tokio::task::spawn_blocking(f).await?
+}
+
+fn hash_file(path: &Path) -> std::io::Result<FileEntry> {
+	let mut hasher = Sha256::new();
+	let size = std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+
+	Ok(FileEntry {
+		sha256: hex(&hasher.finalize()),
+		size,
+	})
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn message(guid: &str, date: i64) -> Message {
+		let mut message = Message::typing("chat");
+		message.guid = guid.to_owned();
+		message.date = date;
+		message
+	}
+
+	fn guids(archive: &Archive) -> Vec<String> {
+		archive.messages("chat").unwrap().into_iter().map(|m| m.guid).collect()
+	}
+
+	#[test]
+	fn interrupted_appends_arent_duplicated() {
+		let dir = std::env::temp_dir()
+			.join(format!("smserver-backup-{}", uuid::Uuid::new_v4()));
+		std::fs::create_dir_all(&dir).unwrap();
+
+		let mut manifest = Manifest::new(Utc::now());
+		append_messages(&dir, &mut manifest, "chat", &[message("a", 1), message("b", 2)]).unwrap();
+		save_manifest(&dir, &manifest).unwrap();
+
+		// the backup crashes after appending, before saving the manifest
+		let mut unsaved = manifest.clone();
+		append_messages(&dir, &mut unsaved, "chat", &[message("c", 3)]).unwrap();
+
+		// so the next one fetches `c` again
+		let mut manifest = Archive::open(&dir).unwrap().manifest;
+		append_messages(&dir, &mut manifest, "chat", &[message("c", 3), message("d", 3)]).unwrap();
+		save_manifest(&dir, &manifest).unwrap();
+
+		let archive = Archive::open(&dir).unwrap();
+		assert_eq!(guids(&archive), vec!["a", "b", "c", "d"]);
+		assert!(archive.verify().unwrap().is_ok());
+
+		let state = &archive.manifest.chats["chat"];
+		assert_eq!(state.message_count, 4);
+		assert_eq!(state.newest_date, 3);
+		assert_eq!(state.newest_guids, vec!["c", "d"]);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,405 @@
+// A daemon that keeps a connection to SMServer open, POSTs every notification
+// (new messages, typing, battery status) to a list of webhooks, and serves a
+// small HTTP API so that other services can send messages and fetch chats
+// without linking the SDK themselves.
+//
+// Usage: smserver-bridge <bridge.toml>
+//
+// sdk_config = "/etc/smserver/sdk.toml"   # a normal SDKConfig file, which
+//                                         # `SMSERVER_*` variables still apply to
+// listen = "127.0.0.1:8787"
+// api_token = "..."                       # required as `Authorization: Bearer ...`,
+//                                         # or set SMSERVER_BRIDGE_API_TOKEN
+//
+// [[webhooks]]
+// url = "https://tools.internal/hooks/sms"
+// secret = "..."                          # signs each body, see `signature`
+// events = ["new-message"]                # leave out to get every event
+//
+// API:
+// GET  /health                       -> { "state": "Connected" }
+// GET  /chats?limit=&offset=         -> get_chats
+// GET  /chats/:chat/messages?limit=&offset=
+//                                    -> get_messages
+// POST /messages  { chat, text, subject }
+//                                    -> send_message
+// POST /tapbacks  { guid, tapback, remove }
+//                                    -> send_tapback
+
+use std::{
+	collections::HashMap,
+	convert::Infallible,
+	net::SocketAddr,
+	sync::Arc,
+	time::Duration,
+};
+use hmac::{Hmac, Mac};
+use hyper::{
+	body::HttpBody,
+	service::{make_service_fn, service_fn},
+	Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use smserver_rs_sdk::{
+	socket::{ConnectionState, Notifications},
+	APIClient, RetryPolicy, SDKConfig,
+};
+
+// the biggest request body that the API accepts
+const MAX_BODY: usize = 1024 * 1024;
+
+// how long to wait between attempts to reconnect the socket, doubling
+// after each one that fails
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct BridgeConfig {
+	sdk_config: Option<std::path::PathBuf>,
+	listen: String,
+	api_token: Option<String>,
+	webhooks: Vec<Webhook>,
+	// how deliveries to the webhooks are retried
+	retry: RetryPolicy,
+}
+
+#[derive(Deserialize, Clone)]
+struct Webhook {
+	url: String,
+	secret: Option<String>,
+	// the command strings of the events to send (e.g. `new-message`), or all if empty
+	#[serde(default)]
+	events: Vec<String>,
+}
+
+impl Default for BridgeConfig {
+	fn default() -> BridgeConfig {
+		BridgeConfig {
+			sdk_config: None,
+			listen: "127.0.0.1:8787".to_owned(),
+			api_token: None,
+			webhooks: Vec::new(),
+			retry: RetryPolicy::default(),
+		}
+	}
+}
+
+impl Webhook {
+	fn wants(&self, event: &str) -> bool {
+		self.events.is_empty() || self.events.iter().any(|e| e == event)
+	}
+}
+
+struct State {
+	// requests only need to read it, but reconnecting the socket needs it to
+	// itself, so `supervise` waits for the requests in progress to finish first
+	client: tokio::sync::RwLock<APIClient>,
+	api_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SendMessageBody {
+	chat: String,
+	text: Option<String>,
+	subject: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TapbackBody {
+	guid: String,
+	tapback: u16,
+	#[serde(default)]
+	remove: bool,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+	tracing_subscriber::fmt()
+		.with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+		.init();
+
+	let path = std::env::args().nth(1)
+		.ok_or_else(|| anyhow::anyhow!("usage: smserver-bridge <bridge.toml>"))?;
+
+	let config: BridgeConfig = toml::from_str(&std::fs::read_to_string(&path)?)?;
+
+	let sdk_config = match &config.sdk_config {
+		Some(file) => SDKConfig::from_file(file)?,
+		None => SDKConfig::default(),
+	}.with_env()?;
+
+	let api_token = config.api_token.or_else(|| std::env::var("SMSERVER_BRIDGE_API_TOKEN").ok());
+
+	if api_token.is_none() {
+		tracing::warn!("no api_token is set, so anyone who can reach the API can use it");
+	}
+
+	let (sender, receiver) = crossbeam_channel::unbounded();
+	let client = APIClient::new(sdk_config, sender).await?;
+
+	tokio::spawn(forward_notifications(
+		Notifications::new(receiver), config.webhooks, config.retry
+	));
+
+	let state = Arc::new(State { client: tokio::sync::RwLock::new(client), api_token });
+	let addr: SocketAddr = config.listen.parse()?;
+
+	tokio::spawn(supervise(state.clone()));
+
+	let make_svc = make_service_fn(move |_| {
+		let state = state.clone();
+		async move {
+			Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req)))
+		}
+	});
+
+	tracing::info!(%addr, "bridge listening");
+
+	Server::bind(&addr)
+		.serve(make_svc)
+		.with_graceful_shutdown(async {
+			let _ = tokio::signal::ctrl_c().await;
+			tracing::info!("shutting down");
+		})
+		.await?;
+
+	Ok(())
+}
+
+// reconnects the socket whenever it's disconnected, e.g. because SMServer restarted
+async fn supervise(state: Arc<State>) {
+	let mut conn_state = state.client.read().await.connection_state();
+
+	loop {
+		// only errors once the socket is gone for good
+		if conn_state.wait_for(|s| *s == ConnectionState::Disconnected).await.is_err() {
+			return;
+		}
+
+		let mut delay = RECONNECT_MIN;
+
+		loop {
+			tracing::info!("reconnecting to SMServer");
+
+			// the lock has to be let go before sleeping, so that requests can
+			// still fail fast (instead of waiting) while we're disconnected
+			let res = state.client.write().await.reconnect().await;
+
+			match res {
+				Ok(()) => break,
+				Err(err) => {
+					tracing::warn!(error = %err, delay_secs = delay.as_secs(), "failed to reconnect");
+					tokio::time::sleep(delay).await;
+					delay = (delay * 2).min(RECONNECT_MAX);
+				},
+			}
+		}
+	}
+}
+
+async fn forward_notifications(
+	mut receiver: Notifications,
+	webhooks: Vec<Webhook>,
+	retry: RetryPolicy,
+) {
+	let http = reqwest::Client::new();
+
+	while let Some(notif) = receiver.recv().await {
+		let event = notif.command.command_string();
+
+		let body = json!({
+			"event": event,
+			"id": notif.id,
+			"data": notif.data,
+			"sent_at": chrono::Utc::now().to_rfc3339(),
+		}).to_string();
+
+		// each delivery runs separately, so that a slow webhook doesn't hold up the rest
+		for hook in webhooks.iter().filter(|h| h.wants(&event)) {
+			tokio::spawn(deliver(
+				http.clone(), hook.clone(), event.to_owned(), body.to_owned(), retry.clone()
+			));
+		}
+	}
+}
+
+async fn deliver(
+	http: reqwest::Client, hook: Webhook, event: String, body: String, retry: RetryPolicy
+) {
+	let mut attempt = 1;
+
+	loop {
+		let timestamp = chrono::Utc::now().timestamp();
+		let mut req = http.post(&hook.url)
+			.header("Content-Type", "application/json")
+			.header("X-SMServer-Event", &event)
+			.body(body.to_owned());
+
+		if let Some(secret) = &hook.secret {
+			req = req.header("X-SMServer-Signature", signature(secret, timestamp, &body));
+		}
+
+		let err: anyhow::Error = match req.send().await.and_then(|r| r.error_for_status()) {
+			Ok(_) => return,
+			Err(err) => err.into(),
+		};
+
+		match retry.retry_delay(attempt, &err) {
+			Some(delay) => {
+				tracing::debug!(url = %hook.url, attempt, error = %err, "retrying webhook");
+				tokio::time::sleep(delay).await;
+				attempt += 1;
+			},
+			None => {
+				tracing::warn!(url = %hook.url, %event, error = %err, "failed to deliver webhook");
+				return;
+			},
+		}
+	}
+}
+
+// `t=${timestamp},sha256=${hmac}`, where the hmac is of `${timestamp}.${body}`.
+// The timestamp is included so that receivers can reject old deliveries that are replayed.
+fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+	let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+		.expect("HMAC accepts keys of any length");
+
+	mac.update(format!("{}.{}", timestamp, body).as_bytes());
+
+	let hex: String = mac.finalize()
+		.into_bytes()
+		.iter()
+		.map(|b| format!("{:02x}", b))
+		.collect();
+
+	format!("t={},sha256={}", timestamp, hex)
+}
+
+async fn handle(state: Arc<State>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+	if !authorized(&state, &req) {
+		return Ok(error(StatusCode::UNAUTHORIZED, "missing or incorrect api token"));
+	}
+
+	let res = route(&state, req).await.unwrap_or_else(|err| {
+		tracing::warn!(error = %err, "request failed");
+		error(StatusCode::BAD_GATEWAY, &err.to_string())
+	});
+
+	Ok(res)
+}
+
+async fn route(state: &State, req: Request<Body>) -> anyhow::Result<Response<Body>> {
+	let client = state.client.read().await;
+	let query: HashMap<String, String> = req.uri().query()
+		.map(|q| url::form_urlencoded::parse(q.as_bytes()).into_owned().collect())
+		.unwrap_or_default();
+
+	let num = |key: &str| query.get(key).and_then(|v| v.parse::<u32>().ok());
+
+	let segments: Vec<String> = req.uri().path()
+		.split('/')
+		.filter(|s| !s.is_empty())
+		.map(|s| percent_encoding::percent_decode_str(s).decode_utf8_lossy().into_owned())
+		.collect();
+	let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+	match (req.method(), segments.as_slice()) {
+		(&Method::GET, ["health"]) => {
+			let conn_state = *client.connection_state().borrow();
+			Ok(ok(json!({ "state": format!("{:?}", conn_state) })))
+		},
+		(&Method::GET, ["chats"]) => {
+			let chats = client.get_chats(num("limit"), num("offset")).await?;
+			Ok(ok(serde_json::to_value(chats)?))
+		},
+		(&Method::GET, ["chats", chat, "messages"]) => {
+			let messages = client.get_messages(chat, num("limit"), num("offset"), Some(false)).await?;
+			Ok(ok(serde_json::to_value(messages)?))
+		},
+		(&Method::POST, ["messages"]) => {
+			let body: SendMessageBody = match read_json(req).await {
+				Ok(body) => body,
+				Err(res) => return Ok(res),
+			};
+
+			client.send_message(body.chat, body.text, body.subject, None, None).await?;
+			Ok(ok(json!({ "sent": true })))
+		},
+		(&Method::POST, ["tapbacks"]) => {
+			let body: TapbackBody = match read_json(req).await {
+				Ok(body) => body,
+				Err(res) => return Ok(res),
+			};
+
+			client.send_tapback(&body.guid, body.tapback, Some(body.remove)).await?;
+			Ok(ok(json!({ "sent": true })))
+		},
+		_ => Ok(error(StatusCode::NOT_FOUND, "no such endpoint")),
+	}
+}
+
+fn authorized(state: &State, req: &Request<Body>) -> bool {
+	let token = match &state.api_token {
+		Some(token) => token,
+		None => return true,
+	};
+
+	let given = req.headers().get("Authorization")
+		.and_then(|h| h.to_str().ok())
+		.and_then(|h| h.strip_prefix("Bearer "))
+		.unwrap_or_default();
+
+	// compared without returning early, so that the time it takes doesn't
+	// give away how much of the token was right
+	given.len() == token.len() && given.bytes()
+		.zip(token.bytes())
+		.fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(req: Request<Body>) -> Result<T, Response<Body>> {
+	let too_big = req.headers().get("Content-Length")
+		.and_then(|h| h.to_str().ok())
+		.and_then(|h| h.parse::<usize>().ok())
+		.map(|len| len > MAX_BODY)
+		.unwrap_or(false);
+
+	if too_big {
+		return Err(error(StatusCode::PAYLOAD_TOO_LARGE, "body is too large"));
+	}
+
+	// read a frame at a time, since a chunked body doesn't say how big it is up front
+	let mut body = req.into_body();
+	let mut bytes = Vec::new();
+
+	while let Some(chunk) = body.data().await {
+		let chunk = chunk.map_err(|err| error(StatusCode::BAD_REQUEST, &err.to_string()))?;
+
+		if bytes.len() + chunk.len() > MAX_BODY {
+			return Err(error(StatusCode::PAYLOAD_TOO_LARGE, "body is too large"));
+		}
+
+		bytes.extend_from_slice(&chunk);
+	}
+
+	serde_json::from_slice(&bytes)
+		.map_err(|err| error(StatusCode::BAD_REQUEST, &err.to_string()))
+}
+
+fn ok(body: serde_json::Value) -> Response<Body> {
+	json_response(StatusCode::OK, body)
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+	json_response(status, json!({ "error": message }))
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+	Response::builder()
+		.status(status)
+		.header("Content-Type", "application/json")
+		.body(Body::from(body.to_string()))
+		.expect("the response is always valid")
+}
@@ -0,0 +1,307 @@
+use std::{
+	future::Future,
+	pin::Pin,
+	sync::Arc,
+	time::Duration,
+};
+use regex::Regex;
+use crate::{
+	api::APIClient,
+	commands::APICommand,
+	dispatch::Shutdown,
+	models::{Message, MessageType, Tapback},
+	socket::{Notifications, SocketResponse},
+};
+
+type HandlerFuture = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+type Handler = Arc<dyn Fn(Context) -> HandlerFuture + Send + Sync>;
+
+// what's passed to each handler: the message that it matched, and ways to respond to it
+#[derive(Clone)]
+pub struct Context {
+	pub client: Arc<APIClient>,
+	pub message: Message,
+	pub chat: String,
+	// for command routes, the name of the command that matched (e.g. `ping`
+	// for `!ping`), and the words after it
+	pub command: Option<String>,
+	pub args: Vec<String>,
+	// for regex routes, the capture groups (0 being the whole match)
+	pub captures: Vec<Option<String>>,
+}
+
+impl Context {
+	pub fn text(&self) -> &str {
+		&self.message.text
+	}
+
+	pub fn sender(&self) -> Option<&str> {
+		self.message.sender.as_deref()
+	}
+
+	// sends a message to the chat that this one came from
+	pub async fn reply(&self, text: impl Into<String>) -> anyhow::Result<()> {
+		self.client.send_message(self.chat.to_owned(), Some(text.into()), None, None, None).await
+	}
+
+	pub async fn react(&self, tapback: Tapback) -> anyhow::Result<()> {
+		self.client.send_tapback(&self.message.guid, tapback.index(), None).await
+	}
+
+	// shows (or hides) the typing indicator in this chat, e.g. while a slow handler runs
+	pub async fn typing(&self, active: bool) -> anyhow::Result<()> {
+		self.client.send_typing(&self.chat, active).await
+	}
+}
+
+// Hooks that run around every handler, e.g. for logging, rate limiting or
+// only letting certain senders use the bot.
+pub trait Middleware: Send + Sync {
+	// called before the handler; return false to skip it
+	fn before(&self, _ctx: &Context) -> bool {
+		true
+	}
+
+	// called with whatever the handler returned
+	fn after(&self, _ctx: &Context, _result: &anyhow::Result<()>) {}
+}
+
+#[derive(Clone)]
+enum Matcher {
+	Command(String),
+	Regex(Regex),
+	Any,
+}
+
+// which messages a handler gets
+#[derive(Clone)]
+pub struct Route {
+	matcher: Matcher,
+	// if set, only messages in these chats match
+	chats: Option<Vec<String>>,
+}
+
+impl Route {
+	// messages that start with the bot's prefix and then `name`, e.g. `!ping`
+	pub fn command(name: impl Into<String>) -> Route {
+		Route { matcher: Matcher::Command(name.into()), chats: None }
+	}
+
+	// messages whose text matches `regex` anywhere
+	pub fn regex(regex: Regex) -> Route {
+		Route { matcher: Matcher::Regex(regex), chats: None }
+	}
+
+	// every message; usually added last, as a fallback
+	pub fn any() -> Route {
+		Route { matcher: Matcher::Any, chats: None }
+	}
+
+	// only match messages in `chat`. Can be called more than once to allow more chats.
+	pub fn in_chat(mut self, chat: impl Into<String>) -> Self {
+		self.chats.get_or_insert_with(Vec::new).push(chat.into());
+		self
+	}
+
+	// fills in the parts of `ctx` that depend on the route, if it matches
+	fn matches(&self, prefix: &str, ctx: &mut Context) -> bool {
+		if let Some(chats) = &self.chats {
+			if !chats.contains(&ctx.chat) {
+				return false;
+			}
+		}
+
+		match &self.matcher {
+			Matcher::Any => true,
+			Matcher::Command(name) => {
+				let mut words = match ctx.message.text.strip_prefix(prefix) {
+					Some(rest) => rest.split_whitespace(),
+					None => return false,
+				};
+
+				match words.next() {
+					Some(word) if word.eq_ignore_ascii_case(name) => {
+						ctx.command = Some(name.to_owned());
+						ctx.args = words.map(str::to_owned).collect();
+						true
+					},
+					_ => false,
+				}
+			},
+			Matcher::Regex(regex) => match regex.captures(&ctx.message.text) {
+				Some(caps) => {
+					ctx.captures = caps.iter()
+						.map(|c| c.map(|m| m.as_str().to_owned()))
+						.collect();
+					true
+				},
+				None => false,
+			},
+		}
+	}
+}
+
+// Routes incoming messages to handlers, e.g.
+//
+// Bot::new(client)
+//     .with_command("ping", |ctx| async move { ctx.reply("pong").await })
+//     .with_route(Route::regex(Regex::new(r"(?i)\bhello\b")?).in_chat("+15555555555"), |ctx| async move {
+//         ctx.react(Tapback::Love).await
+//     })
+//     .run(notifications, shutdown)
+//     .await;
+//
+// Only the first route that matches a message handles it, so routes should be
+// added from most to least specific.
+pub struct Bot {
+	client: Arc<APIClient>,
+	prefix: String,
+	routes: Vec<(Route, Handler)>,
+	middleware: Vec<Arc<dyn Middleware>>,
+	ignore_own: bool,
+	shutdown_timeout: Duration,
+}
+
+impl Bot {
+	pub fn new(client: Arc<APIClient>) -> Bot {
+		Bot {
+			client,
+			prefix: "!".to_owned(),
+			routes: Vec::new(),
+			middleware: Vec::new(),
+			ignore_own: true,
+			shutdown_timeout: Duration::from_secs(10),
+		}
+	}
+
+	// what commands have to start with
+	pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+		self.prefix = prefix.into();
+		self
+	}
+
+	// whether messages sent from this phone are ignored, which they are by
+	// default so that the bot doesn't respond to its own replies
+	pub fn with_ignore_own(mut self, ignore: bool) -> Self {
+		self.ignore_own = ignore;
+		self
+	}
+
+	// how long handlers that are still running get to finish once the bot is shut down
+	pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+		self.shutdown_timeout = timeout;
+		self
+	}
+
+	pub fn with_middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+		self.middleware.push(Arc::new(middleware));
+		self
+	}
+
+	pub fn with_route<F, Fut>(mut self, route: Route, handler: F) -> Self
+	where
+		F: Fn(Context) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+	{
+		let handler: Handler = Arc::new(move |ctx| Box::pin(handler(ctx)));
+		self.routes.push((route, handler));
+		self
+	}
+
+	pub fn with_command<F, Fut>(self, name: impl Into<String>, handler: F) -> Self
+	where
+		F: Fn(Context) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+	{
+		self.with_route(Route::command(name), handler)
+	}
+
+	// Handles the `NewMessage` notifications from `notifications` (the receiver
+	// for the sender that was passed to `APIClient::new`) until `shutdown`
+	// finishes. Other notifications are ignored. Each message is handled in
+	// its own task, so a slow handler doesn't hold up the rest.
+	pub async fn run(
+		self,
+		notifications: crossbeam_channel::Receiver<SocketResponse>,
+		shutdown: impl Future<Output = ()>,
+	) {
+		let mut receiver = Notifications::new(notifications);
+		let bot = Arc::new(self);
+		let mut tasks = tokio::task::JoinSet::new();
+		let mut shutdown = Shutdown::new(shutdown);
+
+		tracing::info!(routes = bot.routes.len(), "bot started");
+
+		loop {
+			let notif = match shutdown.or(receiver.recv()).await {
+				Some(Some(notif)) => notif,
+				_ => break,
+			};
+
+			// reap the handlers that have finished, so they don't pile up
+			while tasks.try_join_next().is_some() {}
+
+			if let Some(ctx) = bot.context(notif) {
+				let bot = bot.clone();
+				tasks.spawn(async move { bot.dispatch(ctx).await });
+			}
+		}
+
+		tracing::info!(running = tasks.len(), "bot shutting down");
+
+		let drain = async { while tasks.join_next().await.is_some() {} };
+
+		if tokio::time::timeout(bot.shutdown_timeout, drain).await.is_err() {
+			tracing::warn!("handlers didn't finish in time, stopping them");
+			tasks.abort_all();
+		}
+	}
+
+	fn context(&self, notif: SocketResponse) -> Option<Context> {
+		if !matches!(notif.command, APICommand::NewMessage) {
+			return None;
+		}
+
+		let message = match notif.new_message_data() {
+			Ok(data) => data.message,
+			Err(err) => {
+				tracing::warn!(error = %err, "couldn't parse new message");
+				return None;
+			},
+		};
+
+		if (self.ignore_own && message.is_from_me) || message.message_type != MessageType::Normal {
+			return None;
+		}
+
+		Some(Context {
+			client: self.client.clone(),
+			chat: message.chat_identifier.to_owned()?,
+			message,
+			command: None,
+			args: Vec::new(),
+			captures: Vec::new(),
+		})
+	}
+
+	async fn dispatch(&self, mut ctx: Context) {
+		let handler = match self.routes.iter().find(|(route, _)| route.matches(&self.prefix, &mut ctx)) {
+			Some((_, handler)) => handler,
+			None => return,
+		};
+
+		if !self.middleware.iter().all(|m| m.before(&ctx)) {
+			return;
+		}
+
+		let res = handler(ctx.clone()).await;
+
+		if let Err(err) = &res {
+			tracing::warn!(chat = %ctx.chat, command = ?ctx.command, error = %err, "bot handler failed");
+		}
+
+		for m in self.middleware.iter() {
+			m.after(&ctx, &res);
+		}
+	}
+}
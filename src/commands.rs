@@ -1,8 +1,7 @@
 use derive_commands::Commands;
 use std::str::FromStr;
-use serde::{Deserialize, Serialize};
 
-#[derive(Commands, Deserialize, Serialize, Debug, Clone)]
+#[derive(Commands, Debug, Clone)]
 pub enum APICommand {
 	// so basically the entire API is created through this enum and the `Commands`
 	// macro that I wrote. For each variant, it does three main things:
@@ -18,7 +17,8 @@ pub enum APICommand {
 	//
 	//       Unless `data_return` is true, the RestAPIClient function returns a
 	//       value of the type defined by `return_type`. If `data_return` is true,
-	//       it returns a Vec<u8> -- the data.
+	//       it returns a BinaryPayload -- the data, along with the mime type and
+	//       file name from the response headers.
 	//
 	//       You can also change which subdirectory of the rest_base_url the
 	//       command goes to with the `subdir` key in the `command` attribute.
@@ -30,7 +30,8 @@ pub enum APICommand {
 	//
 	//       Unless `data_return` is true, the RestAPIClient function returns a
 	//       value of the type defined by `return_type`. If `data_return` is true,
-	//       it returns a Vec<u8> (the data).
+	//       it returns a BinaryPayload (the data, along with the metadata from
+	//       the chunks it was sent in).
 	//
 	// 3. If 1 and 2 don't happen & the `no_main` attribute is not set, this macro
 	//       creates a function to perform this function from the `APIClient`
@@ -38,6 +39,12 @@ pub enum APICommand {
 	//       connecting via REST or remote websocket, and then calls the
 	//       appropriate function.
 	//
+	//       Alongside it, a `${Variant}Request` struct is created (e.g. GetMessages
+	//       => `GetMessagesRequest`), which owns each parameter. Required parameters
+	//       are passed to `new`, and each optional one gets a `with_${param}`
+	//       function. It can be sent with `APIClient::execute`, and the positional
+	//       function described above just builds one of these and executes it.
+	//
 	// If a variant has a `data` attribute, that means that this data cannot be
 	// sent to the host and the client can only receive this information from the
 	// host, through the socket. If this is the case:
@@ -51,55 +58,76 @@ pub enum APICommand {
 	//   `SocketResponse` into the generated struct, consuming the SocketResponse
 	//   in the process.
 	//
+	// Unknown keys in the `command` attribute, types that don't parse, and options
+	// that contradict each other (e.g. `files` without `multipart`) are reported
+	// as compile errors on the offending attribute.
+	//
+	// If `idempotent` is true, the APIClient function and the request are retried
+	// according to the `RetryPolicy` in the SDKConfig when they fail. Only set this
+	// on commands that are safe to send more than once (so never on e.g. SendMessage).
+	//
 	// This macro also creates an `impl` of APICommand that allows you to get the
-	// command string for each variant (e.g. GetChats => "get-chats")
+	// command string for each variant (e.g. GetChats => "get-chats"), and the
+	// other way around. APICommand is (de)serialized with these same strings, so
+	// there's no need for `serde(rename)`s that could drift from them.
+	//
+	// The variant with `fallback` set in its `command` attribute holds any command
+	// string that isn't recognized, so that notifications from a newer host still
+	// reach the app, with their `data` untouched.
 
 	#[command(
 		subdir = "requests",
-		return_type = "Vec<crate::models::Conversation>"
+		return_type = "Vec<crate::models::Conversation>",
+		idempotent = true
 	)]
 	#[parameters(chats = "Option<u32>", chats_offset = "Option<u32>")]
-	#[serde(rename = "get-chats")]
 	GetChats,
 
-	#[command(return_type = "Vec<crate::models::Message>")]
+	#[command(return_type = "Vec<crate::models::Message>", idempotent = true)]
 	#[parameters(
 		messages = "&str",
 		num_messages = "Option<u32>",
 		messages_offset = "Option<u32>",
 		read_messages = "Option<bool>",
 	)]
-	#[serde(rename = "get-messages")]
 	GetMessages,
 
-	#[command(return_type = "crate::models::Conversation")]
+	// `search_after` and `search_before` are in the same format as `Message::date`.
+	// Older hosts don't support this; `APIClient::search` falls back to
+	// scanning the history when that's the case.
+	#[command(return_type = "Vec<crate::models::Message>", idempotent = true)]
+	#[parameters(
+		search = "&str",
+		search_chat = "Option<String>",
+		search_after = "Option<i64>",
+		search_before = "Option<i64>",
+		search_limit = "Option<u32>",
+		search_offset = "Option<u32>",
+	)]
+	SearchMessages,
+
+	#[command(return_type = "crate::models::Conversation", idempotent = true)]
 	#[parameters(chat_id = "&str")]
-	#[serde(rename = "get-conversation")]
 	GetConversation,
 
-	#[command(return_type = "String")]
+	#[command(return_type = "String", idempotent = true)]
 	#[parameters(name = "&str")]
-	#[serde(rename = "get-name")]
 	GetName,
 
-	#[command(subdir = "data", data_return = true)]
+	#[command(subdir = "data", data_return = true, idempotent = true)]
 	#[parameters(path = "&str")]
-	#[serde(rename = "get-attachment")]
 	GetAttachment,
 
-	#[command(data_return = true)]
+	#[command(data_return = true, idempotent = true)]
 	#[parameters(chat_id = "&str")]
-	#[serde(rename = "get-icon")]
 	GetIcon,
 
-	#[command(return_type = "Vec<crate::models::Photo>")]
+	#[command(return_type = "Vec<crate::models::Photo>", idempotent = true)]
 	#[parameters(photos = "Option<u32>", photos_offset = "Option<u32>", photos_recent = "Option<bool>")]
-	#[serde(rename = "get-photos")]
 	GetPhotos,
 
-	#[command(data_return = true)]
+	#[command(data_return = true, idempotent = true)]
 	#[parameters(photo = "&str")]
-	#[serde(rename = "get-photo")]
 	GetPhoto,
 
 	#[command(
@@ -115,19 +143,15 @@ pub enum APICommand {
 		attachments = "Option<serde_json::Value>",
 		photos = "Option<String>"
 	)]
-	#[serde(rename = "send-message")]
 	SendMessage,
 
 	#[parameters(tap_guid = "&str", tapback = "u16", remove_tap = "Option<bool>")]
-	#[serde(rename = "send-tapback")]
 	SendTapback,
 
 	#[parameters(delete_chat = "&str")]
-	#[serde(rename = "delete-chat")]
 	DeleteChat,
 
 	#[parameters(delete_text = "&str")]
-	#[serde(rename = "delete-text")]
 	DeleteText,
 
 	#[command(rest = false)]
@@ -137,22 +161,27 @@ pub enum APICommand {
 		index = "u32",
 		data = "&str",
 	)]
-	#[serde(rename = "attachment-data")]
 	AttachmentData,
 
 	#[parameters(chat = "&str", active = "bool")]
-	#[serde(rename = "send-typing")]
 	SendTyping,
 
+	// The current battery state, for when it's needed before the next
+	// `BatteryStatus` notification comes in. The value of `battery` is ignored;
+	// the key is just how the host knows what's being asked for.
+	#[command(subdir = "requests", return_type = "crate::device::BatteryState", idempotent = true)]
+	#[parameters(battery = "Option<bool>")]
+	GetBatteryStatus,
+
 	#[data(charging = "bool", percentage = "f64")]
-	#[serde(rename = "battery-status")]
 	BatteryStatus,
 
 	#[data(chat = "String", active = "bool")]
-	#[serde(rename = "typing")]
 	Typing,
 
 	#[data(message = "crate::models::Message")]
-	#[serde(rename = "new-message")]
 	NewMessage,
+
+	#[command(fallback = true)]
+	Unknown(String),
 }
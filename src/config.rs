@@ -1,31 +1,295 @@
-#[macro_export]
-macro_rules! log{
-	($msg:expr$(, $vars:expr)*) => {
-		crate::config::SDKConfig::log(format!($msg$(, $vars)*));
-	}
-}
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use zeroize::Zeroize;
+use crate::{
+	error::ConfigError,
+	retry::RetryPolicy,
+	contacts::ContactCacheConfig,
+	secret::*,
+};
+
+// the prefix of every environment variable that can override a config value,
+// e.g. `SMSERVER_REST_BASE_URL` overrides `rest_base_url`
+const ENV_PREFIX: &str = "SMSERVER_";
 
+// every value that isn't specified in a config file is left as it was, so
+// files can be layered on top of each other and the defaults
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
 pub struct SDKConfig {
 	pub rest_base_url: String,
 	pub sock_base_url: String,
-	pub password: String,
+	// this is set in files with either `password` or `password_file`, and
+	// is handled separately so that it's never serialized
+	#[serde(skip)]
+	pub password: Password,
 	pub timeout: usize, // in seconds
 	pub chunk_size: usize, // in bytes
 	pub use_rest: bool,
 	pub secure: bool,
+	pub poll_interval: Option<usize>, // in seconds
+	pub ping_interval: usize, // in seconds, 0 to disable
+	pub ping_timeout: usize, // in seconds
+	pub session_file: Option<std::path::PathBuf>,
+	pub retry: RetryPolicy,
+	pub contacts: ContactCacheConfig,
+	// how many commands `APIClient::batch` sends at once
+	pub batch_concurrency: usize,
 }
 
-impl SDKConfig {
-	pub fn default() -> SDKConfig {
+impl Default for SDKConfig {
+	fn default() -> SDKConfig {
 		SDKConfig {
 			rest_base_url: "".to_owned(),
 			sock_base_url: "".to_owned(),
-			password: "toor".to_owned(),
+			password: Password::default(),
 			timeout: 10,
 			chunk_size: 51200,
 			use_rest: true,
 			secure: true,
+			poll_interval: None,
+			ping_interval: 15,
+			ping_timeout: 45,
+			session_file: None,
+			retry: RetryPolicy::default(),
+			contacts: ContactCacheConfig::default(),
+			batch_concurrency: 8,
+		}
+	}
+}
+
+impl SDKConfig {
+	pub fn from_file(path: impl AsRef<Path>) -> Result<SDKConfig, ConfigError> {
+		SDKConfig::default().with_file(path)
+	}
+
+	pub fn from_env() -> Result<SDKConfig, ConfigError> {
+		SDKConfig::default().with_env()
+	}
+
+	// overrides the values in this config with the ones in a TOML or JSON file,
+	// depending on its extension
+	pub fn with_file(self, path: impl AsRef<Path>) -> Result<SDKConfig, ConfigError> {
+		let path = path.as_ref();
+		let parse_err = |message: String| ConfigError::Parse {
+			path: path.to_owned(),
+			message
+		};
+
+		let mut contents = std::fs::read_to_string(path)
+			.map_err(|source| ConfigError::Read { path: path.to_owned(), source })?;
+
+		let values: Result<Value, String> = match path.extension().and_then(|e| e.to_str()) {
+			Some("toml") => toml::from_str(&contents).map_err(|err| err.to_string()),
+			Some("json") => serde_json::from_str(&contents).map_err(|err| err.to_string()),
+			_ => Err("expected a `.toml` or `.json` file".to_owned()),
+		};
+
+		// since it may contain the password
+		contents.zeroize();
+
+		let mut overrides = match values.map_err(parse_err)? {
+			Value::Object(map) => map,
+			_ => return Err(parse_err("expected a table of config values".to_owned())),
+		};
+
+		let password = match (overrides.remove("password"), overrides.remove("password_file")) {
+			(Some(_), Some(_)) => return Err(parse_err(
+				"only one of `password` and `password_file` can be set".to_owned()
+			)),
+			(Some(Value::String(pass)), None) => Some(Password::Secret(Secret::from(pass))),
+			(None, Some(Value::String(file))) => Some(Password::File(file.into())),
+			(None, None) => None,
+			_ => return Err(parse_err("the password must be a string".to_owned())),
+		};
+
+		let mut config = self.merge(overrides).map_err(parse_err)?;
+
+		if let Some(pass) = password {
+			config.password = pass;
+		}
+
+		Ok(config)
+	}
+
+	// Overrides the values in this config with any `SMSERVER_${FIELD}`
+	// environment variables that are set. Fields of nested tables are set one
+	// at a time, with the table's name in front, e.g. `SMSERVER_RETRY_MAX_ATTEMPTS`
+	// for `retry.max_attempts`. Lists, like `retry.retry_on`, are comma-separated.
+	pub fn with_env(self) -> Result<SDKConfig, ConfigError> {
+		let current = match serde_json::to_value(&self) {
+			Ok(Value::Object(map)) => map,
+			_ => return Ok(self),
+		};
+
+		let mut config = self;
+
+		// each variable is merged on its own, so that an error can say which one was wrong
+		for (key, val) in current {
+			let var = format!("{}{}", ENV_PREFIX, key.to_uppercase());
+
+			let mut table = match val {
+				Value::Object(table) => table,
+				val => {
+					if let Some(parsed) = env_value(&var, &val, |v| config.accepts(&key, v))? {
+						config = config.merge_env(&var, &key, parsed)?;
+					}
+
+					continue;
+				},
+			};
+
+			for (field, field_val) in table.clone() {
+				let var = format!("{}_{}", var, field.to_uppercase());
+
+				let accepts = |v: &Value| {
+					let mut attempt = table.clone();
+					attempt.insert(field.to_owned(), v.clone());
+					config.accepts(&key, &Value::Object(attempt))
+				};
+
+				if let Some(parsed) = env_value(&var, &field_val, accepts)? {
+					table.insert(field, parsed);
+					config = config.merge_env(&var, &key, Value::Object(table.clone()))?;
+				}
+			}
+		}
+
+		// the password isn't serialized, so it isn't in the map above
+		if let Ok(pass) = std::env::var(format!("{}PASSWORD", ENV_PREFIX)) {
+			config.password = Password::Secret(Secret::from(pass));
+		} else if let Ok(file) = std::env::var(format!("{}PASSWORD_FILE", ENV_PREFIX)) {
+			config.password = Password::File(file.into());
+		}
+
+		Ok(config)
+	}
+
+	fn merge_env(self, var: &str, key: &str, val: Value) -> Result<SDKConfig, ConfigError> {
+		let mut overrides = serde_json::Map::new();
+		overrides.insert(key.to_owned(), val);
+
+		self.merge(overrides)
+			.map_err(|message| ConfigError::Env {
+				var: var.to_owned(),
+				message
+			})
+	}
+
+	// whether `val` could be deserialized as the value of `key`
+	fn accepts(&self, key: &str, val: &Value) -> bool {
+		match serde_json::to_value(self) {
+			Ok(Value::Object(mut map)) => {
+				map.insert(key.to_owned(), val.clone());
+				serde_json::from_value::<SDKConfig>(Value::Object(map)).is_ok()
+			},
+			_ => false,
+		}
+	}
+
+	fn merge(
+		self, overrides: serde_json::Map<String, Value>
+	) -> Result<SDKConfig, String> {
+		let mut current = match serde_json::to_value(&self) {
+			Ok(Value::Object(map)) => map,
+			_ => return Ok(self),
+		};
+
+		let password = self.password.clone();
+
+		// check them one at a time, so that the error can say which one was wrong
+		for (key, val) in overrides {
+			if !current.contains_key(&key) {
+				return Err(format!("unknown key `{}`", key));
+			}
+
+			let mut attempt = current.clone();
+			attempt.insert(key.to_owned(), val.clone());
+
+			if let Err(err) = serde_json::from_value::<SDKConfig>(Value::Object(attempt)) {
+				return Err(format!("`{}`: {}", key, err));
+			}
+
+			current.insert(key, val);
+		}
+
+		let mut merged: SDKConfig = serde_json::from_value(Value::Object(current))
+			.map_err(|err| err.to_string())?;
+
+		merged.password = password;
+
+		// make sure the urls are formatted the same as if they were set
+		// with `with_rest_url` and `with_sock_url`
+		let rest = merged.rest_base_url.to_owned();
+		let sock = merged.sock_base_url.to_owned();
+
+		Ok(match sock.is_empty() {
+			true => merged.with_rest_url(rest),
+			false => merged.with_rest_url(rest).with_sock_url(sock),
+		})
+	}
+
+	// checks that everything in this config makes sense, so that mistakes
+	// show up before trying to connect instead of as confusing errors later
+	pub fn validate(&self) -> Result<(), ConfigError> {
+		let invalid = |field: &'static str, message: String| Err(ConfigError::Invalid {
+			field,
+			message
+		});
+
+		if self.use_rest {
+			if let Err(message) = check_url(&self.rest_base_url, &["http", "https"]) {
+				return invalid("rest_base_url", message);
+			}
+		}
+
+		// the socket is currently opened even when using the REST API
+		if let Err(message) = check_url(&self.sock_base_url, &["ws", "wss", "http", "https"]) {
+			return invalid("sock_base_url", message);
+		}
+
+		if let Password::File(path) = &self.password {
+			if !path.is_file() {
+				return invalid("password_file", format!("{:?} is not a file", path));
+			}
+		}
+
+		if self.timeout == 0 {
+			return invalid("timeout", "must be at least 1 second".to_owned());
+		}
+
+		if self.chunk_size == 0 {
+			return invalid("chunk_size", "must be at least 1 byte".to_owned());
+		}
+
+		if self.poll_interval == Some(0) {
+			return invalid("poll_interval", "must be at least 1 second".to_owned());
+		}
+
+		if self.ping_interval != 0 && self.ping_timeout <= self.ping_interval {
+			return invalid("ping_timeout", format!(
+				"must be longer than `ping_interval` ({} seconds)", self.ping_interval
+			));
+		}
+
+		if self.retry.max_attempts == 0 {
+			return invalid("retry.max_attempts", "must be at least 1".to_owned());
+		}
+
+		if self.retry.multiplier < 1.0 {
+			return invalid("retry.multiplier", "must be at least 1".to_owned());
+		}
+
+		if self.contacts.capacity == 0 {
+			return invalid("contacts.capacity", "must be at least 1".to_owned());
+		}
+
+		if self.batch_concurrency == 0 {
+			return invalid("batch_concurrency", "must be at least 1".to_owned());
 		}
+
+		Ok(())
 	}
 
 	pub fn with_rest_url(mut self, url: impl Into<String>) -> Self {
@@ -41,7 +305,22 @@ impl SDKConfig {
 	}
 
 	pub fn with_password(mut self, pass: impl Into<String>) -> Self {
-		self.password = pass.into();
+		self.password = Password::Secret(Secret::new(pass));
+		self
+	}
+
+	// reads the password from this file every time it's needed
+	pub fn with_password_file(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+		self.password = Password::File(path.into());
+		self
+	}
+
+	// calls this every time the password is needed, e.g. to get it from a keychain
+	pub fn with_password_provider(
+		mut self,
+		provider: impl Fn() -> anyhow::Result<Secret> + Send + Sync + 'static
+	) -> Self {
+		self.password = Password::Provider(std::sync::Arc::new(provider));
 		self
 	}
 
@@ -77,8 +356,52 @@ impl SDKConfig {
 		self
 	}
 
-	pub fn password(&self) -> &str {
-		&self.password
+	// when using the REST API, check for new messages this often and send them
+	// through the notification channel, since they can't be pushed over REST
+	pub fn with_poll_interval(mut self, secs: usize) -> Self {
+		self.poll_interval = Some(secs);
+		self
+	}
+
+	// how often to ping the host over the socket, to make sure it's still there
+	pub fn with_ping_interval(mut self, secs: usize) -> Self {
+		self.ping_interval = secs;
+		self
+	}
+
+	// how long the host can go without responding before the socket
+	// is considered disconnected
+	pub fn with_ping_timeout(mut self, secs: usize) -> Self {
+		self.ping_timeout = secs;
+		self
+	}
+
+	// saves the session cookies to this file, so that the next time the SDK
+	// starts, it doesn't have to authenticate again
+	pub fn with_session_file(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+		self.session_file = Some(path.into());
+		self
+	}
+
+	// how idempotent commands (like `get_chats`) are retried when they fail
+	pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+		self.retry = retry;
+		self
+	}
+
+	pub fn with_batch_concurrency(mut self, concurrency: usize) -> Self {
+		self.batch_concurrency = concurrency;
+		self
+	}
+
+	// how the names and icons used by `resolve_name` and `resolve_icon` are cached
+	pub fn with_contact_cache(mut self, contacts: ContactCacheConfig) -> Self {
+		self.contacts = contacts;
+		self
+	}
+
+	pub fn password(&self) -> anyhow::Result<Secret> {
+		self.password.resolve()
 	}
 
 	pub fn push_to_rest_url(&self, url: impl Into<String>) -> String {
@@ -88,19 +411,207 @@ impl SDKConfig {
 	pub fn push_to_sock_url(&self, url: impl Into<String>) -> String {
 		format!("{}/{}", self.sock_base_url, url.into())
 	}
+}
+
+// the value of the environment variable `var`, if it's set, parsed based on
+// the type of `current` (what it's replacing), since e.g. a password could look
+// like a number. `accepts` says whether a value would deserialize in its place.
+fn env_value(
+	var: &str, current: &Value, accepts: impl Fn(&Value) -> bool
+) -> Result<Option<Value>, ConfigError> {
+	let env_val = match std::env::var(var) {
+		Ok(env_val) => env_val,
+		Err(_) => return Ok(None),
+	};
+
+	let env_err = |message: &str| ConfigError::Env {
+		var: var.to_owned(),
+		message: message.to_owned()
+	};
+
+	Ok(Some(match current {
+		Value::String(_) => Value::String(env_val),
+		Value::Bool(_) => match env_val.to_lowercase().as_str() {
+			"true" | "1" | "yes" => Value::Bool(true),
+			"false" | "0" | "no" => Value::Bool(false),
+			_ => return Err(env_err("expected `true` or `false`")),
+		},
+		Value::Number(num) if num.is_f64() => env_val.parse::<f64>()
+			.ok()
+			.and_then(serde_json::Number::from_f64)
+			.map(Value::Number)
+			.ok_or_else(|| env_err("expected a number"))?,
+		Value::Number(_) => env_val.parse::<u64>()
+			.map(Value::from)
+			.map_err(|_| env_err("expected a positive whole number"))?,
+		Value::Array(_) => Value::Array(env_val.split(',')
+			.map(str::trim)
+			.filter(|item| !item.is_empty())
+			.map(|item| Value::String(item.to_owned()))
+			.collect()),
+		// optional values could be numbers (e.g. `poll_interval`) or strings
+		// (e.g. `session_file`), so use whichever one the field accepts
+		Value::Null => match env_val.parse::<u64>().map(Value::from) {
+			_ if env_val.is_empty() => Value::Null,
+			Ok(num) if accepts(&num) => num,
+			_ => Value::String(env_val),
+		},
+		// there aren't any tables nested more than one deep
+		Value::Object(_) => return Err(env_err("can't be set from the environment")),
+	}))
+}
+
+fn check_url(url: &str, schemes: &[&str]) -> Result<(), String> {
+	if url.is_empty() {
+		return Err("is empty".to_owned());
+	}
+
+	let parsed = url::Url::parse(url)
+		.map_err(|err| format!("`{}` is not a valid url: {}", url, err))?;
+
+	if !schemes.contains(&parsed.scheme()) {
+		return Err(format!(
+			"has the scheme `{}`, but expected one of: {}", parsed.scheme(), schemes.join(", ")
+		));
+	}
+
+	if parsed.host().is_none() {
+		return Err(format!("`{}` has no host", url));
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::retry::RetryKind;
+
+	fn write_temp(ext: &str, contents: &str) -> std::path::PathBuf {
+		let path = std::env::temp_dir()
+			.join(format!("smserver-config-{}.{}", uuid::Uuid::new_v4(), ext));
+
+		std::fs::write(&path, contents).unwrap();
+		path
+	}
+
+	fn valid() -> SDKConfig {
+		SDKConfig::default()
+			.with_rest_url("https://192.168.0.2:8741/")
+			.with_sock_url("wss://192.168.0.2:8740")
+	}
+
+	#[test]
+	fn file_overrides_only_what_it_sets() {
+		let path = write_temp("toml", r#"
+			rest_base_url = "https://host:8741/"
+			timeout = 30
+			password = "hunter2"
+
+			[retry]
+			max_attempts = 5
+		"#);
+
+		let config = SDKConfig::default().with_chunk_size(1024).with_file(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+
+		// the trailing slash is removed, like `with_rest_url` does
+		assert_eq!(config.rest_base_url, "https://host:8741");
+		assert_eq!(config.timeout, 30);
+		assert_eq!(config.chunk_size, 1024);
+		assert_eq!(config.retry.max_attempts, 5);
+		assert_eq!(config.retry.initial_backoff_ms, RetryPolicy::default().initial_backoff_ms);
+		assert_eq!(config.password().unwrap().expose(), "hunter2");
+	}
+
+	#[test]
+	fn file_errors_name_the_key() {
+		let path = write_temp("json", r#"{ "timeout": "soon" }"#);
+		let err = SDKConfig::from_file(&path).unwrap_err().to_string();
+		std::fs::remove_file(&path).unwrap();
+		assert!(err.contains("`timeout`"), "{}", err);
+
+		let path = write_temp("toml", "not_a_key = 1");
+		let err = SDKConfig::from_file(&path).unwrap_err().to_string();
+		std::fs::remove_file(&path).unwrap();
+		assert!(err.contains("unknown key `not_a_key`"), "{}", err);
+
+		let path = write_temp("toml", "password = \"a\"\npassword_file = \"b\"");
+		assert!(SDKConfig::from_file(&path).is_err());
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	// all of the environment is checked in one test, since the tests run in
+	// parallel and `with_env` reads every `SMSERVER_` variable
+	#[test]
+	fn env_overrides() {
+		let vars = [
+			("SMSERVER_TIMEOUT", "30"),
+			("SMSERVER_USE_REST", "no"),
+			("SMSERVER_POLL_INTERVAL", "5"),
+			("SMSERVER_SESSION_FILE", "/tmp/session.json"),
+			("SMSERVER_RETRY_MAX_ATTEMPTS", "7"),
+			("SMSERVER_RETRY_MULTIPLIER", "1.5"),
+			("SMSERVER_RETRY_RETRY_ON", "connect, timeout"),
+			("SMSERVER_CONTACTS_TTL", "0"),
+		];
+
+		for (var, val) in vars.iter() {
+			std::env::set_var(var, val);
+		}
+
+		let config = SDKConfig::default().with_env().unwrap();
+
+		assert_eq!(config.timeout, 30);
+		assert!(!config.use_rest);
+		assert_eq!(config.poll_interval, Some(5));
+		assert_eq!(config.session_file, Some("/tmp/session.json".into()));
+		assert_eq!(config.retry.max_attempts, 7);
+		assert_eq!(config.retry.multiplier, 1.5);
+		assert_eq!(config.retry.retry_on, vec![RetryKind::Connect, RetryKind::Timeout]);
+		// the rest of the table is left alone
+		assert_eq!(config.retry.max_backoff_ms, RetryPolicy::default().max_backoff_ms);
+		assert_eq!(config.contacts.ttl, 0);
+
+		// errors name the variable that was wrong
+		std::env::set_var("SMSERVER_RETRY_RETRY_ON", "connect,sometimes");
+
+		match SDKConfig::default().with_env() {
+			Err(ConfigError::Env { var, .. }) => assert_eq!(var, "SMSERVER_RETRY_RETRY_ON"),
+			other => panic!("expected an env error, got {:?}", other),
+		}
+
+		std::env::set_var("SMSERVER_RETRY_RETRY_ON", "connect");
+		std::env::set_var("SMSERVER_TIMEOUT", "-1");
+
+		match SDKConfig::default().with_env() {
+			Err(ConfigError::Env { var, .. }) => assert_eq!(var, "SMSERVER_TIMEOUT"),
+			other => panic!("expected an env error, got {:?}", other),
+		}
+
+		for (var, _) in vars.iter() {
+			std::env::remove_var(var);
+		}
+	}
+
+	#[test]
+	fn validate_checks_each_field() {
+		assert!(valid().validate().is_ok());
 
-	pub fn log(log_str: String) {
-		use std::{
-			fs::OpenOptions,
-			io::Write
+		let field = |config: SDKConfig| match config.validate() {
+			Err(ConfigError::Invalid { field, .. }) => field,
+			other => panic!("expected an invalid field, got {:?}", other),
 		};
 
-		let mut file = OpenOptions::new()
-			.create(true)
-			.append(true)
-			.open("log.log")
-			.expect("Cannot open log file for writing");
+		assert_eq!(field(SDKConfig::default()), "rest_base_url");
+		assert_eq!(field(valid().with_rest_url("ftp://host")), "rest_base_url");
+		assert_eq!(field(valid().with_sock_url("not a url")), "sock_base_url");
+		assert_eq!(field(valid().with_timeout(0)), "timeout");
+		assert_eq!(field(valid().with_ping_interval(60)), "ping_timeout");
+		assert_eq!(field(valid().with_batch_concurrency(0)), "batch_concurrency");
+		assert_eq!(field(valid().with_retry(RetryPolicy::default().with_max_attempts(0))), "retry.max_attempts");
 
-		let _ = writeln!(file, "{}", log_str);
+		// the socket url is checked even without the REST API
+		assert!(valid().with_rest(false).with_rest_url("").validate().is_ok());
 	}
 }
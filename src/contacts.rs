@@ -0,0 +1,447 @@
+use std::{
+	collections::HashMap,
+	future::Future,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+	time::{Duration, SystemTime},
+};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use crate::payload::BinaryPayload;
+
+// how the names and icons from `get_name` and `get_icon` are cached
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ContactCacheConfig {
+	// how many names (and, separately, icons) are kept in memory
+	pub capacity: usize,
+	pub ttl: u64, // in seconds, 0 to never expire
+	// if set, names and icons are also saved here, so that they don't all
+	// have to be requested again the next time the SDK starts
+	pub cache_dir: Option<PathBuf>,
+}
+
+impl Default for ContactCacheConfig {
+	fn default() -> ContactCacheConfig {
+		ContactCacheConfig {
+			capacity: 512,
+			ttl: 60 * 60,
+			cache_dir: None,
+		}
+	}
+}
+
+impl ContactCacheConfig {
+	pub fn with_capacity(mut self, capacity: usize) -> Self {
+		self.capacity = capacity;
+		self
+	}
+
+	pub fn with_ttl(mut self, ttl: Duration) -> Self {
+		self.ttl = ttl.as_secs();
+		self
+	}
+
+	pub fn with_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+		self.cache_dir = Some(dir.into());
+		self
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+	Name,
+	Icon,
+}
+
+impl Kind {
+	fn dir(&self) -> &'static str {
+		match self {
+			Kind::Name => "names",
+			Kind::Icon => "icons",
+		}
+	}
+}
+
+// the metadata that's saved next to each icon on disk
+#[derive(Deserialize, Serialize)]
+struct IconMeta {
+	mime_type: Option<String>,
+	file_name: Option<String>,
+}
+
+struct Entry<V> {
+	value: V,
+	fetched: SystemTime,
+	last_used: u64,
+}
+
+// a small least-recently-used cache. It's only meant to hold a few hundred
+// contacts, so finding the one to evict by looking through all of them is fine.
+struct LruCache<V> {
+	entries: Mutex<(HashMap<String, Entry<V>>, u64)>,
+	capacity: usize,
+}
+
+impl<V: Clone> LruCache<V> {
+	fn new(capacity: usize) -> LruCache<V> {
+		LruCache {
+			entries: Mutex::new((HashMap::new(), 0)),
+			capacity: capacity.max(1),
+		}
+	}
+
+	fn get(&self, key: &str, ttl: Option<Duration>) -> Option<V> {
+		let mut guard = self.entries.lock().ok()?;
+		let (entries, clock) = &mut *guard;
+
+		if entries.get(key).map(|e| is_expired(e.fetched, ttl)).unwrap_or(false) {
+			entries.remove(key);
+			return None;
+		}
+
+		*clock += 1;
+		let entry = entries.get_mut(key)?;
+		entry.last_used = *clock;
+
+		Some(entry.value.clone())
+	}
+
+	fn insert(&self, key: &str, value: V, fetched: SystemTime) {
+		let mut guard = match self.entries.lock() {
+			Ok(guard) => guard,
+			Err(_) => return,
+		};
+		let (entries, clock) = &mut *guard;
+
+		if entries.len() >= self.capacity && !entries.contains_key(key) {
+			let oldest = entries.iter()
+				.min_by_key(|(_, e)| e.last_used)
+				.map(|(k, _)| k.to_owned());
+
+			if let Some(oldest) = oldest {
+				entries.remove(&oldest);
+			}
+		}
+
+		*clock += 1;
+		entries.insert(key.to_owned(), Entry { value, fetched, last_used: *clock });
+	}
+
+	fn remove(&self, key: &str) {
+		if let Ok(mut guard) = self.entries.lock() {
+			guard.0.remove(key);
+		}
+	}
+
+	fn clear(&self) {
+		if let Ok(mut guard) = self.entries.lock() {
+			guard.0.clear();
+		}
+	}
+}
+
+// Caches the results of `get_name` and `get_icon`, so that e.g. a conversation
+// list can look up every row each time it refreshes without sending a request
+// for each of them. If multiple lookups for the same address happen at once,
+// only the first one sends a request and the rest wait for its result.
+pub struct ContactResolver {
+	names: LruCache<String>,
+	icons: LruCache<BinaryPayload>,
+	// one lock for each address that's being looked up right now
+	pending: DashMap<(Kind, String), Arc<tokio::sync::Mutex<()>>>,
+	ttl: Option<Duration>,
+	cache_dir: Option<PathBuf>,
+}
+
+impl ContactResolver {
+	pub fn new(config: &ContactCacheConfig) -> ContactResolver {
+		ContactResolver {
+			names: LruCache::new(config.capacity),
+			icons: LruCache::new(config.capacity),
+			pending: DashMap::new(),
+			ttl: match config.ttl {
+				0 => None,
+				secs => Some(Duration::from_secs(secs)),
+			},
+			cache_dir: config.cache_dir.to_owned(),
+		}
+	}
+
+	// the name for `address`, using `fetch` (e.g. `client.get_name(address)`)
+	// if it isn't cached already
+	pub async fn name(
+		&self, address: &str, fetch: impl Future<Output = anyhow::Result<String>>
+	) -> anyhow::Result<String> {
+		self.resolve(Kind::Name, &self.names, address, fetch).await
+	}
+
+	// the icon for `chat_id`, using `fetch` (e.g. `client.get_icon(chat_id)`)
+	// if it isn't cached already
+	pub async fn icon(
+		&self, chat_id: &str, fetch: impl Future<Output = anyhow::Result<BinaryPayload>>
+	) -> anyhow::Result<BinaryPayload> {
+		self.resolve(Kind::Icon, &self.icons, chat_id, fetch).await
+	}
+
+	// forgets the name and icon for this address, e.g. once the contact has changed
+	pub fn invalidate(&self, address: &str) {
+		self.names.remove(address);
+		self.icons.remove(address);
+
+		for kind in [Kind::Name, Kind::Icon] {
+			if let Some(path) = self.disk_path(kind, address) {
+				remove_entry(kind, &path);
+			}
+		}
+	}
+
+	// forgets every name and icon, in memory and on disk
+	pub fn clear(&self) {
+		self.names.clear();
+		self.icons.clear();
+
+		if let Some(dir) = &self.cache_dir {
+			for kind in [Kind::Name, Kind::Icon] {
+				let _ = std::fs::remove_dir_all(dir.join(kind.dir()));
+			}
+		}
+	}
+
+	async fn resolve<V: Clone + Persist>(
+		&self,
+		kind: Kind,
+		cache: &LruCache<V>,
+		key: &str,
+		fetch: impl Future<Output = anyhow::Result<V>>
+	) -> anyhow::Result<V> {
+		if let Some(val) = cache.get(key, self.ttl) {
+			return Ok(val);
+		}
+
+		let lock = self.pending.entry((kind, key.to_owned()))
+			.or_default()
+			.clone();
+
+		let _guard = lock.lock().await;
+
+		// another lookup may have gotten it while we were waiting
+		if let Some(val) = cache.get(key, self.ttl) {
+			return Ok(val);
+		}
+
+		let res = self.load_or_fetch(kind, cache, key, fetch).await;
+
+		self.pending.remove(&(kind, key.to_owned()));
+
+		res
+	}
+
+	async fn load_or_fetch<V: Clone + Persist>(
+		&self,
+		kind: Kind,
+		cache: &LruCache<V>,
+		key: &str,
+		fetch: impl Future<Output = anyhow::Result<V>>
+	) -> anyhow::Result<V> {
+		let path = self.disk_path(kind, key);
+
+		if let Some((val, fetched)) = path.as_ref().and_then(|p| V::load(p)) {
+			if !is_expired(fetched, self.ttl) {
+				cache.insert(key, val.clone(), fetched);
+				return Ok(val);
+			}
+		}
+
+		tracing::debug!(kind = kind.dir(), key, "looking up contact");
+
+		let val = fetch.await?;
+
+		cache.insert(key, val.clone(), SystemTime::now());
+
+		if let Some(path) = path {
+			// the cache on disk is just nice to have, so it's fine if it can't be saved
+			if let Err(err) = val.save(&path) {
+				tracing::warn!(path = ?path, error = %err, "failed to save contact to cache");
+			}
+		}
+
+		Ok(val)
+	}
+
+	fn disk_path(&self, kind: Kind, key: &str) -> Option<PathBuf> {
+		// the addresses can have all sorts of characters in them (e.g. `+` or
+		// `@`), so they're hex-encoded to make them safe to use as file names
+		let name: String = key.bytes()
+			.map(|b| format!("{:02x}", b))
+			.collect();
+
+		self.cache_dir.as_ref().map(|dir| dir.join(kind.dir()).join(name))
+	}
+}
+
+fn is_expired(fetched: SystemTime, ttl: Option<Duration>) -> bool {
+	match (ttl, fetched.elapsed()) {
+		(None, _) => false,
+		(Some(ttl), Ok(age)) => age > ttl,
+		// it was fetched in the future, so the clock must've changed
+		(Some(_), Err(_)) => true,
+	}
+}
+
+fn remove_entry(kind: Kind, path: &Path) {
+	let _ = std::fs::remove_file(path);
+
+	if kind == Kind::Icon {
+		let _ = std::fs::remove_file(path.with_extension("json"));
+	}
+}
+
+// how each kind of value is saved to and loaded from the cache directory. When
+// it was fetched is taken from when the file was last modified.
+trait Persist: Sized {
+	fn load(path: &Path) -> Option<(Self, SystemTime)>;
+	fn save(&self, path: &Path) -> std::io::Result<()>;
+}
+
+impl Persist for String {
+	fn load(path: &Path) -> Option<(String, SystemTime)> {
+		let fetched = std::fs::metadata(path).ok()?.modified().ok()?;
+		Some((std::fs::read_to_string(path).ok()?, fetched))
+	}
+
+	fn save(&self, path: &Path) -> std::io::Result<()> {
+		create_parent(path)?;
+		std::fs::write(path, self)
+	}
+}
+
+impl Persist for BinaryPayload {
+	fn load(path: &Path) -> Option<(BinaryPayload, SystemTime)> {
+		let fetched = std::fs::metadata(path).ok()?.modified().ok()?;
+		let meta: IconMeta = std::fs::read(path.with_extension("json")).ok()
+			.and_then(|m| serde_json::from_slice(&m).ok())?;
+
+		let payload = BinaryPayload::new(std::fs::read(path).ok()?)
+			.with_mime_type(meta.mime_type)
+			.with_file_name(meta.file_name);
+
+		Some((payload, fetched))
+	}
+
+	fn save(&self, path: &Path) -> std::io::Result<()> {
+		create_parent(path)?;
+
+		let meta = IconMeta {
+			mime_type: self.mime_type.to_owned(),
+			file_name: self.file_name.to_owned(),
+		};
+
+		// the metadata is written first, so that there's never an icon without it
+		std::fs::write(path.with_extension("json"), serde_json::to_vec(&meta)?)?;
+		std::fs::write(path, &self.bytes)
+	}
+}
+
+fn create_parent(path: &Path) -> std::io::Result<()> {
+	match path.parent() {
+		Some(parent) => std::fs::create_dir_all(parent),
+		None => Ok(()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	#[test]
+	fn evicts_the_least_recently_used() {
+		let cache = LruCache::new(2);
+		let now = SystemTime::now();
+
+		cache.insert("a", 1, now);
+		cache.insert("b", 2, now);
+		// `a` is used, so `b` is the one to go
+		assert_eq!(cache.get("a", None), Some(1));
+		cache.insert("c", 3, now);
+
+		assert_eq!(cache.get("b", None), None);
+		assert_eq!(cache.get("a", None), Some(1));
+		assert_eq!(cache.get("c", None), Some(3));
+
+		// replacing an entry doesn't evict anything
+		cache.insert("c", 4, now);
+		assert_eq!(cache.get("a", None), Some(1));
+		assert_eq!(cache.get("c", None), Some(4));
+
+		cache.remove("a");
+		assert_eq!(cache.get("a", None), None);
+		cache.clear();
+		assert_eq!(cache.get("c", None), None);
+	}
+
+	#[test]
+	fn expires_old_entries() {
+		let cache = LruCache::new(4);
+		let ttl = Some(Duration::from_secs(60));
+
+		cache.insert("old", 1, SystemTime::now() - Duration::from_secs(120));
+		cache.insert("new", 2, SystemTime::now());
+		cache.insert("future", 3, SystemTime::now() + Duration::from_secs(120));
+
+		assert_eq!(cache.get("old", None), Some(1));
+		assert_eq!(cache.get("old", ttl), None);
+		// and it's gone now, even without a ttl
+		assert_eq!(cache.get("old", None), None);
+
+		assert_eq!(cache.get("new", ttl), Some(2));
+		assert_eq!(cache.get("future", ttl), None);
+	}
+
+	#[test]
+	fn resolves_each_address_once() {
+		let dir = std::env::temp_dir()
+			.join(format!("smserver-contacts-{}", uuid::Uuid::new_v4()));
+		let config = ContactCacheConfig::default().with_cache_dir(&dir);
+		let fetches = AtomicUsize::new(0);
+
+		let fetch = || async {
+			fetches.fetch_add(1, Ordering::SeqCst);
+			tokio::task::yield_now().await;
+			Ok("Jane Appleseed".to_owned())
+		};
+
+		let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+		rt.block_on(async {
+			let resolver = ContactResolver::new(&config);
+
+			let (a, b) = futures_util::join!(
+				resolver.name("+15555550123", fetch()),
+				resolver.name("+15555550123", fetch()),
+			);
+			assert_eq!(a.unwrap(), "Jane Appleseed");
+			assert_eq!(b.unwrap(), "Jane Appleseed");
+			assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+			// a new resolver finds it on disk
+			let resolver = ContactResolver::new(&config);
+			resolver.name("+15555550123", fetch()).await.unwrap();
+			assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+			resolver.invalidate("+15555550123");
+			resolver.name("+15555550123", fetch()).await.unwrap();
+			assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+			// failures aren't cached
+			let failed = resolver.name("someone@example.com", async {
+				Err(anyhow::anyhow!("no host"))
+			}).await;
+			assert!(failed.is_err());
+			assert_eq!(resolver.name("someone@example.com", fetch()).await.unwrap(), "Jane Appleseed");
+		});
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
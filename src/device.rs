@@ -0,0 +1,215 @@
+use std::{
+	collections::VecDeque,
+	sync::{Arc, Mutex},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::{
+	api::APIClient,
+	commands::APICommand,
+	dispatch::NotificationHandler,
+	socket::SocketResponse,
+};
+
+// how far above the low battery threshold the battery has to get before
+// another low battery event can be sent, so that it doesn't go off over and
+// over while the percentage hovers around the threshold
+const LOW_HYSTERESIS: f64 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct BatteryState {
+	pub charging: bool,
+	// from 0 to 100
+	pub percentage: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct BatterySample {
+	#[serde(flatten)]
+	pub state: BatteryState,
+	pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DeviceEvent {
+	// the battery dropped to or below the low battery threshold while not charging
+	LowBattery { percentage: f64 },
+	StartedCharging { percentage: f64 },
+	StoppedCharging { percentage: f64 },
+}
+
+struct State {
+	history: VecDeque<BatterySample>,
+	// whether a low battery event was sent and the battery hasn't recovered since
+	low_sent: bool,
+}
+
+// Keeps track of the phone's battery, from the `BatteryStatus` notifications
+// (see `NotificationHandler`) and from asking the host with `refresh`. The latest
+// states are kept, up to `with_history`, e.g. to graph them on a dashboard.
+pub struct DeviceStatus {
+	client: Arc<APIClient>,
+	state: Mutex<State>,
+	capacity: usize,
+	low_threshold: f64,
+	events: Option<crossbeam_channel::Sender<DeviceEvent>>,
+}
+
+impl DeviceStatus {
+	pub fn new(client: Arc<APIClient>) -> DeviceStatus {
+		DeviceStatus {
+			client,
+			state: Mutex::new(State {
+				history: VecDeque::new(),
+				low_sent: false,
+			}),
+			capacity: 512,
+			low_threshold: 20.0,
+			events: None,
+		}
+	}
+
+	// how many states are kept, at least 1
+	pub fn with_history(mut self, capacity: usize) -> Self {
+		self.capacity = capacity.max(1);
+		self
+	}
+
+	// the percentage at or below which `DeviceEvent::LowBattery` is sent
+	pub fn with_low_threshold(mut self, percentage: f64) -> Self {
+		self.low_threshold = percentage;
+		self
+	}
+
+	// receives an event whenever the battery gets low or starts or stops charging
+	pub fn with_events(mut self, sender: crossbeam_channel::Sender<DeviceEvent>) -> Self {
+		self.events = Some(sender);
+		self
+	}
+
+	// asks the host for the current state, and records it
+	pub async fn refresh(&self) -> anyhow::Result<BatteryState> {
+		// the value doesn't matter, but without one the socket leaves the key out
+		let state = self.client.get_battery_status(Some(true)).await?;
+		self.record(state);
+		Ok(state)
+	}
+
+	pub fn record(&self, state: BatteryState) {
+		let events = match self.state.lock() {
+			Ok(mut current) => current.update(state, self.low_threshold, self.capacity),
+			Err(_) => return,
+		};
+
+		for event in events {
+			tracing::info!(?event, "battery event");
+
+			if let Some(sender) = &self.events {
+				let _ = sender.send(event);
+			}
+		}
+	}
+
+	pub fn latest(&self) -> Option<BatterySample> {
+		self.state.lock().ok()?.history.back().copied()
+	}
+
+	// oldest first
+	pub fn history(&self) -> Vec<BatterySample> {
+		self.state.lock()
+			.map(|state| state.history.iter().copied().collect())
+			.unwrap_or_default()
+	}
+}
+
+// records the state from the `BatteryStatus` notifications
+impl NotificationHandler for DeviceStatus {
+	fn handles(&self, command: &APICommand) -> bool {
+		matches!(command, APICommand::BatteryStatus)
+	}
+
+	fn notify(&self, notif: SocketResponse) -> anyhow::Result<()> {
+		let status = notif.battery_status_data()?;
+
+		self.record(BatteryState {
+			charging: status.charging,
+			percentage: status.percentage,
+		});
+
+		Ok(())
+	}
+}
+
+impl State {
+	// adds `new` to the history, keeping at most `capacity` states, and returns
+	// the events that it caused
+	fn update(&mut self, new: BatteryState, low_threshold: f64, capacity: usize) -> Vec<DeviceEvent> {
+		let mut events = Vec::new();
+		let percentage = new.percentage;
+
+		match self.history.back().map(|s| s.state.charging) {
+			Some(false) if new.charging => events.push(DeviceEvent::StartedCharging { percentage }),
+			Some(true) if !new.charging => events.push(DeviceEvent::StoppedCharging { percentage }),
+			_ => (),
+		}
+
+		if new.charging || percentage > low_threshold + LOW_HYSTERESIS {
+			self.low_sent = false;
+		} else if percentage <= low_threshold && !self.low_sent {
+			self.low_sent = true;
+			events.push(DeviceEvent::LowBattery { percentage });
+		}
+
+		self.history.push_back(BatterySample { state: new, at: Utc::now() });
+
+		while self.history.len() > capacity {
+			self.history.pop_front();
+		}
+
+		events
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sample(state: &mut State, charging: bool, percentage: f64) -> Vec<DeviceEvent> {
+		state.update(BatteryState { charging, percentage }, 20.0, 3)
+	}
+
+	#[test]
+	fn sends_low_battery_once_until_it_recovers() {
+		let mut state = State { history: VecDeque::new(), low_sent: false };
+
+		assert!(sample(&mut state, false, 21.0).is_empty());
+		assert_eq!(sample(&mut state, false, 20.0), vec![DeviceEvent::LowBattery { percentage: 20.0 }]);
+		assert!(sample(&mut state, false, 19.0).is_empty());
+
+		// hovering around the threshold doesn't count as recovering
+		assert!(sample(&mut state, false, 24.0).is_empty());
+		assert!(sample(&mut state, false, 18.0).is_empty());
+
+		assert!(sample(&mut state, false, 26.0).is_empty());
+		assert_eq!(sample(&mut state, false, 15.0), vec![DeviceEvent::LowBattery { percentage: 15.0 }]);
+
+		// and so does charging
+		assert_eq!(sample(&mut state, true, 15.0), vec![DeviceEvent::StartedCharging { percentage: 15.0 }]);
+		assert_eq!(sample(&mut state, false, 15.0), vec![
+			DeviceEvent::StoppedCharging { percentage: 15.0 },
+			DeviceEvent::LowBattery { percentage: 15.0 },
+		]);
+
+		assert_eq!(state.history.len(), 3);
+		assert_eq!(state.history.back().unwrap().state.percentage, 15.0);
+	}
+
+	#[test]
+	fn first_sample_isnt_a_charging_change() {
+		let mut state = State { history: VecDeque::new(), low_sent: false };
+
+		assert!(sample(&mut state, true, 50.0).is_empty());
+		assert!(sample(&mut state, true, 60.0).is_empty());
+	}
+}
@@ -0,0 +1,172 @@
+use std::{
+	future::Future,
+	pin::Pin,
+	sync::Arc,
+};
+use futures_util::future::{select, Either};
+use crate::{
+	commands::APICommand,
+	socket::{Notifications, SocketResponse},
+};
+
+// Something that keeps track of one kind of notification, e.g. `TypingTracker`
+// for `Typing`. Each one only has to say which notifications it wants and what
+// to do with them; `handle` can then be called with every notification, and a
+// `Dispatcher` can feed a few of them from the same receiver.
+pub trait NotificationHandler: Send + Sync {
+	fn handles(&self, command: &APICommand) -> bool;
+
+	// called with each notification that `handles` said yes to
+	fn notify(&self, notif: SocketResponse) -> anyhow::Result<()>;
+
+	// passes `notif` to `notify` if this handles it, and returns whether it did
+	fn handle(&self, notif: &SocketResponse) -> bool {
+		if !self.handles(&notif.command) {
+			return false;
+		}
+
+		if let Err(err) = self.notify(notif.clone()) {
+			tracing::warn!(
+				command = %notif.command.command_string(), error = %err, "couldn't handle notification"
+			);
+		}
+
+		true
+	}
+}
+
+impl<T: NotificationHandler + ?Sized> NotificationHandler for Arc<T> {
+	fn handles(&self, command: &APICommand) -> bool {
+		(**self).handles(command)
+	}
+
+	fn notify(&self, notif: SocketResponse) -> anyhow::Result<()> {
+		(**self).notify(notif)
+	}
+}
+
+// Passes each notification to every handler that wants it, e.g.
+//
+// let dispatcher = Dispatcher::new()
+//     .with_handler(tracker.clone())
+//     .with_handler(Arc::new(device_status));
+//
+// tokio::spawn(async move { dispatcher.run(notifications, shutdown).await });
+#[derive(Clone, Default)]
+pub struct Dispatcher {
+	handlers: Vec<Arc<dyn NotificationHandler>>,
+}
+
+impl Dispatcher {
+	pub fn new() -> Dispatcher {
+		Dispatcher::default()
+	}
+
+	pub fn with_handler(mut self, handler: impl NotificationHandler + 'static) -> Self {
+		self.handlers.push(Arc::new(handler));
+		self
+	}
+
+	// returns whether any of the handlers wanted it. Every handler that wants
+	// it gets it, not just the first one.
+	pub fn dispatch(&self, notif: &SocketResponse) -> bool {
+		let mut handled = false;
+
+		for handler in self.handlers.iter() {
+			handled |= handler.handle(notif);
+		}
+
+		handled
+	}
+
+	// dispatches everything from `notifications` (the receiver for the sender that
+	// was passed to `APIClient::new`) until `shutdown` finishes
+	pub async fn run(
+		&self,
+		notifications: crossbeam_channel::Receiver<SocketResponse>,
+		shutdown: impl Future<Output = ()>,
+	) {
+		let mut notifications = Notifications::new(notifications);
+		let mut shutdown = Shutdown::new(shutdown);
+
+		while let Some(Some(notif)) = shutdown.or(notifications.recv()).await {
+			if !self.dispatch(&notif) {
+				tracing::trace!(command = %notif.command.command_string(), "nothing handles notification");
+			}
+		}
+	}
+}
+
+// Lets a loop wait on something (e.g. a sleep, or the next notification) and on
+// `shutdown` at the same time, which is what each of the `run` functions do.
+pub(crate) struct Shutdown<F> {
+	signal: Pin<Box<F>>,
+	done: bool,
+}
+
+impl<F: Future<Output = ()>> Shutdown<F> {
+	pub(crate) fn new(signal: F) -> Shutdown<F> {
+		Shutdown {
+			signal: Box::pin(signal),
+			done: false,
+		}
+	}
+
+	// waits for `fut`, or returns None if the shutdown finished first (and from then on)
+	pub(crate) async fn or<T>(&mut self, fut: impl Future<Output = T>) -> Option<T> {
+		if self.done {
+			return None;
+		}
+
+		match select(Box::pin(fut), self.signal.as_mut()).await {
+			Either::Left((val, _)) => Some(val),
+			Either::Right(_) => {
+				self.done = true;
+				None
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::typing::TypingTracker;
+
+	fn notif(command: APICommand, data: serde_json::Value) -> SocketResponse {
+		SocketResponse { id: String::new(), last: true, command, data }
+	}
+
+	#[test]
+	fn dispatches_to_every_handler_that_wants_it() {
+		let (first, second) = (TypingTracker::new(), TypingTracker::new());
+		let dispatcher = Dispatcher::new()
+			.with_handler(first.clone())
+			.with_handler(Arc::new(second.clone()));
+
+		let typing = notif(APICommand::Typing, serde_json::json!({ "chat": "a", "active": true }));
+		assert!(dispatcher.dispatch(&typing));
+		assert!(first.is_typing("a") && second.is_typing("a"));
+
+		// one that can't be parsed is still theirs, it's just logged
+		assert!(first.handle(&notif(APICommand::Typing, serde_json::json!("garbage"))));
+		assert!(!dispatcher.dispatch(&notif(APICommand::NewMessage, serde_json::Value::Null)));
+	}
+
+	#[test]
+	fn stops_waiting_on_shutdown() {
+		let rt = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+
+		rt.block_on(async {
+			let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+			let mut shutdown = Shutdown::new(async { let _ = stopped.await; });
+
+			assert_eq!(shutdown.or(async { 1 }).await, Some(1));
+
+			stop.send(()).unwrap();
+			assert_eq!(shutdown.or(futures_util::future::pending::<()>()).await, None);
+			// and it stays shut down
+			assert_eq!(shutdown.or(async { 2 }).await, None);
+		});
+	}
+}
@@ -0,0 +1,318 @@
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use crate::{
+	api::APIClient,
+	models::{Attachment, Message},
+};
+
+// how the downloaded attachments are sorted into directories
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirLayout {
+	// everything goes straight into the download directory
+	Flat,
+	// `${chat}/`
+	PerChat,
+	// `${yyyy-mm-dd}/`
+	PerDate,
+	// `${chat}/${yyyy-mm-dd}/`
+	PerChatDate,
+}
+
+// an attachment to download, along with what's known about the message it
+// came from so that it can be put in the right directory
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DownloadItem {
+	pub attachment: Attachment,
+	pub chat: Option<String>,
+	pub message_guid: Option<String>,
+	// which of the message's attachments this is
+	#[serde(default)]
+	pub index: usize,
+	pub date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+pub enum DownloadEvent {
+	Started { path: PathBuf },
+	// the file was already on disk, so it wasn't downloaded again
+	Skipped { path: PathBuf },
+	Finished { path: PathBuf, bytes: usize },
+	Failed { path: PathBuf, error: String },
+	// sent after each attachment is done, whether it succeeded or not
+	Progress { completed: usize, total: usize },
+}
+
+#[derive(Debug, Default)]
+pub struct DownloadReport {
+	pub downloaded: Vec<PathBuf>,
+	pub skipped: Vec<PathBuf>,
+	pub failed: Vec<(DownloadItem, anyhow::Error)>,
+}
+
+// Downloads attachments with `get_attachment`, a few at a time. Each one is
+// written to a `.part` file first and only renamed once it's complete, so a
+// file that's on disk is always whole. If a download is interrupted, running it
+// again skips the files that were already downloaded, and over REST, carries on
+// with the ones that were only partly written from where they got to (the
+// socket can't ask for part of a file, so they start over there). Failed
+// downloads are retried according to the client's `RetryPolicy`.
+pub struct DownloadManager<'a> {
+	client: &'a APIClient,
+	dir: PathBuf,
+	layout: DirLayout,
+	concurrency: usize,
+	progress: Option<crossbeam_channel::Sender<DownloadEvent>>,
+}
+
+enum Outcome {
+	Downloaded,
+	Skipped,
+}
+
+impl DownloadItem {
+	pub fn from_attachment(attachment: Attachment) -> DownloadItem {
+		DownloadItem {
+			attachment,
+			chat: None,
+			message_guid: None,
+			index: 0,
+			date: None,
+		}
+	}
+
+	pub fn from_message(message: &Message) -> Vec<DownloadItem> {
+		message.attachments.iter()
+			.enumerate()
+			.map(|(index, att)| DownloadItem {
+				attachment: att.clone(),
+				chat: message.chat_identifier.to_owned(),
+				message_guid: Some(message.guid.to_owned()),
+				index,
+				date: message.datetime(),
+			})
+			.collect()
+	}
+
+	// `get_messages` doesn't always fill in `chat_identifier`, so this can be used
+	// to set it to the chat that the messages were requested from
+	pub fn with_chat(mut self, chat: impl Into<String>) -> Self {
+		self.chat = Some(chat.into());
+		self
+	}
+}
+
+impl<'a> DownloadManager<'a> {
+	pub fn new(client: &'a APIClient, dir: impl Into<PathBuf>) -> Self {
+		DownloadManager {
+			client,
+			dir: dir.into(),
+			layout: DirLayout::PerChatDate,
+			concurrency: 4,
+			progress: None,
+		}
+	}
+
+	pub fn with_layout(mut self, layout: DirLayout) -> Self {
+		self.layout = layout;
+		self
+	}
+
+	// how many attachments are downloaded at once
+	pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+		self.concurrency = concurrency.max(1);
+		self
+	}
+
+	pub fn with_progress(mut self, sender: crossbeam_channel::Sender<DownloadEvent>) -> Self {
+		self.progress = Some(sender);
+		self
+	}
+
+	// where this item will be saved
+	pub fn path_for(&self, item: &DownloadItem) -> PathBuf {
+		let chat = || sanitize(item.chat.as_deref().unwrap_or("unknown-chat"));
+		let date = || item.date
+			.map(|d| d.format("%Y-%m-%d").to_string())
+			.unwrap_or_else(|| "unknown-date".to_owned());
+
+		let dir = match self.layout {
+			DirLayout::Flat => self.dir.to_owned(),
+			DirLayout::PerChat => self.dir.join(chat()),
+			DirLayout::PerDate => self.dir.join(date()),
+			DirLayout::PerChatDate => self.dir.join(chat()).join(date()),
+		};
+
+		dir.join(file_name(item))
+	}
+
+	pub async fn download_messages(&self, messages: &[Message]) -> DownloadReport {
+		self.download(messages.iter().flat_map(DownloadItem::from_message).collect()).await
+	}
+
+	pub async fn download(&self, items: Vec<DownloadItem>) -> DownloadReport {
+		let total = items.len();
+		let mut report = DownloadReport::default();
+
+		let mut results = futures_util::stream::iter(items)
+			.map(|item| async move {
+				let path = self.path_for(&item);
+				let res = self.download_one(&item, &path).await;
+				(item, path, res)
+			})
+			.buffer_unordered(self.concurrency);
+
+		let mut completed = 0;
+
+		while let Some((item, path, res)) = results.next().await {
+			completed += 1;
+
+			match res {
+				Ok(Outcome::Downloaded) => report.downloaded.push(path),
+				Ok(Outcome::Skipped) => report.skipped.push(path),
+				Err(err) => {
+					tracing::warn!(path = ?path, error = %err, "failed to download attachment");
+					self.send(DownloadEvent::Failed { path, error: err.to_string() });
+					report.failed.push((item, err));
+				}
+			}
+
+			self.send(DownloadEvent::Progress { completed, total });
+		}
+
+		report
+	}
+
+	async fn download_one(&self, item: &DownloadItem, path: &Path) -> anyhow::Result<Outcome> {
+		if tokio::fs::try_exists(path).await? {
+			self.send(DownloadEvent::Skipped { path: path.to_owned() });
+			return Ok(Outcome::Skipped);
+		}
+
+		self.send(DownloadEvent::Started { path: path.to_owned() });
+
+		if let Some(parent) = path.parent() {
+			tokio::fs::create_dir_all(parent).await?;
+		}
+
+		let mut part = path.as_os_str().to_owned();
+		part.push(".part");
+		let part = PathBuf::from(part);
+
+		let bytes = match self.client.uses_rest {
+			true => self.resume(item, &part).await? as usize,
+			false => {
+				let data = self.client.get_attachment(&item.attachment.path).await?;
+				tokio::fs::write(&part, &data.bytes).await?;
+				data.size
+			},
+		};
+
+		tokio::fs::rename(&part, path).await?;
+
+		tracing::debug!(path = ?path, bytes, "downloaded attachment");
+		self.send(DownloadEvent::Finished { path: path.to_owned(), bytes });
+
+		Ok(Outcome::Downloaded)
+	}
+
+	// downloads the rest of `part`, retrying the same way that `get_attachment`
+	// does. Each try carries on from what the last one wrote.
+	async fn resume(&self, item: &DownloadItem, part: &Path) -> anyhow::Result<u64> {
+		let mut attempt = 1;
+
+		loop {
+			let err = match self.client.rest_client.download_attachment(&item.attachment.path, part).await {
+				Ok(size) => return Ok(size),
+				Err(err) => err,
+			};
+
+			match self.client.retry_policy.retry_delay(attempt, &err) {
+				Some(delay) => {
+					tracing::info!(
+						attempt, error = %err, delay_ms = delay.as_millis() as u64, "retrying download"
+					);
+					tokio::time::sleep(delay).await;
+					attempt += 1;
+				},
+				None => return Err(err),
+			}
+		}
+	}
+
+	fn send(&self, event: DownloadEvent) {
+		if let Some(sender) = &self.progress {
+			// it's fine if nobody's listening for progress
+			let _ = sender.send(event);
+		}
+	}
+}
+
+fn file_name(item: &DownloadItem) -> String {
+	attachment_file_name(item.message_guid.as_deref(), item.index, &item.attachment.file_name())
+}
+
+// Attachments from different messages often have the same name (e.g.
+// `IMG_0001.jpg`), so they're prefixed with part of the message's guid. One
+// message can also have a few attachments with the same name, so every one
+// after the first gets its index too, e.g. `8F3A1B2C-1_IMG_0001.jpg`. This is
+// also used by `Export`, so the result is always safe to join onto a directory.
+pub(crate) fn attachment_file_name(guid: Option<&str>, index: usize, name: &str) -> String {
+	let name = match guid.map(|g| g.chars().take(8).collect::<String>()) {
+		Some(guid) if index > 0 => format!("{}-{}_{}", guid, index, name),
+		Some(guid) => format!("{}_{}", guid, name),
+		None => name.to_owned(),
+	};
+
+	sanitize(&name)
+}
+
+// makes sure that a chat identifier or guid can be used as a single path component
+fn sanitize(name: &str) -> String {
+	let clean: String = name.chars()
+		.map(|c| match c {
+			'/' | '\\' | ':' | '\0' => '_',
+			c => c,
+		})
+		.collect();
+
+	match clean.trim_start_matches('.') {
+		"" => "_".to_owned(),
+		_ => clean,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn item(guid: Option<&str>, index: usize, path: &str) -> DownloadItem {
+		DownloadItem {
+			attachment: Attachment { mime_type: "image/jpeg".to_owned(), path: path.to_owned() },
+			chat: None,
+			message_guid: guid.map(str::to_owned),
+			index,
+			date: None,
+		}
+	}
+
+	#[test]
+	fn names_dont_collide() {
+		let guid = Some("8F3A1B2C-0000-4000-8000-000000000000");
+
+		assert_eq!(file_name(&item(guid, 0, "a/IMG_0001.jpg")), "8F3A1B2C_IMG_0001.jpg");
+		assert_eq!(file_name(&item(guid, 1, "b/IMG_0001.jpg")), "8F3A1B2C-1_IMG_0001.jpg");
+		assert_eq!(file_name(&item(None, 3, "a/IMG_0001.jpg")), "IMG_0001.jpg");
+		assert_eq!(file_name(&item(Some("p:0/ABCD"), 0, "a/x.jpg")), "p_0_ABCD_x.jpg");
+	}
+
+	#[test]
+	fn sanitizes_path_components() {
+		assert_eq!(sanitize("+15555550123"), "+15555550123");
+		assert_eq!(sanitize("chat/../../etc"), "chat_.._.._etc");
+		assert_eq!(sanitize(".."), "_");
+		assert_eq!(sanitize(""), "_");
+		assert_eq!(sanitize("a\\b:c"), "a_b_c");
+	}
+}
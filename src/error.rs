@@ -10,5 +10,21 @@ pub enum SDKError {
 	#[error("This function is not allowed by the current SDK Configuration")]
 	ConfigBlocked,
 	#[error("The data json was sent in an improper format")]
-	ImproperDataFormat
+	ImproperDataFormat,
+	#[error("The host didn't respond in time")]
+	TimedOut,
+	#[error("The host doesn't know this command")]
+	UnknownCommand
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+	#[error("Couldn't read config file {path}: {source}")]
+	Read { path: std::path::PathBuf, source: std::io::Error },
+	#[error("Couldn't parse config file {path}: {message}")]
+	Parse { path: std::path::PathBuf, message: String },
+	#[error("Invalid environment variable {var}: {message}")]
+	Env { var: String, message: String },
+	#[error("Invalid config value `{field}`: {message}")]
+	Invalid { field: &'static str, message: String },
 }
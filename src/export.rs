@@ -0,0 +1,582 @@
+use std::{
+	collections::HashMap,
+	fmt::Write as _,
+	path::{Path, PathBuf},
+};
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use serde::Serialize;
+use crate::{
+	api::{APIClient, APIRequest},
+	commands::GetMessagesRequest,
+	downloads::attachment_file_name,
+	graph::ConversationGraph,
+	models::{Attachment, Message, Tapback},
+	payload::BinaryPayload,
+};
+
+// how many messages are requested at once while paging through the history
+const PAGE_SIZE: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+	// everything, including the attachments (as base64), in one file
+	Json,
+	// a single page, with images inline and the other attachments as links
+	Html,
+	// attachments are saved in a directory next to the file, and linked
+	Markdown,
+	// attachments are saved the same way as with `Markdown`
+	Text,
+}
+
+// Fetches a conversation so that it can be written out in one of the
+// `ExportFormat`s, e.g.
+//
+// let export = Exporter::new(&client, "+15555555555")
+//     .with_after(start)
+//     .fetch()
+//     .await?;
+//
+// export.write("chat.html", ExportFormat::Html)?;
+pub struct Exporter<'a> {
+	client: &'a APIClient,
+	chat: String,
+	after: Option<DateTime<Utc>>,
+	before: Option<DateTime<Utc>>,
+	attachments: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Export {
+	pub chat: String,
+	pub display_name: Option<String>,
+	pub exported_at: DateTime<Utc>,
+	// oldest first
+	pub messages: Vec<ExportedMessage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportedMessage {
+	pub guid: String,
+	pub date: Option<DateTime<Utc>>,
+	pub is_from_me: bool,
+	// the sender's name, or their address if it couldn't be resolved
+	pub sender: String,
+	pub subject: String,
+	pub text: String,
+	pub tapbacks: Vec<ExportedTapback>,
+	pub attachments: Vec<ExportedAttachment>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportedTapback {
+	pub sender: String,
+	pub tapback: Tapback,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportedAttachment {
+	pub file_name: String,
+	pub mime_type: String,
+	#[serde(rename = "data_base64", serialize_with = "serialize_data")]
+	pub data: Option<BinaryPayload>,
+	// why `data` is missing, if it couldn't be downloaded
+	pub error: Option<String>,
+}
+
+impl<'a> Exporter<'a> {
+	pub fn new(client: &'a APIClient, chat: impl Into<String>) -> Self {
+		Exporter {
+			client,
+			chat: chat.into(),
+			after: None,
+			before: None,
+			attachments: true,
+		}
+	}
+
+	// only export messages sent at or after this date
+	pub fn with_after(mut self, after: DateTime<Utc>) -> Self {
+		self.after = Some(after);
+		self
+	}
+
+	// only export messages sent before this date
+	pub fn with_before(mut self, before: DateTime<Utc>) -> Self {
+		self.before = Some(before);
+		self
+	}
+
+	// whether to download the attachments, or just list them
+	pub fn with_attachments(mut self, attachments: bool) -> Self {
+		self.attachments = attachments;
+		self
+	}
+
+	pub async fn fetch(&self) -> anyhow::Result<Export> {
+		let display_name = self.client.get_conversation(&self.chat).await
+			.ok()
+			.map(|c| c.display_name)
+			.filter(|n| !n.is_empty());
+
+		let messages = self.fetch_messages().await?;
+		let names = self.resolve_names(&messages).await;
+		let name_of = |is_from_me: bool, sender: Option<&String>| match (sender, is_from_me) {
+			(_, true) => "Me".to_owned(),
+			(Some(addr), _) => names.get(addr).cloned().unwrap_or_else(|| addr.to_owned()),
+			(None, _) => display_name.clone().unwrap_or_else(|| self.chat.to_owned()),
+		};
+
+		// tapbacks are their own messages, so this puts them with the ones they're on
+		let graph = ConversationGraph::new(messages.iter().cloned());
+
+		let mut exported = Vec::new();
+
+		for msg in messages.iter().filter(|m| m.tapback().is_none()) {
+			exported.push(ExportedMessage {
+				guid: msg.guid.to_owned(),
+				date: msg.datetime(),
+				is_from_me: msg.is_from_me,
+				sender: name_of(msg.is_from_me, msg.sender.as_ref()),
+				subject: msg.subject.to_owned(),
+				text: msg.text.to_owned(),
+				tapbacks: graph.get(&msg.guid)
+					.map(|node| node.reactions.iter()
+						.map(|r| ExportedTapback {
+							sender: name_of(r.is_from_me, r.sender.as_ref()),
+							tapback: r.tapback,
+						})
+						.collect())
+					.unwrap_or_default(),
+				attachments: self.fetch_attachments(&msg.attachments).await,
+			});
+		}
+
+		Ok(Export {
+			chat: self.chat.to_owned(),
+			display_name,
+			exported_at: Utc::now(),
+			messages: exported,
+		})
+	}
+
+	// every message in the date range, oldest first
+	async fn fetch_messages(&self) -> anyhow::Result<Vec<Message>> {
+		let mut messages = Vec::new();
+		let mut offset = 0;
+
+		loop {
+			let page = GetMessagesRequest::new(self.chat.to_owned())
+				.with_num_messages(PAGE_SIZE)
+				.with_messages_offset(offset)
+				.with_read_messages(false)
+				.execute(self.client)
+				.await?;
+
+			offset += page.len() as u32;
+
+			// the pages are newest first, so once they're older than the
+			// range, there's nothing left to get
+			let done = (page.len() as u32) < PAGE_SIZE || page.last()
+				.and_then(Message::datetime)
+				.zip(self.after)
+				.map(|(date, after)| date < after)
+				.unwrap_or(false);
+
+			messages.extend(page.into_iter().filter(|m| self.in_range(m)));
+
+			if done {
+				break;
+			}
+		}
+
+		messages.reverse();
+		Ok(messages)
+	}
+
+	fn in_range(&self, message: &Message) -> bool {
+		let date = match message.datetime() {
+			Some(date) => date,
+			None => return self.after.is_none() && self.before.is_none(),
+		};
+
+		self.after.map(|a| date >= a).unwrap_or(true)
+			&& self.before.map(|b| date < b).unwrap_or(true)
+	}
+
+	async fn resolve_names(&self, messages: &[Message]) -> HashMap<String, String> {
+		let mut addresses: Vec<&String> = messages.iter()
+			.filter_map(|m| m.sender.as_ref())
+			.collect();
+
+		addresses.sort();
+		addresses.dedup();
+
+		futures_util::stream::iter(addresses)
+			.map(|addr| async move {
+				let name = self.client.resolve_name(addr).await.ok()
+					.filter(|n| !n.is_empty());
+				(addr.to_owned(), name)
+			})
+			.buffer_unordered(self.client.batch_concurrency)
+			.filter_map(|(addr, name)| async move { name.map(|n| (addr, n)) })
+			.collect()
+			.await
+	}
+
+	async fn fetch_attachments(&self, attachments: &[Attachment]) -> Vec<ExportedAttachment> {
+		let mut exported = Vec::new();
+
+		for att in attachments {
+			let (data, error) = match self.attachments {
+				true => match self.client.get_attachment(&att.path).await {
+					Ok(data) => (Some(data), None),
+					Err(err) => {
+						tracing::warn!(path = %att.path, error = %err, "failed to download attachment for export");
+						(None, Some(err.to_string()))
+					},
+				},
+				false => (None, None),
+			};
+
+			exported.push(ExportedAttachment {
+				file_name: att.file_name(),
+				mime_type: att.mime_type.to_owned(),
+				data,
+				error,
+			});
+		}
+
+		exported
+	}
+}
+
+impl Export {
+	// `title` is the name of the conversation, or the chat identifier if it doesn't have one
+	pub fn title(&self) -> &str {
+		self.display_name.as_deref().unwrap_or(&self.chat)
+	}
+
+	// Writes the export to `path`. For `Markdown` and `Text`, the attachments
+	// are saved in a directory next to it (e.g. `chat_attachments/` for `chat.md`).
+	pub fn write(&self, path: impl AsRef<Path>, format: ExportFormat) -> anyhow::Result<()> {
+		let path = path.as_ref();
+
+		let attachment_dir = match format {
+			ExportFormat::Markdown | ExportFormat::Text => Some(self.write_attachments(path)?),
+			_ => None,
+		};
+
+		let rendered = match format {
+			ExportFormat::Json => self.to_json()?,
+			ExportFormat::Html => self.to_html(),
+			ExportFormat::Markdown => self.to_markdown(attachment_dir.as_deref()),
+			ExportFormat::Text => self.to_text(attachment_dir.as_deref()),
+		};
+
+		std::fs::write(path, rendered)?;
+		Ok(())
+	}
+
+	pub fn to_json(&self) -> anyhow::Result<String> {
+		Ok(serde_json::to_string_pretty(self)?)
+	}
+
+	pub fn to_html(&self) -> String {
+		let mut out = String::new();
+
+		let _ = write!(
+			out,
+			"<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+			<style>\n{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+			escape_html(self.title()), HTML_STYLE, escape_html(self.title())
+		);
+
+		for msg in self.messages.iter() {
+			let class = if msg.is_from_me { "message me" } else { "message" };
+			let _ = write!(
+				out,
+				"<div class=\"{}\">\n<div class=\"meta\">{} &middot; {}</div>\n",
+				class, escape_html(&msg.sender), format_date(msg.date)
+			);
+
+			if !msg.subject.is_empty() {
+				let _ = writeln!(out, "<div class=\"subject\">{}</div>", escape_html(&msg.subject));
+			}
+
+			if !msg.text.is_empty() {
+				let _ = writeln!(out, "<div class=\"text\">{}</div>", escape_html(&msg.text));
+			}
+
+			for att in msg.attachments.iter() {
+				let _ = writeln!(out, "{}", html_attachment(att));
+			}
+
+			if !msg.tapbacks.is_empty() {
+				let tapbacks = msg.tapbacks.iter()
+					.map(|t| format!(
+						"<span title=\"{}\">{}</span>", escape_html(&t.sender), t.tapback.emoji()
+					))
+					.collect::<Vec<String>>()
+					.join(" ");
+
+				let _ = writeln!(out, "<div class=\"tapbacks\">{}</div>", tapbacks);
+			}
+
+			out.push_str("</div>\n");
+		}
+
+		out.push_str("</body>\n</html>\n");
+		out
+	}
+
+	// `attachment_dir` is where the attachments were saved, if they were. Like
+	// with `to_html`, everything from the messages is escaped, so that e.g. a
+	// name with `*` in it doesn't turn the rest of the line bold.
+	pub fn to_markdown(&self, attachment_dir: Option<&Path>) -> String {
+		let mut out = format!("# {}\n\n", escape_markdown(self.title()));
+
+		for msg in self.messages.iter() {
+			let _ = writeln!(
+				out, "**{}** _{}_\n", escape_markdown(&msg.sender), format_date(msg.date)
+			);
+
+			if !msg.subject.is_empty() {
+				let _ = writeln!(out, "**{}**\n", escape_markdown(&msg.subject));
+			}
+
+			if !msg.text.is_empty() {
+				// each line has to be quoted, or only the first one would be
+				for line in msg.text.lines() {
+					let _ = writeln!(out, "> {}", escape_markdown(line));
+				}
+				out.push('\n');
+			}
+
+			for (index, att) in msg.attachments.iter().enumerate() {
+				let link = attachment_link(att, &msg.guid, index, attachment_dir);
+				let bang = if att.mime_type.starts_with("image/") { "!" } else { "" };
+				let _ = writeln!(
+					out, "{}[{}](<{}>)\n", bang, escape_markdown(&att.file_name), escape_markdown(&link)
+				);
+			}
+
+			if !msg.tapbacks.is_empty() {
+				let _ = writeln!(out, "{}\n", escape_markdown(&tapback_summary(msg)));
+			}
+		}
+
+		out
+	}
+
+	pub fn to_text(&self, attachment_dir: Option<&Path>) -> String {
+		let mut out = format!("{}\n\n", self.title());
+
+		for msg in self.messages.iter() {
+			let _ = writeln!(out, "[{}] {}:", format_date(msg.date), msg.sender);
+
+			if !msg.subject.is_empty() {
+				let _ = writeln!(out, "  Subject: {}", msg.subject);
+			}
+
+			for line in msg.text.lines() {
+				let _ = writeln!(out, "  {}", line);
+			}
+
+			for (index, att) in msg.attachments.iter().enumerate() {
+				let link = attachment_link(att, &msg.guid, index, attachment_dir);
+				let _ = writeln!(out, "  Attachment: {}", link);
+			}
+
+			if !msg.tapbacks.is_empty() {
+				let _ = writeln!(out, "  {}", tapback_summary(msg));
+			}
+
+			out.push('\n');
+		}
+
+		out
+	}
+
+	// saves the attachments that were downloaded in a directory next to `path`,
+	// and returns the path to that directory
+	fn write_attachments(&self, path: &Path) -> anyhow::Result<PathBuf> {
+		let stem = path.file_stem()
+			.map(|s| s.to_string_lossy().into_owned())
+			.unwrap_or_else(|| "export".to_owned());
+
+		let dir = path.with_file_name(format!("{}_attachments", stem));
+
+		for msg in self.messages.iter() {
+			for (index, att) in msg.attachments.iter().enumerate() {
+				if let Some(data) = &att.data {
+					let name = attachment_file_name(Some(&msg.guid), index, &att.file_name);
+
+					std::fs::create_dir_all(&dir)?;
+					std::fs::write(dir.join(name), &data.bytes)?;
+				}
+			}
+		}
+
+		Ok(dir)
+	}
+}
+
+const HTML_STYLE: &str = "\
+body { font-family: -apple-system, Helvetica, sans-serif; max-width: 48em; margin: auto; }
+.message { margin: 1em 0; padding: 0.5em 0.75em; border-radius: 1em; background: #e5e5ea; }
+.message.me { background: #0b84ff; color: white; }
+.meta { font-size: 0.8em; opacity: 0.7; }
+.subject { font-weight: bold; }
+.text { white-space: pre-wrap; }
+.tapbacks { font-size: 0.9em; }
+img, video { max-width: 100%; border-radius: 0.5em; }
+";
+
+fn html_attachment(att: &ExportedAttachment) -> String {
+	let name = escape_html(&att.file_name);
+
+	let data = match &att.data {
+		Some(data) => data,
+		None => return format!("<div class=\"attachment missing\">{} (not downloaded)</div>", name),
+	};
+
+	// prefer the type that the host sent with the data
+	let mime = data.mime_type.as_deref().unwrap_or(&att.mime_type);
+	let uri = format!("data:{};base64,{}", escape_html(mime), base64::encode(&data.bytes));
+
+	if mime.starts_with("image/") {
+		format!("<img src=\"{}\" alt=\"{}\">", uri, name)
+	} else if mime.starts_with("video/") {
+		format!("<video controls src=\"{}\"></video>", uri)
+	} else if mime.starts_with("audio/") {
+		format!("<audio controls src=\"{}\"></audio>", uri)
+	} else {
+		format!("<a download=\"{}\" href=\"{}\">{}</a>", name, uri, name)
+	}
+}
+
+// where the `index`th attachment of the message with this `guid` was saved, named
+// the same way that the `DownloadManager` names them
+fn attachment_link(
+	att: &ExportedAttachment, guid: &str, index: usize, dir: Option<&Path>
+) -> String {
+	match (&att.data, dir.and_then(Path::file_name)) {
+		(Some(_), Some(dir)) => format!(
+			"{}/{}", dir.to_string_lossy(), attachment_file_name(Some(guid), index, &att.file_name)
+		),
+		_ => format!("{} (not downloaded)", att.file_name),
+	}
+}
+
+fn tapback_summary(msg: &ExportedMessage) -> String {
+	msg.tapbacks.iter()
+		.map(|t| format!("{} {}", t.tapback.emoji(), t.sender))
+		.collect::<Vec<String>>()
+		.join(", ")
+}
+
+fn format_date(date: Option<DateTime<Utc>>) -> String {
+	date.map(|d| d.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+		.unwrap_or_else(|| "unknown date".to_owned())
+}
+
+fn escape_html(text: &str) -> String {
+	let mut out = String::with_capacity(text.len());
+
+	for c in text.chars() {
+		match c {
+			'&' => out.push_str("&amp;"),
+			'<' => out.push_str("&lt;"),
+			'>' => out.push_str("&gt;"),
+			'"' => out.push_str("&quot;"),
+			'\'' => out.push_str("&#39;"),
+			c => out.push(c),
+		}
+	}
+
+	out
+}
+
+// backslash-escapes anything that Markdown would treat as formatting (or html),
+// and keeps line breaks from ending the paragraph or heading that it's in
+fn escape_markdown(text: &str) -> String {
+	let mut out = String::with_capacity(text.len());
+
+	for c in text.chars() {
+		match c {
+			'\\' | '`' | '*' | '_' | '[' | ']' | '(' | ')' | '#' | '<' | '>' | '!' | '|' | '~' => {
+				out.push('\\');
+				out.push(c);
+			},
+			'\r' | '\n' => out.push(' '),
+			c => out.push(c),
+		}
+	}
+
+	out
+}
+
+fn serialize_data<S: serde::Serializer>(
+	data: &Option<BinaryPayload>, serializer: S
+) -> Result<S::Ok, S::Error> {
+	match data {
+		Some(data) => serializer.serialize_str(&base64::encode(&data.bytes)),
+		None => serializer.serialize_none(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn attachment(file_name: &str) -> ExportedAttachment {
+		ExportedAttachment {
+			file_name: file_name.to_owned(),
+			mime_type: "image/png".to_owned(),
+			data: Some(BinaryPayload::new(vec![1, 2, 3])),
+			error: None,
+		}
+	}
+
+	fn export() -> Export {
+		Export {
+			chat: "chat".to_owned(),
+			display_name: Some("*Friends*".to_owned()),
+			exported_at: Utc::now(),
+			messages: vec![ExportedMessage {
+				guid: "ab/cd:ef-0000".to_owned(),
+				date: None,
+				is_from_me: false,
+				sender: "[Jane](http://evil) <b>".to_owned(),
+				subject: "# not a heading\nreally".to_owned(),
+				text: "1 * 2".to_owned(),
+				tapbacks: Vec::new(),
+				attachments: vec![attachment("a.png"), attachment("a.png")],
+			}],
+		}
+	}
+
+	#[test]
+	fn escapes_markdown() {
+		let md = export().to_markdown(Some(Path::new("/tmp/chat_attachments")));
+
+		assert!(md.starts_with("# \\*Friends\\*\n"), "{}", md);
+		assert!(md.contains("**\\[Jane\\]\\(http://evil\\) \\<b\\>**"), "{}", md);
+		assert!(md.contains("**\\# not a heading really**"), "{}", md);
+		assert!(md.contains("> 1 \\* 2"), "{}", md);
+	}
+
+	#[test]
+	fn saves_attachments_under_safe_distinct_names() {
+		let md = export().to_markdown(Some(Path::new("/tmp/chat_attachments")));
+
+		// the guid can't make the path escape the directory
+		assert!(md.contains("![a.png](<chat\\_attachments/ab\\_cd\\_ef\\_a.png>)"), "{}", md);
+		assert!(md.contains("![a.png](<chat\\_attachments/ab\\_cd\\_ef-1\\_a.png>)"), "{}", md);
+
+		assert_eq!(attachment_file_name(Some("../../x"), 0, "a.png"), ".._.._x_a.png");
+		assert_eq!(attachment_file_name(None, 0, ".."), "_");
+	}
+}
@@ -0,0 +1,318 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use crate::models::{Message, Tapback};
+
+#[derive(Debug, Clone)]
+pub struct Reaction {
+	pub tapback: Tapback,
+	// `None` for reactions from this phone
+	pub sender: Option<String>,
+	pub is_from_me: bool,
+	// the guid and date of the message that added it
+	pub guid: String,
+	pub date: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageNode {
+	pub message: Message,
+	// the reactions that are still on the message, oldest first. Each person
+	// only has one reaction on a message at a time, like in Messages.app.
+	pub reactions: Vec<Reaction>,
+	// the last reaction each person took back, if they haven't reacted since,
+	// with the guid and date of the message that removed it
+	pub removed: Vec<Reaction>,
+	pub stickers: Vec<Message>,
+	// for inline replies, the guid of the message that started the thread
+	pub parent: Option<String>,
+	// the guids of the inline replies to this message, oldest first
+	pub replies: Vec<String>,
+}
+
+impl MessageNode {
+	// how many of each tapback the message has, e.g. for `❤️ 2 👍 1`
+	pub fn reaction_counts(&self) -> BTreeMap<Tapback, usize> {
+		let mut counts = BTreeMap::new();
+
+		for reaction in self.reactions.iter() {
+			*counts.entry(reaction.tapback).or_insert(0) += 1;
+		}
+
+		counts
+	}
+}
+
+// A conversation with the messages that are associated with other messages
+// (tapbacks and stickers) attached to the messages they belong to, and inline
+// replies threaded under the message that started their thread, e.g.
+//
+// let graph = ConversationGraph::new(client.get_messages(chat, Some(100), None, None).await?);
+//
+// for node in graph.roots() {
+//     println!("{} {:?}", node.message.text, node.reaction_counts());
+//
+//     for reply in graph.replies(&node.message.guid) {
+//         println!("  > {}", reply.message.text);
+//     }
+// }
+#[derive(Debug, Clone, Default)]
+pub struct ConversationGraph {
+	nodes: HashMap<String, MessageNode>,
+	// the guids of every message other than the tapbacks and stickers, oldest first
+	order: Vec<String>,
+	// tapbacks and stickers for messages that weren't in the list
+	// (e.g. because they're older than the page that was fetched)
+	orphans: Vec<Message>,
+	// the guids of every message that was added, so that overlapping pages
+	// don't add the same tapback or sticker twice
+	seen: HashSet<String>,
+}
+
+impl ConversationGraph {
+	// the messages can be in any order, e.g. newest first like `get_messages` returns them
+	pub fn new(messages: impl IntoIterator<Item = Message>) -> ConversationGraph {
+		let mut graph = ConversationGraph::default();
+		graph.extend(messages);
+		graph
+	}
+
+	// adds more messages, e.g. the next page of the history
+	pub fn extend(&mut self, messages: impl IntoIterator<Item = Message>) {
+		let mut messages: Vec<Message> = messages.into_iter()
+			.filter(|m| self.seen.insert(m.guid.to_owned()))
+			.collect();
+
+		// the messages are added before the reactions and replies are attached,
+		// so that they can be attached regardless of the order they came in
+		messages.sort_by_key(|m| m.date);
+
+		let (associated, plain): (Vec<Message>, Vec<Message>) = messages.into_iter()
+			.partition(|m| m.associated_guid().is_some());
+
+		for message in plain {
+			self.order.push(message.guid.to_owned());
+			self.nodes.insert(message.guid.to_owned(), MessageNode {
+				message,
+				reactions: Vec::new(),
+				removed: Vec::new(),
+				stickers: Vec::new(),
+				parent: None,
+				replies: Vec::new(),
+			});
+		}
+
+		// older orphans might belong to the messages that were just added, and
+		// have to be attached in order along with the new ones
+		let mut associated: Vec<Message> = std::mem::take(&mut self.orphans).into_iter()
+			.chain(associated)
+			.collect();
+		associated.sort_by_key(|m| m.date);
+
+		for message in associated {
+			self.attach(message);
+		}
+
+		let nodes = &self.nodes;
+		self.order.sort_by_key(|guid| nodes.get(guid).map(|n| n.message.date));
+		self.thread();
+	}
+
+	pub fn get(&self, guid: &str) -> Option<&MessageNode> {
+		self.nodes.get(guid)
+	}
+
+	// every message, including replies, oldest first
+	pub fn messages(&self) -> impl Iterator<Item = &MessageNode> {
+		self.order.iter().filter_map(move |guid| self.nodes.get(guid))
+	}
+
+	// the messages that aren't replies, oldest first
+	pub fn roots(&self) -> impl Iterator<Item = &MessageNode> {
+		self.messages().filter(|node| node.parent.is_none())
+	}
+
+	// the inline replies to the message with `guid`, oldest first
+	pub fn replies(&self, guid: &str) -> Vec<&MessageNode> {
+		self.nodes.get(guid)
+			.map(|node| node.replies.iter().filter_map(|g| self.nodes.get(g)).collect())
+			.unwrap_or_default()
+	}
+
+	pub fn orphans(&self) -> &[Message] {
+		&self.orphans
+	}
+
+	pub fn len(&self) -> usize {
+		self.nodes.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.nodes.is_empty()
+	}
+
+	fn attach(&mut self, message: Message) {
+		let target = match message.associated_guid().and_then(|guid| self.nodes.get_mut(guid)) {
+			Some(target) => target,
+			None => {
+				self.orphans.push(message);
+				return;
+			},
+		};
+
+		if message.is_sticker() {
+			target.stickers.push(message);
+			return;
+		}
+
+		let (tapback, added) = match message.tapback() {
+			Some(tapback) => tapback,
+			// some other kind of association that there's nothing to show for
+			None => return,
+		};
+
+		let sender = sender_key(message.is_from_me, message.sender.as_deref());
+		let same_sender = |r: &Reaction| sender_key(r.is_from_me, r.sender.as_deref()) == sender;
+
+		// older pages can be added after newer ones, so a change only applies
+		// if nothing newer from the same person was already seen, including
+		// them taking their reaction back
+		if target.reactions.iter().chain(target.removed.iter()).any(|r| same_sender(r) && r.date > message.date) {
+			return;
+		}
+
+		let reaction = Reaction {
+			tapback,
+			sender: message.sender.to_owned(),
+			is_from_me: message.is_from_me,
+			guid: message.guid.to_owned(),
+			date: message.date,
+		};
+
+		target.removed.retain(|r| !same_sender(r));
+
+		if added {
+			// a new reaction from someone replaces the one they had before
+			target.reactions.retain(|r| !same_sender(r));
+			target.reactions.push(reaction);
+		} else {
+			target.reactions.retain(|r| !(same_sender(r) && r.tapback == tapback));
+			target.removed.push(reaction);
+		}
+	}
+
+	// links the inline replies to the messages that started their threads
+	fn thread(&mut self) {
+		for node in self.nodes.values_mut() {
+			node.replies.clear();
+		}
+
+		for guid in self.order.iter() {
+			let parent = self.nodes.get(guid)
+				.and_then(|node| node.message.thread_originator_guid.to_owned())
+				.filter(|parent| parent != guid && self.nodes.contains_key(parent));
+
+			if let Some(parent) = &parent {
+				if let Some(parent_node) = self.nodes.get_mut(parent) {
+					parent_node.replies.push(guid.to_owned());
+				}
+			}
+
+			if let Some(node) = self.nodes.get_mut(guid) {
+				node.parent = parent;
+			}
+		}
+	}
+}
+
+// who a reaction is from. The host sometimes sends a sender for the messages
+// from this phone and sometimes doesn't, so it's only used for everyone else.
+fn sender_key(is_from_me: bool, sender: Option<&str>) -> (bool, Option<&str>) {
+	(is_from_me, sender.filter(|_| !is_from_me))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn message(guid: &str, date: i64) -> Message {
+		let mut message = Message::typing("chat");
+		message.guid = guid.to_owned();
+		message.date = date;
+		message.sender = Some("+15555555555".to_owned());
+		message
+	}
+
+	// `typ` is 1000 for stickers, 2000 + the tapback for adding one, and 3000 + it for removing one
+	fn associated(guid: &str, date: i64, target: &str, typ: i16) -> Message {
+		let mut message = message(guid, date);
+		message.associated_message_guid = format!("p:0/{}", target);
+		message.associated_message_type = typ;
+		message
+	}
+
+	#[test]
+	fn attaches_regardless_of_page_order() {
+		// the newest page comes first, and has a tapback for a message on the next one
+		let mut graph = ConversationGraph::new(vec![
+			associated("heart", 30, "a", 2000),
+			message("b", 20),
+		]);
+		assert_eq!(graph.orphans().len(), 1);
+
+		graph.extend(vec![message("b", 20), message("a", 10)]);
+
+		assert!(graph.orphans().is_empty());
+		assert_eq!(graph.messages().map(|n| n.message.guid.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+		assert_eq!(graph.get("a").unwrap().reactions[0].tapback, Tapback::Love);
+	}
+
+	#[test]
+	fn older_pages_dont_undo_newer_changes() {
+		let mut graph = ConversationGraph::new(vec![
+			message("a", 10),
+			associated("unheart", 40, "a", 3000),
+		]);
+
+		// the heart that was taken back, and a like from before that
+		graph.extend(vec![associated("like", 20, "a", 2001), associated("heart", 30, "a", 2000)]);
+
+		let node = graph.get("a").unwrap();
+		assert!(node.reactions.is_empty());
+		assert_eq!(node.removed.len(), 1);
+		assert_eq!(node.removed[0].guid, "unheart");
+
+		// and reacting again after removing it counts
+		graph.extend(vec![associated("laugh", 50, "a", 2003)]);
+
+		let node = graph.get("a").unwrap();
+		assert_eq!(node.reactions.len(), 1);
+		assert_eq!(node.reactions[0].tapback, Tapback::Laugh);
+		assert!(node.removed.is_empty());
+	}
+
+	#[test]
+	fn overlapping_pages_arent_added_twice() {
+		let mut from_me = associated("like", 20, "a", 2001);
+		from_me.is_from_me = true;
+		from_me.sender = None;
+
+		let page = vec![
+			message("a", 10),
+			from_me,
+			associated("heart", 25, "a", 2000),
+			associated("sticker", 30, "a", 1000),
+		];
+		let mut graph = ConversationGraph::new(page.clone());
+		graph.extend(page);
+
+		let node = graph.get("a").unwrap();
+		assert_eq!(node.stickers.len(), 1);
+		assert_eq!(node.reaction_counts(), BTreeMap::from([(Tapback::Love, 1), (Tapback::Like, 1)]));
+
+		// the same person is the same person, whether or not the host sent an address for them
+		let mut from_me = associated("unlike", 40, "a", 3001);
+		from_me.is_from_me = true;
+		graph.extend(vec![from_me]);
+
+		assert_eq!(graph.get("a").unwrap().reaction_counts(), BTreeMap::from([(Tapback::Love, 1)]));
+	}
+}
@@ -1,6 +1,21 @@
 pub use commands::*;
 pub use config::*;
 pub use api::*;
+pub use raw_command::*;
+pub use secret::*;
+pub use retry::*;
+pub use payload::BinaryPayload;
+pub use contacts::ContactCacheConfig;
+pub use search::{SearchQuery, SearchHit};
+pub use export::{Exporter, ExportFormat};
+pub use backup::{Archive, Backup};
+pub use bot::{Bot, Context, Middleware, Route};
+pub use scheduler::{Scheduler, ScheduledMessage};
+pub use rules::{RuleEngine, RuleSet};
+pub use typing::{TypingIndicator, TypingTracker};
+pub use device::DeviceStatus;
+pub use graph::ConversationGraph;
+pub use dispatch::{Dispatcher, NotificationHandler};
 
 pub mod commands;
 pub mod config;
@@ -10,3 +25,21 @@ pub mod socket;
 pub mod rest_api;
 pub mod registration_type;
 pub mod models;
+pub mod raw_command;
+pub mod poller;
+pub mod secret;
+pub mod session;
+pub mod retry;
+pub mod downloads;
+pub mod payload;
+pub mod contacts;
+pub mod search;
+pub mod export;
+pub mod backup;
+pub mod bot;
+pub mod scheduler;
+pub mod rules;
+pub mod typing;
+pub mod device;
+pub mod graph;
+pub mod dispatch;
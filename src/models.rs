@@ -1,6 +1,11 @@
 use serde::{Deserialize, Serialize};
+use chrono::{DateTime, TimeZone, Utc};
 
-#[derive(Debug, Deserialize, Serialize)]
+// the dates in messages are relative to Apple's reference date (2001-01-01),
+// which is this many seconds after the unix epoch
+const APPLE_EPOCH_OFFSET: i64 = 978_307_200;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Conversation {
 	pub display_name: String,
 	pub chat_identifier: String,
@@ -15,7 +20,7 @@ pub struct Conversation {
 	pub relative_time: String
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Message {
 	pub guid: String,
 	pub date_read: Option<i64>,
@@ -30,6 +35,10 @@ pub struct Message {
 	pub text: String,
 	pub associated_message_guid: String,
 	pub associated_message_type: i16,
+	// for inline replies, the guid of the first message in the thread. Older
+	// hosts don't send this.
+	#[serde(default)]
+	pub thread_originator_guid: Option<String>,
 	pub sender: Option<String>,
 	pub chat_identifier: Option<String>,
 	#[serde(default)]
@@ -37,6 +46,14 @@ pub struct Message {
 }
 
 impl Message {
+	pub fn datetime(&self) -> Option<DateTime<Utc>> {
+		apple_date(self.date)
+	}
+
+	pub fn read_datetime(&self) -> Option<DateTime<Utc>> {
+		self.date_read.and_then(apple_date)
+	}
+
 	pub fn typing(chat: &str) -> Message {
 		Message {
 			guid: "".to_owned(),
@@ -49,6 +66,7 @@ impl Message {
 			text: "".to_owned(),
 			associated_message_guid: "".to_owned(),
 			associated_message_type: 0,
+			thread_originator_guid: None,
 			message_type: MessageType::Typing,
 			chat_identifier: Some(chat.to_owned()),
 			attachments: Vec::new(),
@@ -69,6 +87,7 @@ impl Message {
 			text: "".to_owned(),
 			associated_message_guid: "".to_owned(),
 			associated_message_type: 0,
+			thread_originator_guid: None,
 			message_type: MessageType::Idle,
 			chat_identifier: Some(chat.to_owned()),
 			attachments: Vec::new(),
@@ -76,31 +95,224 @@ impl Message {
 			sender: None,
 		}
 	}
+
+	// the guid of the message that this one is a reaction to (or otherwise
+	// associated with), without the `p:0/` or `bp:` prefix that it's stored with
+	pub fn associated_guid(&self) -> Option<&str> {
+		let guid = self.associated_message_guid.as_str();
+
+		if guid.is_empty() {
+			return None;
+		}
+
+		let guid = match guid.split_once('/') {
+			Some((prefix, rest)) if prefix.starts_with("p:") => rest,
+			_ => guid.strip_prefix("bp:").unwrap_or(guid),
+		};
+
+		Some(guid)
+	}
+
+	// whether this is a sticker placed on the message from `associated_guid`
+	pub fn is_sticker(&self) -> bool {
+		self.associated_message_type == 1000
+	}
+
+	// if this message is a tapback, which one it is and whether it was
+	// added (true) or removed (false)
+	pub fn tapback(&self) -> Option<(Tapback, bool)> {
+		let typ = self.associated_message_type;
+
+		match typ {
+			2000..=2005 => Tapback::from_index(typ - 2000).map(|t| (t, true)),
+			3000..=3005 => Tapback::from_index(typ - 3000).map(|t| (t, false)),
+			_ => None,
+		}
+	}
+}
+
+// the reactions that can be added to a message. Their order matches the
+// `tapback` parameter of `send_tapback`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tapback {
+	Love,
+	Like,
+	Dislike,
+	Laugh,
+	Emphasize,
+	Question,
 }
 
-#[derive(PartialEq, Debug, Deserialize, Serialize)]
+impl Tapback {
+	pub fn from_index(index: i16) -> Option<Tapback> {
+		Some(match index {
+			0 => Tapback::Love,
+			1 => Tapback::Like,
+			2 => Tapback::Dislike,
+			3 => Tapback::Laugh,
+			4 => Tapback::Emphasize,
+			5 => Tapback::Question,
+			_ => return None,
+		})
+	}
+
+	// what `send_tapback` takes as its `tapback` parameter
+	pub fn index(&self) -> u16 {
+		*self as u16
+	}
+
+	pub fn emoji(&self) -> &'static str {
+		match self {
+			Tapback::Love => "\u{2764}\u{fe0f}",
+			Tapback::Like => "\u{1f44d}",
+			Tapback::Dislike => "\u{1f44e}",
+			Tapback::Laugh => "\u{1f602}",
+			Tapback::Emphasize => "\u{203c}\u{fe0f}",
+			Tapback::Question => "\u{2753}",
+		}
+	}
+}
+
+#[derive(PartialEq, Debug, Clone, Default, Deserialize, Serialize)]
 pub enum MessageType {
+	#[default]
 	Normal,
 	Typing,
 	Idle,
 }
 
-impl Default for MessageType {
-	fn default() -> Self {
-		MessageType::Normal
-	}
-}
-
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Attachment {
 	pub mime_type: String,
 	#[serde(rename = "filename")]
 	pub path: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Attachment {
+	// the name of the file on the host, with an extension that matches
+	// its mime type if it didn't already have one
+	pub fn file_name(&self) -> String {
+		let name: String = self.path.rsplit('/')
+			.next()
+			.unwrap_or_default()
+			.chars()
+			.map(|c| match c {
+				'\\' | ':' | '\0' => '_',
+				c => c,
+			})
+			.collect();
+
+		let name = match name.trim_start_matches('.') {
+			"" => "attachment".to_owned(),
+			_ => name,
+		};
+
+		match (name.contains('.'), self.extension()) {
+			(false, Some(ext)) => format!("{}.{}", name, ext),
+			_ => name,
+		}
+	}
+
+	pub fn extension(&self) -> Option<&'static str> {
+		extension_for_mime(&self.mime_type)
+	}
+}
+
+// the usual file extension for each of the mime types that are common in messages
+pub fn extension_for_mime(mime: &str) -> Option<&'static str> {
+	let ext = match mime.split(';').next()?.trim().to_lowercase().as_str() {
+		"image/jpeg" | "image/jpg" => "jpg",
+		"image/png" => "png",
+		"image/gif" => "gif",
+		"image/heic" => "heic",
+		"image/heif" => "heif",
+		"image/webp" => "webp",
+		"image/tiff" => "tiff",
+		"video/quicktime" => "mov",
+		"video/mp4" => "mp4",
+		"video/3gpp" => "3gp",
+		"audio/x-m4a" | "audio/mp4" => "m4a",
+		"audio/amr" => "amr",
+		"audio/mpeg" => "mp3",
+		"audio/x-caf" => "caf",
+		"audio/wav" | "audio/x-wav" => "wav",
+		"application/pdf" => "pdf",
+		"application/zip" => "zip",
+		"text/vcard" | "text/x-vcard" => "vcf",
+		"text/plain" => "txt",
+		"text/html" => "html",
+		"application/json" => "json",
+		_ => return None,
+	};
+
+	Some(ext)
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Photo {
 	pub is_favorite: bool,
 	#[serde(rename = "URL")]
 	pub url: String,
 }
+
+// converts a date from the Messages database into a real date. Newer versions of
+// iOS store them in nanoseconds since the reference date, and older ones in seconds.
+pub fn apple_date(date: i64) -> Option<DateTime<Utc>> {
+	if date == 0 {
+		return None;
+	}
+
+	let (secs, nanos) = match date.abs() < 100_000_000_000 {
+		true => (date, 0),
+		false => (date.div_euclid(1_000_000_000), date.rem_euclid(1_000_000_000) as u32),
+	};
+
+	Utc.timestamp_opt(secs + APPLE_EPOCH_OFFSET, nanos).single()
+}
+
+// the inverse of `apple_date`, in nanoseconds
+pub fn to_apple_date(date: DateTime<Utc>) -> i64 {
+	(date.timestamp() - APPLE_EPOCH_OFFSET) * 1_000_000_000
+		+ date.timestamp_subsec_nanos() as i64
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn converts_apple_dates() {
+		// 2021-01-01 00:00:00 UTC
+		let new_year = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+		let secs = new_year.timestamp() - APPLE_EPOCH_OFFSET;
+
+		assert_eq!(apple_date(0), None);
+		assert_eq!(apple_date(secs), Some(new_year));
+		assert_eq!(apple_date(secs * 1_000_000_000), Some(new_year));
+
+		let later = new_year + chrono::Duration::nanoseconds(1_500_000_123);
+		assert_eq!(to_apple_date(later), secs * 1_000_000_000 + 1_500_000_123);
+		assert_eq!(apple_date(to_apple_date(later)), Some(later));
+
+		// before the reference date
+		let old = Utc.with_ymd_and_hms(2000, 6, 1, 12, 0, 0).unwrap()
+			+ chrono::Duration::milliseconds(250);
+		assert!(to_apple_date(old) < 0);
+		assert_eq!(apple_date(to_apple_date(old)), Some(old));
+	}
+
+	#[test]
+	fn names_attachments_safely() {
+		let att = |path: &str, mime: &str| Attachment {
+			mime_type: mime.to_owned(),
+			path: path.to_owned(),
+		};
+
+		assert_eq!(att("Attachments/ab/IMG_0001.HEIC", "image/heic").file_name(), "IMG_0001.HEIC");
+		assert_eq!(att("Attachments/ab/voice", "audio/x-m4a").file_name(), "voice.m4a");
+		assert_eq!(att("Attachments/ab/..", "image/png").file_name(), "attachment.png");
+		assert_eq!(att("a/..\\evil:name", "").file_name(), ".._evil_name");
+		assert_eq!(extension_for_mime("IMAGE/JPEG; charset=binary"), Some("jpg"));
+	}
+}
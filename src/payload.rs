@@ -0,0 +1,176 @@
+use reqwest::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use crate::{
+	error::SDKError,
+	models::extension_for_mime,
+};
+
+// the data returned by commands like `get_attachment`, along with whatever
+// the host told us about it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BinaryPayload {
+	pub bytes: Vec<u8>,
+	pub mime_type: Option<String>,
+	// the name that the host suggested for the file, if any
+	pub file_name: Option<String>,
+	pub size: usize,
+}
+
+impl BinaryPayload {
+	pub fn new(bytes: Vec<u8>) -> BinaryPayload {
+		BinaryPayload {
+			size: bytes.len(),
+			bytes,
+			mime_type: None,
+			file_name: None,
+		}
+	}
+
+	pub fn with_mime_type(mut self, mime_type: Option<String>) -> Self {
+		self.mime_type = mime_type;
+		self
+	}
+
+	pub fn with_file_name(mut self, file_name: Option<String>) -> Self {
+		self.file_name = file_name;
+		self
+	}
+
+	// the extension that the file should have, from its mime type or else
+	// from the name the host suggested
+	pub fn extension(&self) -> Option<&str> {
+		self.mime_type.as_deref()
+			.and_then(extension_for_mime)
+			.or_else(|| self.file_name.as_deref()
+				.and_then(|n| n.rsplit_once('.'))
+				.map(|(_, ext)| ext)
+			)
+	}
+
+	pub fn into_bytes(self) -> Vec<u8> {
+		self.bytes
+	}
+
+	pub(crate) async fn from_response(response: reqwest::Response) -> anyhow::Result<BinaryPayload> {
+		let headers = response.headers();
+
+		let mime_type = headers.get(CONTENT_TYPE)
+			.and_then(|h| h.to_str().ok())
+			.map(str::to_owned);
+
+		let file_name = headers.get(CONTENT_DISPOSITION)
+			.and_then(|h| h.to_str().ok())
+			.and_then(disposition_file_name);
+
+		let bytes = response.bytes().await?.to_vec();
+
+		Ok(BinaryPayload::new(bytes)
+			.with_mime_type(mime_type)
+			.with_file_name(file_name))
+	}
+
+	// puts together the chunks that the socket sends data in. Each chunk is like
+	// `{ "data": "<base64>", "total": 3 }`, and any of them (usually the first)
+	// may also have `mime_type`, `file_name` and `size` keys.
+	pub(crate) fn from_chunks(chunks: &[serde_json::Value]) -> anyhow::Result<BinaryPayload> {
+		let mut encoded = String::new();
+		let mut mime_type = None;
+		let mut file_name = None;
+		let mut size = None;
+
+		for chunk in chunks {
+			match chunk.get("data").and_then(|d| d.as_str()) {
+				Some(data) => encoded.push_str(data),
+				None => return Err(SDKError::ImproperDataFormat.into()),
+			}
+
+			let text = |key: &str| chunk.get(key)
+				.and_then(|v| v.as_str())
+				.map(str::to_owned);
+
+			mime_type = mime_type.or_else(|| text("mime_type"));
+			file_name = file_name.or_else(|| text("file_name"));
+			size = size.or_else(|| chunk.get("size").and_then(|s| s.as_u64()));
+		}
+
+		let bytes = base64::decode(encoded)?;
+
+		// if the host told us how big it should be, make sure we got all of it
+		if size.map(|s| s != bytes.len() as u64).unwrap_or(false) {
+			return Err(SDKError::MangledReceive.into());
+		}
+
+		Ok(BinaryPayload::new(bytes)
+			.with_mime_type(mime_type)
+			.with_file_name(file_name))
+	}
+}
+
+impl AsRef<[u8]> for BinaryPayload {
+	fn as_ref(&self) -> &[u8] {
+		&self.bytes
+	}
+}
+
+impl From<BinaryPayload> for Vec<u8> {
+	fn from(payload: BinaryPayload) -> Vec<u8> {
+		payload.bytes
+	}
+}
+
+// gets the file name out of a header like `attachment; filename="IMG_0001.jpg"`.
+// `filename*` (which is percent-encoded) is preferred when both are there.
+fn disposition_file_name(header: &str) -> Option<String> {
+	let mut plain = None;
+	let mut extended = None;
+
+	for param in header.split(';').skip(1) {
+		let (key, value) = match param.split_once('=') {
+			Some((key, value)) => (key.trim().to_lowercase(), value.trim()),
+			None => continue,
+		};
+
+		match key.as_str() {
+			"filename" => plain = Some(value.trim_matches('"').to_owned()),
+			// e.g. `UTF-8''na%C3%AFve.txt`
+			"filename*" => extended = value.split_once("''")
+				.and_then(|(_, name)| percent_encoding::percent_decode_str(name)
+					.decode_utf8()
+					.ok()
+					.map(|name| name.into_owned())
+				),
+			_ => (),
+		}
+	}
+
+	// the name is only a suggestion, so don't let it point anywhere else
+	extended.or(plain)
+		.and_then(|name| name.rsplit(&['/', '\\'][..]).next().map(str::to_owned))
+		.filter(|name| !name.is_empty() && name != "." && name != "..")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reads_disposition_file_names() {
+		let name = |header: &str| disposition_file_name(header);
+
+		assert_eq!(name(r#"attachment; filename="IMG_0001.jpg""#).as_deref(), Some("IMG_0001.jpg"));
+		assert_eq!(name("attachment; FileName=notes.txt").as_deref(), Some("notes.txt"));
+		assert_eq!(
+			name(r#"attachment; filename="naive.txt"; filename*=UTF-8''na%C3%AFve.txt"#).as_deref(),
+			Some("naïve.txt")
+		);
+
+		// only the last part of a path is kept
+		assert_eq!(name(r#"attachment; filename="../../.ssh/authorized_keys""#).as_deref(), Some("authorized_keys"));
+		assert_eq!(name(r#"attachment; filename="C:\Users\me\a.png""#).as_deref(), Some("a.png"));
+		assert_eq!(name("attachment; filename*=UTF-8''..%2F..%2Fa.png").as_deref(), Some("a.png"));
+
+		assert_eq!(name(r#"attachment; filename="..""#), None);
+		assert_eq!(name(r#"attachment; filename="dir/""#), None);
+		assert_eq!(name("attachment"), None);
+		assert_eq!(name("inline; size=12"), None);
+	}
+}
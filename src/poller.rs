@@ -0,0 +1,273 @@
+use std::{
+	collections::{HashMap, HashSet, VecDeque},
+	time::Duration,
+};
+use serde_json::json;
+use crate::{
+	commands::APICommand,
+	models::{Conversation, Message},
+	rest_api::RestAPIClient,
+	socket::SocketResponse,
+};
+
+// how many of the most recent messages are fetched for a chat that changed
+const POLL_MESSAGES: u32 = 20;
+// how many guids we remember for deduplicating, so it doesn't grow forever
+const SEEN_CAPACITY: usize = 2048;
+
+// The REST API can't push notifications to us like the socket can, so this
+// periodically checks `get_chats` for conversations whose `latest_text` or
+// `has_unread` changed, then grabs the newest messages from those and sends
+// them through the notification channel as `new-message` SocketResponses,
+// just like the socket would. Typing notifications can't be polled for, so
+// they're only available over the socket.
+pub struct Poller {
+	rest_client: RestAPIClient,
+	sender: crossbeam_channel::Sender<SocketResponse>,
+	interval: Duration,
+	chats: HashMap<String, ChatState>,
+	seen: HashSet<String>,
+	seen_order: VecDeque<String>,
+	started: bool,
+}
+
+struct ChatState {
+	latest_text: String,
+	has_unread: bool,
+	// the date of the newest message we've sent a notification for, or
+	// None if we haven't fetched any messages for this chat yet
+	newest_date: Option<i64>,
+}
+
+impl Poller {
+	pub fn new(
+		rest_client: RestAPIClient,
+		sender: crossbeam_channel::Sender<SocketResponse>,
+		interval: Duration
+	) -> Poller {
+		Poller {
+			rest_client,
+			sender,
+			interval,
+			chats: HashMap::new(),
+			seen: HashSet::new(),
+			seen_order: VecDeque::new(),
+			started: false,
+		}
+	}
+
+	pub fn spawn(mut self) -> tokio::task::JoinHandle<()> {
+		tokio::spawn(async move {
+			loop {
+				// if nobody's listening for notifications anymore,
+				// there's no reason to keep polling
+				if !self.poll().await {
+					break;
+				}
+
+				tokio::time::sleep(self.interval).await;
+			}
+		})
+	}
+
+	// returns false once the notification channel has been disconnected
+	pub async fn poll(&mut self) -> bool {
+		let convos = match self.rest_client.get_chats(None, None).await {
+			Ok(convos) => convos,
+			// this'll most likely just be a network issue, so try again next time
+			Err(err) => {
+				tracing::warn!(error = %err, "failed to poll chats");
+				return true;
+			}
+		};
+
+		let changed: Vec<String> = convos.into_iter()
+			.filter_map(|convo| self.update_chat(convo))
+			.collect();
+
+		// the first poll is only used to see what the chats look like right now,
+		// since everything in them has already happened
+		if !self.started {
+			self.started = true;
+
+			for chat in changed {
+				self.seed(&chat).await;
+			}
+
+			return true;
+		}
+
+		for chat in changed {
+			let mut messages = match self.rest_client.get_messages(
+				&chat, Some(POLL_MESSAGES), None, Some(false)
+			).await {
+				Ok(msgs) => msgs,
+				Err(err) => {
+					tracing::warn!(chat = %chat, error = %err, "failed to poll messages");
+					continue;
+				}
+			};
+
+			// send them in the order they were sent
+			messages.sort_by_key(|m| m.date);
+
+			for msg in self.new_messages(&chat, messages) {
+				tracing::debug!(chat = %chat, guid = %msg.guid, "polled new message");
+
+				let res = SocketResponse {
+					id: String::new(),
+					last: true,
+					command: APICommand::NewMessage,
+					data: json!(msg),
+				};
+
+				if self.sender.send(res).is_err() {
+					return false;
+				}
+			}
+		}
+
+		true
+	}
+
+	// fetches the newest message that's already in `chat`, so that every message
+	// after it is sent as a notification, however many come in before it changes
+	async fn seed(&mut self, chat: &str) {
+		match self.rest_client.get_messages(chat, Some(1), None, Some(false)).await {
+			Ok(messages) => self.seed_with(chat, messages.first()),
+			// then only its newest message is known to be new when it changes
+			Err(err) => tracing::warn!(chat = %chat, error = %err, "failed to fetch newest message"),
+		}
+	}
+
+	fn seed_with(&mut self, chat: &str, newest: Option<&Message>) {
+		if let Some(state) = self.chats.get_mut(chat) {
+			// a chat with nothing in it yet has nothing to skip
+			state.newest_date = Some(newest.map(|m| m.date).unwrap_or(i64::MIN));
+		}
+
+		if let Some(newest) = newest {
+			self.remember(&newest.guid);
+		}
+	}
+
+	// updates the state of this chat, and returns its identifier
+	// if it looks like it got a new message since the last poll
+	fn update_chat(&mut self, convo: Conversation) -> Option<String> {
+		let Conversation { chat_identifier, latest_text, has_unread, .. } = convo;
+
+		match self.chats.get_mut(&chat_identifier) {
+			Some(state) => {
+				// if it just switched from unread to read, that means they read it
+				// somewhere else, not that there's a new message
+				let changed = state.latest_text != latest_text
+					|| (has_unread && !state.has_unread);
+
+				state.latest_text = latest_text;
+				state.has_unread = has_unread;
+
+				if changed { Some(chat_identifier) } else { None }
+			},
+			None => {
+				self.chats.insert(chat_identifier.to_owned(), ChatState {
+					latest_text,
+					has_unread,
+					newest_date: None,
+				});
+
+				Some(chat_identifier)
+			}
+		}
+	}
+
+	// filters out all the messages that have already been sent as notifications,
+	// or that were already in the chat before we started watching it
+	fn new_messages(&mut self, chat: &str, messages: Vec<Message>) -> Vec<Message> {
+		let state = match self.chats.get_mut(chat) {
+			Some(state) => state,
+			None => return Vec::new(),
+		};
+
+		// if this chat wasn't around for the first poll (or fetching its newest
+		// message failed then), we only know that the newest one is new, since
+		// that's what made it change
+		let cutoff = match state.newest_date {
+			Some(date) => date,
+			None => messages.last().map(|m| m.date).unwrap_or_default(),
+		};
+
+		if let Some(newest) = messages.last() {
+			state.newest_date = Some(newest.date.max(cutoff));
+		}
+
+		let new: Vec<Message> = messages.into_iter()
+			.filter(|m| m.date >= cutoff && !self.seen.contains(&m.guid))
+			.collect();
+
+		for msg in new.iter() {
+			self.remember(&msg.guid);
+		}
+
+		new
+	}
+
+	fn remember(&mut self, guid: &str) {
+		if !self.seen.insert(guid.to_owned()) {
+			return;
+		}
+
+		self.seen_order.push_back(guid.to_owned());
+
+		if self.seen_order.len() > SEEN_CAPACITY {
+			if let Some(old) = self.seen_order.pop_front() {
+				self.seen.remove(&old);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::SDKConfig;
+
+	fn message(guid: &str, date: i64) -> Message {
+		let mut message = Message::typing("chat");
+		message.guid = guid.to_owned();
+		message.date = date;
+		message
+	}
+
+	fn chat(latest_text: &str) -> Conversation {
+		serde_json::from_value(json!({
+			"display_name": "",
+			"chat_identifier": "chat",
+			"latest_text": latest_text,
+			"has_unread": false,
+			"addresses": "",
+		})).unwrap()
+	}
+
+	fn guids(messages: Vec<Message>) -> Vec<String> {
+		messages.into_iter().map(|m| m.guid).collect()
+	}
+
+	#[test]
+	fn sends_everything_since_the_first_poll() {
+		let (sender, _receiver) = crossbeam_channel::unbounded();
+		let mut poller = Poller::new(RestAPIClient::new(SDKConfig::default()), sender, Duration::from_secs(1));
+
+		// what the first poll sees
+		assert!(poller.update_chat(chat("old")).is_some());
+		poller.seed_with("chat", Some(&message("a", 10)));
+
+		// two come in before the next one
+		assert!(poller.update_chat(chat("newer")).is_some());
+		let new = poller.new_messages("chat", vec![message("a", 10), message("b", 20), message("c", 30)]);
+		assert_eq!(guids(new), vec!["b", "c"]);
+
+		assert!(poller.update_chat(chat("newest")).is_some());
+		let new = poller.new_messages("chat", vec![message("b", 20), message("c", 30), message("d", 30)]);
+		assert_eq!(guids(new), vec!["d"]);
+	}
+}
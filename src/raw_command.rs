@@ -0,0 +1,138 @@
+use serde_json::Value;
+use crate::{
+	api::*,
+	commands::APICommand,
+	error::*,
+	payload::BinaryPayload,
+};
+
+// a command that's built at runtime instead of by the `Commands` macro, so that
+// commands which SMServer added after this SDK was released can still be used.
+// On the socket, it's sent with `name` as the command string. On the REST API,
+// `name` isn't used, since the REST API only decides what to do based on the
+// subdirectory and the parameters.
+#[derive(Debug, Clone)]
+pub struct RawCommand {
+	pub name: String,
+	pub params: Value,
+	pub subdir: String,
+	pub data_return: bool,
+	pub response: bool,
+	pub multipart: bool,
+	pub files: Option<(String, Vec<String>)>,
+}
+
+// what the host returned for a `RawCommand`
+#[derive(Debug, Clone)]
+pub enum RawResponse {
+	Json(Value),
+	Data(BinaryPayload),
+	Empty,
+}
+
+impl RawCommand {
+	pub fn new(name: impl Into<String>, params: Value) -> Self {
+		RawCommand {
+			name: name.into(),
+			params,
+			subdir: "requests".to_owned(),
+			data_return: false,
+			response: true,
+			multipart: false,
+			files: None,
+		}
+	}
+
+	// which subdirectory of the rest_base_url the command is sent to
+	pub fn with_subdir(mut self, subdir: impl Into<String>) -> Self {
+		self.subdir = subdir.into();
+		self
+	}
+
+	// if the command returns data (like `get-attachment`), as opposed to json
+	pub fn with_data_return(mut self, data: bool) -> Self {
+		self.data_return = data;
+		self
+	}
+
+	// if the host sends anything back for this command. If it doesn't, the
+	// socket won't wait for a response.
+	pub fn with_response(mut self, response: bool) -> Self {
+		self.response = response;
+		self
+	}
+
+	// sends the params as a multipart form over the REST API, like `send-message`
+	pub fn with_multipart(mut self, multipart: bool) -> Self {
+		self.multipart = multipart;
+		self
+	}
+
+	// files to be sent under the key `key` in the multipart form. These are
+	// only sent over the REST API, since the socket sends attachments in chunks.
+	pub fn with_files(mut self, key: impl Into<String>, files: Vec<String>) -> Self {
+		self.multipart = true;
+		self.files = Some((key.into(), files));
+		self
+	}
+}
+
+impl RawResponse {
+	// parses the JSON that was returned into whichever type is wanted
+	pub fn parse<T: serde::de::DeserializeOwned>(self) -> anyhow::Result<T> {
+		match self {
+			RawResponse::Json(val) => Ok(serde_json::from_value(val)?),
+			RawResponse::Empty => Ok(serde_json::from_value(Value::Null)?),
+			RawResponse::Data(_) => Err(SDKError::ImproperDataFormat.into()),
+		}
+	}
+}
+
+impl APIRequest for RawCommand {
+	type Response = RawResponse;
+
+	async fn execute(
+		self,
+		client: &APIClient
+	) -> anyhow::Result<RawResponse> {
+		let span = tracing::debug_span!(
+			"raw_command",
+			command = %self.name,
+			rest = client.uses_rest,
+			id = tracing::field::Empty
+		);
+
+		tracing::Instrument::instrument(self.send(client), span).await
+	}
+}
+
+impl RawCommand {
+	async fn send(
+		self,
+		client: &APIClient
+	) -> anyhow::Result<RawResponse> {
+		if client.uses_rest {
+			return client.rest_client.raw_command(&self).await;
+		}
+
+		let cmd = APICommand::from_command_string(&self.name)
+			.unwrap_or(APICommand::Unknown(self.name));
+
+		if !self.response {
+			let id = client.socket.send_command(cmd, self.params).await?;
+			tracing::Span::current().record("id", id.as_str());
+
+			return Ok(RawResponse::Empty);
+		}
+
+		let mut reply = client.socket.request(cmd, self.params).await?;
+
+		tracing::Span::current().record("id", reply.id.as_str());
+
+		if self.data_return {
+			return APIClient::receive_data(reply).await.map(RawResponse::Data);
+		}
+
+		Ok(RawResponse::Json(reply.recv().await?.data))
+	}
+}
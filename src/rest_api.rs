@@ -1,18 +1,42 @@
-use std::time::Duration;
+use std::{
+	path::Path,
+	sync::{
+		Arc,
+		atomic::{AtomicBool, AtomicU64, Ordering},
+	},
+	time::Duration,
+};
 use crate::{
 	config::*,
 	error::*,
 	registration_type::*,
+	raw_command::*,
+	payload::BinaryPayload,
+	session::SessionStore,
 };
 
 pub struct RestAPIClient {
 	pub client: reqwest::Client,
 	pub config: SDKConfig,
-	pub authenticated: bool
+	pub session: Arc<SessionStore>,
+	pub authenticated: AtomicBool,
+	// makes sure that only one request authenticates at a time, when
+	// multiple are sent at once
+	auth_lock: tokio::sync::Mutex<()>,
+	// bumped every time we authenticate, so that a request that was rejected
+	// can tell whether the session it was sent with has already been replaced
+	generation: AtomicU64,
 }
 
 impl RestAPIClient {
 	pub fn new(config: SDKConfig) -> RestAPIClient {
+		let session = Arc::new(SessionStore::new(config.session_file.to_owned()));
+		RestAPIClient::with_session(config, session)
+	}
+
+	// creates a client that shares its session with another one, so
+	// that they don't both have to authenticate
+	pub fn with_session(config: SDKConfig, session: Arc<SessionStore>) -> RestAPIClient {
 		// these specific things are to make sure that the client can connect
 		// with SMServer, since it uses a self-signed cert and normally connects
 		// with an IP Address, not hostname
@@ -24,58 +48,268 @@ impl RestAPIClient {
 			.build()
 			.expect("Unable to build TlsConnector");
 
+		// `timeout` covers the whole request, including reading the response, so
+		// that a host which accepts the connection and then hangs doesn't hang us too
+		let timeout = Duration::from_secs(config.timeout as u64);
+
 		let client = reqwest::Client::builder()
 			.use_native_tls()
 			.use_preconfigured_tls(tls)
-			.connect_timeout(Duration::from_secs(config.timeout as u64))
+			.connect_timeout(timeout)
+			.timeout(timeout)
+			.cookie_provider(session.clone())
 			.build()
 			.expect("Unable to build API Client");
 
+		// if there's a session left over from last time, try to use it. If it
+		// expired, we'll find out on the first request and authenticate again.
 		RestAPIClient {
-			authenticated: false,
+			authenticated: AtomicBool::new(!session.is_empty()),
+			auth_lock: tokio::sync::Mutex::new(()),
+			generation: AtomicU64::new(0),
 			config,
+			session,
 			client
 		}
 	}
 
+	// sends the request that `build` creates, and if the host says that we're
+	// not authenticated (e.g. because it restarted or the session expired),
+	// authenticates again and retries it once.
+	//
+	// Any other 4xx or 5xx status is returned as an error, instead of handing
+	// back the error page as if it were the response. Before this, e.g. a 500
+	// from `get_url_string` came back as `Ok` with the page's html in it; now it's
+	// a `reqwest::Error` with the status, which is also what lets `RetryPolicy`
+	// tell that it was a server error.
+	pub async fn send_authed(
+		&self, build: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder
+	) -> anyhow::Result<reqwest::Response> {
+		self.check_auth().await?;
+
+		let generation = self.generation.load(Ordering::SeqCst);
+		let response = build(&self.client).send().await?;
+
+		if !RestAPIClient::is_rejection(&response) {
+			return Ok(response.error_for_status()?);
+		}
+
+		self.reauthenticate(generation).await?;
+
+		let response = build(&self.client).send().await?;
+
+		if RestAPIClient::is_rejection(&response) {
+			self.authenticated.store(false, Ordering::SeqCst);
+			return Err(SDKError::UnAuthenticated.into());
+		}
+
+		Ok(response.error_for_status()?)
+	}
+
+	fn is_rejection(response: &reqwest::Response) -> bool {
+		matches!(
+			response.status(),
+			reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN
+		)
+	}
+
 	pub async fn get_url_string(&self, url: &str) -> anyhow::Result<String> {
-		let response = self.client.get(url).send().await?;
+		let response = self.send_authed(|client| client.get(url)).await?;
 
 		Ok(response.text().await?)
 	}
 
-	pub async fn get_url_data(&self, url: &str) -> anyhow::Result<Vec<u8>> {
-		let response = self.client.get(url).send().await?;
+	pub async fn get_url_data(&self, url: &str) -> anyhow::Result<BinaryPayload> {
+		let response = self.send_authed(|client| client.get(url)).await?;
 
-		Ok(response.bytes().await?.to_vec())
+		BinaryPayload::from_response(response).await
+	}
+
+	// Streams the attachment at `path` onto the end of `part`, asking the host to
+	// start from however much of it is already there, so that a download that was
+	// cut off doesn't start over. Hosts that don't do ranges send the whole thing
+	// (a 200 instead of a 206), and then `part` is written from the start. Returns
+	// how big `part` is afterwards.
+	pub async fn download_attachment(&self, path: &str, part: &Path) -> anyhow::Result<u64> {
+		use tokio::io::AsyncWriteExt;
+
+		let url = self.config.push_to_rest_url("data");
+		let mut from = tokio::fs::metadata(part).await.map(|m| m.len()).unwrap_or(0);
+
+		let mut response = loop {
+			let res = self.send_authed(|client| {
+				let req = client.get(&url).query(&[("path", path)]);
+
+				match from {
+					0 => req,
+					from => req.header(reqwest::header::RANGE, format!("bytes={}-", from)),
+				}
+			}).await;
+
+			let unsatisfiable = |err: &anyhow::Error| err.downcast_ref::<reqwest::Error>()
+				.and_then(reqwest::Error::status) == Some(reqwest::StatusCode::RANGE_NOT_SATISFIABLE);
+
+			match res {
+				// the file changed on the host since the last try, so start over
+				Err(err) if from > 0 && unsatisfiable(&err) => from = 0,
+				res => break res?,
+			}
+		};
+
+		let mut file = match response.status() {
+			reqwest::StatusCode::PARTIAL_CONTENT if from > 0 => tokio::fs::OpenOptions::new()
+				.append(true)
+				.open(part)
+				.await?,
+			_ => {
+				from = 0;
+				tokio::fs::File::create(part).await?
+			},
+		};
+
+		let mut size = from;
+
+		while let Some(chunk) = response.chunk().await? {
+			file.write_all(&chunk).await?;
+			size += chunk.len() as u64;
+		}
+
+		file.flush().await?;
+
+		Ok(size)
+	}
+
+	pub async fn raw_command(
+		&self, cmd: &RawCommand
+	) -> anyhow::Result<RawResponse> {
+		// the params have to be a map, since each of them are sent as either
+		// a query item or a form field
+		let params: Vec<(String, String)> = match &cmd.params {
+			serde_json::Value::Object(map) => map.iter()
+				.filter_map(|(key, val)| match val {
+					serde_json::Value::Null => None,
+					serde_json::Value::String(s) => Some((key.to_owned(), s.to_owned())),
+					other => Some((key.to_owned(), other.to_string())),
+				})
+				.collect(),
+			serde_json::Value::Null => Vec::new(),
+			_ => return Err(SDKError::ImproperDataFormat.into()),
+		};
+
+		let url = self.config.push_to_rest_url(&cmd.subdir);
+
+		// this may be called twice if we have to authenticate again
+		let build = |client: &reqwest::Client| if cmd.multipart {
+			let mut form = reqwest::multipart::Form::new();
+
+			for (key, val) in params.iter() {
+				form = form.text(key.to_owned(), val.to_owned());
+			}
+
+			if let Some((key, files)) = &cmd.files {
+				for data in files.iter().filter_map(|f| std::fs::read(f).ok()) {
+					form = form.part(key.to_owned(), reqwest::multipart::Part::bytes(data));
+				}
+			}
+
+			client.post(&url).multipart(form)
+		} else {
+			client.get(&url).query(&params)
+		};
+
+		let response = self.send_authed(build).await?;
+
+		if cmd.data_return {
+			return BinaryPayload::from_response(response).await.map(RawResponse::Data);
+		}
+
+		let text = response.text().await?;
+
+		if !cmd.response || text.is_empty() {
+			return Ok(RawResponse::Empty);
+		}
+
+		// not everything that the host returns is JSON, so just pass it
+		// back as a string if it can't be parsed
+		Ok(RawResponse::Json(
+			serde_json::from_str(&text).unwrap_or(serde_json::Value::String(text))
+		))
 	}
 
 	pub async fn authenticate(&self) -> anyhow::Result<bool> {
 		// authenticate with SMServer so that we can make more requests later
 		// without being denied
-		let pass = format!("requests?password={}", self.config.password());
-		let url = self.config.push_to_rest_url(pass);
+		let pass = self.config.password()?;
+		let url = self.config.push_to_rest_url("requests");
+
+		tracing::debug!("authenticating");
+
+		// the password ends up in the query string, so the url has to be
+		// stripped from any errors, since they may be logged or shown
+		let res = self.client.get(&url)
+			.query(&[("password", pass.expose())])
+			.send()
+			.await
+			.map_err(reqwest::Error::without_url)?
+			.text()
+			.await
+			.map_err(reqwest::Error::without_url)?;
 
-		let res = self.get_url_string(&url).await?;
 		Ok(res.parse().unwrap_or(false))
 	}
 
-	pub async fn check_auth(&mut self) -> anyhow::Result<()> {
-		// this has to be mut since it changes the `authenticated` status of
-		// self if it succeeds in authenticating
+	pub async fn check_auth(&self) -> anyhow::Result<()> {
+		if !self.config.use_rest || self.authenticated.load(Ordering::SeqCst) {
+			return Ok(());
+		}
 
-		if self.config.use_rest && !self.authenticated {
-			match self.authenticate().await? {
-				true => self.authenticated = true,
-				false => return Err(SDKError::UnAuthenticated.into()),
-			}
+		let _guard = self.auth_lock.lock().await;
+
+		// another request may have authenticated while we were waiting
+		if !self.authenticated.load(Ordering::SeqCst) {
+			self.login().await?;
+		}
+
+		Ok(())
+	}
+
+	// throws away the session that was rejected and authenticates again, unless
+	// another request already did since `generation`, in which case the new
+	// session is left alone
+	async fn reauthenticate(&self, generation: u64) -> anyhow::Result<()> {
+		let _guard = self.auth_lock.lock().await;
+
+		if self.generation.load(Ordering::SeqCst) != generation
+			&& self.authenticated.load(Ordering::SeqCst)
+		{
+			return Ok(());
+		}
+
+		tracing::info!("session was rejected, authenticating again");
+
+		self.authenticated.store(false, Ordering::SeqCst);
+		self.session.clear();
+		self.login().await
+	}
+
+	// only called with `auth_lock` held
+	async fn login(&self) -> anyhow::Result<()> {
+		if !self.authenticate().await? {
+			tracing::warn!("authentication was rejected");
+			return Err(SDKError::UnAuthenticated.into());
 		}
 
+		tracing::info!("authenticated");
+		self.generation.fetch_add(1, Ordering::SeqCst);
+		self.authenticated.store(true, Ordering::SeqCst);
+
 		Ok(())
 	}
 
 	// these registration functions are currently unused by the SDK, since the host takes care of
 	// registering a socket and all that, but I keep them here just in case I find a use for them.
+	// They go to the socket server, which doesn't use SMServer's session, so they're sent
+	// directly instead of through `send_authed`.
 	pub async fn register_socket(
 		&self,
 		key: impl Into<String>,
@@ -92,7 +326,7 @@ impl RestAPIClient {
 
 		let register_url = self.config.push_to_sock_url(url);
 
-		self.get_url_string(&register_url).await
+		Ok(self.client.get(&register_url).send().await?.text().await?)
 	}
 
 	pub async fn remove_registration(
@@ -106,8 +340,64 @@ impl RestAPIClient {
 
 		let remove_url = self.config.push_to_sock_url(url);
 
-		self.get_url_string(&remove_url)
-			.await
-			.map(|_| ())
+		self.client.get(&remove_url).send().await?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::{BufRead, Write};
+
+	// answers one request with `response`, and sends back its `Range` header
+	fn serve(listener: &std::net::TcpListener, response: &'static [u8]) -> Option<String> {
+		let (stream, _) = listener.accept().unwrap();
+		let mut reader = std::io::BufReader::new(stream);
+		let mut range = None;
+
+		loop {
+			let mut line = String::new();
+			reader.read_line(&mut line).unwrap();
+
+			match line.trim_end() {
+				"" => break,
+				line => if let Some(val) = line.strip_prefix("range: ") {
+					range = Some(val.to_owned());
+				},
+			}
+		}
+
+		reader.into_inner().write_all(response).unwrap();
+		range
+	}
+
+	#[test]
+	fn resumes_partial_downloads() {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+
+		let server = std::thread::spawn(move || vec![
+			serve(&listener, b"HTTP/1.1 206 Partial Content\r\nContent-Length: 5\r\nConnection: close\r\n\r\nworld"),
+			// one that doesn't do ranges sends all of it
+			serve(&listener, b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello world"),
+		]);
+
+		let config = SDKConfig::default().with_rest_url(format!("http://127.0.0.1:{}", port));
+		let rest = RestAPIClient::new(config);
+		rest.authenticated.store(true, Ordering::SeqCst);
+
+		let part = std::env::temp_dir().join(format!("smserver-part-{}", uuid::Uuid::new_v4()));
+		let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+		for _ in 0..2 {
+			std::fs::write(&part, "hello ").unwrap();
+
+			assert_eq!(rt.block_on(rest.download_attachment("a/b.jpg", &part)).unwrap(), 11);
+			assert_eq!(std::fs::read(&part).unwrap(), b"hello world");
+		}
+
+		assert_eq!(server.join().unwrap(), vec![Some("bytes=6-".to_owned()); 2]);
+		std::fs::remove_file(&part).unwrap();
 	}
 }
@@ -0,0 +1,164 @@
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+// the kinds of errors that a command can be retried after
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryKind {
+	// couldn't connect to the host at all
+	Connect,
+	// the host took too long to respond
+	Timeout,
+	// the host responded with a 5xx status
+	ServerError,
+}
+
+// How commands are retried when they fail. This is only ever applied to commands
+// that are marked `idempotent` in `APICommand`, since e.g. retrying `send_message`
+// could send the same message twice.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+	// including the first attempt, so 1 means never retry
+	pub max_attempts: u32,
+	pub initial_backoff_ms: u64,
+	pub max_backoff_ms: u64,
+	pub multiplier: f64,
+	pub retry_on: Vec<RetryKind>,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> RetryPolicy {
+		RetryPolicy {
+			max_attempts: 3,
+			initial_backoff_ms: 250,
+			max_backoff_ms: 5000,
+			multiplier: 2.0,
+			retry_on: vec![
+				RetryKind::Connect,
+				RetryKind::Timeout,
+				RetryKind::ServerError,
+			],
+		}
+	}
+}
+
+impl RetryPolicy {
+	pub fn never() -> RetryPolicy {
+		RetryPolicy {
+			max_attempts: 1,
+			..RetryPolicy::default()
+		}
+	}
+
+	pub fn with_max_attempts(mut self, attempts: u32) -> Self {
+		self.max_attempts = attempts;
+		self
+	}
+
+	pub fn with_backoff(mut self, initial: Duration, max: Duration, multiplier: f64) -> Self {
+		self.initial_backoff_ms = initial.as_millis() as u64;
+		self.max_backoff_ms = max.as_millis() as u64;
+		self.multiplier = multiplier;
+		self
+	}
+
+	pub fn with_retry_on(mut self, kinds: Vec<RetryKind>) -> Self {
+		self.retry_on = kinds;
+		self
+	}
+
+	// how long to wait before the next attempt, if `attempt` (starting at 1)
+	// failed with `err` and it's worth trying again
+	pub fn retry_delay(&self, attempt: u32, err: &anyhow::Error) -> Option<Duration> {
+		if attempt >= self.max_attempts {
+			return None;
+		}
+
+		let kind = RetryKind::of(err)?;
+
+		if !self.retry_on.contains(&kind) {
+			return None;
+		}
+
+		let backoff = self.initial_backoff_ms as f64
+			* self.multiplier.powi(attempt as i32 - 1);
+
+		Some(Duration::from_millis(backoff.min(self.max_backoff_ms as f64) as u64))
+	}
+}
+
+impl RetryKind {
+	// figures out what kind of error this is, or None if it's one that
+	// wouldn't be fixed by trying again (e.g. a parsing error)
+	pub fn of(err: &anyhow::Error) -> Option<RetryKind> {
+		if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+			return if err.is_timeout() {
+				Some(RetryKind::Timeout)
+			} else if err.is_connect() {
+				Some(RetryKind::Connect)
+			} else if err.status().map(|s| s.is_server_error()).unwrap_or(false) {
+				Some(RetryKind::ServerError)
+			} else {
+				None
+			};
+		}
+
+		// errors from the socket aren't retried, since nothing reconnects it, so
+		// the next attempt would just go out over the same dead connection
+		match err.downcast_ref::<std::io::Error>() {
+			Some(err) if err.kind() == std::io::ErrorKind::TimedOut => Some(RetryKind::Timeout),
+			Some(_) => Some(RetryKind::Connect),
+			None => None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::error::SDKError;
+
+	fn timed_out() -> anyhow::Error {
+		std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out").into()
+	}
+
+	#[test]
+	fn backs_off_up_to_the_max() {
+		let policy = RetryPolicy::default()
+			.with_max_attempts(6)
+			.with_backoff(Duration::from_millis(100), Duration::from_millis(1000), 3.0);
+
+		let delays: Vec<Option<Duration>> = (1..=6)
+			.map(|attempt| policy.retry_delay(attempt, &timed_out()))
+			.collect();
+
+		assert_eq!(delays, vec![
+			Some(Duration::from_millis(100)),
+			Some(Duration::from_millis(300)),
+			Some(Duration::from_millis(900)),
+			Some(Duration::from_millis(1000)),
+			Some(Duration::from_millis(1000)),
+			// that was the last attempt
+			None,
+		]);
+
+		assert_eq!(RetryPolicy::never().retry_delay(1, &timed_out()), None);
+	}
+
+	#[test]
+	fn only_retries_the_configured_kinds() {
+		let refused: anyhow::Error = std::io::Error::new(
+			std::io::ErrorKind::ConnectionRefused, "refused"
+		).into();
+
+		let policy = RetryPolicy::default().with_retry_on(vec![RetryKind::Connect]);
+		assert!(policy.retry_delay(1, &refused).is_some());
+		assert_eq!(policy.retry_delay(1, &timed_out()), None);
+
+		// things that'd fail the same way again
+		let policy = RetryPolicy::default();
+		assert_eq!(policy.retry_delay(1, &SDKError::MangledReceive.into()), None);
+		assert_eq!(policy.retry_delay(1, &anyhow::anyhow!("bad json")), None);
+	}
+}
@@ -0,0 +1,373 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	path::Path,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+use chrono::{Datelike, Local, NaiveTime, Weekday};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use crate::{
+	bot::{Bot, Context, Route},
+	error::ConfigError,
+	models::Tapback,
+};
+
+// Auto-reply rules, usually loaded from a file like
+//
+// [[rules]]
+// name = "away"
+// pattern = "(?i)are you (there|around)"
+// actions = [{ reply = "I'm away right now, I'll get back to you soon" }]
+// windows = [{ start = "22:00", end = "07:00" }, { days = ["sat", "sun"] }]
+//
+// [[rules]]
+// name = "vip"
+// senders = ["+15555555555"]
+// actions = [{ react = "love" }, { forward = "+15555550000" }]
+// rate_limit = { count = 5, per_secs = 60 }
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RuleSet {
+	pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Rule {
+	pub name: String,
+	// if set, only messages in these chats match
+	#[serde(default)]
+	pub chats: Vec<String>,
+	// if set, only messages from these addresses match
+	#[serde(default)]
+	pub senders: Vec<String>,
+	// a regex that has to match somewhere in the text
+	pub pattern: Option<String>,
+	// if set, the rule only applies during one of these (in local time)
+	#[serde(default)]
+	pub windows: Vec<TimeWindow>,
+	pub actions: Vec<Action>,
+	#[serde(default)]
+	pub rate_limit: RateLimit,
+	// whether to stop looking at the rules after this one, once it matches
+	#[serde(default = "default_stop")]
+	pub stop: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TimeWindow {
+	// e.g. `["mon", "tue"]`; every day if empty
+	#[serde(default)]
+	pub days: Vec<String>,
+	// `HH:MM`. If `end` is before `start`, the window goes past midnight.
+	// Leaving either out means the whole day.
+	pub start: Option<String>,
+	pub end: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+	// replies in the same chat
+	Reply(String),
+	React(Tapback),
+	// sends the message, and who it's from, to another chat
+	Forward(String),
+}
+
+// how many times a rule can fire in each chat, so that it doesn't flood a chat
+// or get into a loop with someone else's auto-replies
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RateLimit {
+	pub count: usize,
+	pub per_secs: u64,
+}
+
+impl Default for RateLimit {
+	fn default() -> RateLimit {
+		RateLimit { count: 1, per_secs: 60 }
+	}
+}
+
+fn default_stop() -> bool {
+	true
+}
+
+impl RuleSet {
+	pub fn from_file(path: impl AsRef<Path>) -> Result<RuleSet, ConfigError> {
+		let path = path.as_ref();
+		let parse_err = |message: String| ConfigError::Parse {
+			path: path.to_owned(),
+			message
+		};
+
+		let contents = std::fs::read_to_string(path)
+			.map_err(|source| ConfigError::Read { path: path.to_owned(), source })?;
+
+		let rules: RuleSet = match path.extension().and_then(|e| e.to_str()) {
+			Some("toml") => toml::from_str(&contents).map_err(|err| parse_err(err.to_string()))?,
+			Some("json") => serde_json::from_str(&contents).map_err(|err| parse_err(err.to_string()))?,
+			_ => return Err(parse_err("expected a `.toml` or `.json` file".to_owned())),
+		};
+
+		// so that mistakes show up when the file is loaded, not when a message comes in
+		RuleEngine::new(rules.clone()).map_err(|err| parse_err(err.to_string()))?;
+
+		Ok(rules)
+	}
+}
+
+struct CompiledRule {
+	rule: Rule,
+	pattern: Option<Regex>,
+	windows: Vec<(Vec<Weekday>, NaiveTime, NaiveTime)>,
+}
+
+// Runs a `RuleSet` against incoming messages. It's meant to be attached to a
+// `Bot` with `attach`, which already ignores messages sent from this phone, so
+// that the rules never respond to their own replies.
+pub struct RuleEngine {
+	rules: Vec<CompiledRule>,
+	// when each rule last fired in each chat, for the rate limits
+	fired: Mutex<HashMap<(usize, String), VecDeque<Instant>>>,
+}
+
+impl RuleEngine {
+	pub fn new(rules: RuleSet) -> anyhow::Result<RuleEngine> {
+		let rules = rules.rules.into_iter()
+			.map(|rule| {
+				let pattern = rule.pattern.as_deref()
+					.map(Regex::new)
+					.transpose()
+					.map_err(|err| anyhow::anyhow!("rule `{}`: {}", rule.name, err))?;
+
+				let windows = rule.windows.iter()
+					.map(|w| compile_window(w).map_err(|err| anyhow::anyhow!("rule `{}`: {}", rule.name, err)))
+					.collect::<anyhow::Result<_>>()?;
+
+				if rule.rate_limit.count == 0 {
+					anyhow::bail!("rule `{}`: rate_limit.count must be at least 1", rule.name);
+				}
+
+				Ok(CompiledRule { rule, pattern, windows })
+			})
+			.collect::<anyhow::Result<_>>()?;
+
+		Ok(RuleEngine {
+			rules,
+			fired: Mutex::new(HashMap::new()),
+		})
+	}
+
+	// adds a route to `bot` that runs the rules against every message that
+	// none of its other routes handled
+	pub fn attach(self, bot: Bot) -> Bot {
+		let engine = Arc::new(self);
+
+		bot.with_route(Route::any(), move |ctx| {
+			let engine = engine.clone();
+			async move { engine.handle(&ctx).await }
+		})
+	}
+
+	pub async fn handle(&self, ctx: &Context) -> anyhow::Result<()> {
+		// reacting to a reaction could go back and forth forever
+		if ctx.message.is_from_me || ctx.message.tapback().is_some() {
+			return Ok(());
+		}
+
+		for (idx, compiled) in self.rules.iter().enumerate() {
+			if !compiled.matches(ctx) || !self.allow(idx, compiled, &ctx.chat, Instant::now()) {
+				continue;
+			}
+
+			tracing::debug!(rule = %compiled.rule.name, chat = %ctx.chat, "rule matched");
+
+			for action in compiled.rule.actions.iter() {
+				run_action(action, ctx).await?;
+			}
+
+			if compiled.rule.stop {
+				break;
+			}
+		}
+
+		Ok(())
+	}
+
+	// whether the rule can fire in this chat at `now` without going over its
+	// rate limit, and if it can, counts it
+	fn allow(&self, idx: usize, compiled: &CompiledRule, chat: &str, now: Instant) -> bool {
+		let mut fired = match self.fired.lock() {
+			Ok(fired) => fired,
+			Err(_) => return false,
+		};
+
+		let limit = &compiled.rule.rate_limit;
+		let times = fired.entry((idx, chat.to_owned())).or_default();
+
+		while times.front().map(|t| now - *t > Duration::from_secs(limit.per_secs)).unwrap_or(false) {
+			times.pop_front();
+		}
+
+		if times.len() >= limit.count {
+			tracing::debug!(rule = %compiled.rule.name, %chat, "rule is rate limited");
+			return false;
+		}
+
+		times.push_back(now);
+		true
+	}
+}
+
+impl CompiledRule {
+	fn matches(&self, ctx: &Context) -> bool {
+		let rule = &self.rule;
+
+		if !rule.chats.is_empty() && !rule.chats.contains(&ctx.chat) {
+			return false;
+		}
+
+		if !rule.senders.is_empty() && !ctx.sender().map(|s| rule.senders.iter().any(|r| r == s)).unwrap_or(false) {
+			return false;
+		}
+
+		if let Some(pattern) = &self.pattern {
+			if !pattern.is_match(&ctx.message.text) {
+				return false;
+			}
+		}
+
+		let now = Local::now();
+		self.in_window(now.weekday(), now.time())
+	}
+
+	// whether this day and time (in local time) is in one of the rule's windows
+	fn in_window(&self, day: Weekday, time: NaiveTime) -> bool {
+		if self.windows.is_empty() {
+			return true;
+		}
+
+		self.windows.iter().any(|(days, start, end)| {
+			// for windows that go past midnight, the part after midnight is on the
+			// day after the one that the window is listed for
+			let (on_day, in_time) = if start <= end {
+				(day, *start <= time && time < *end)
+			} else if time >= *start {
+				(day, true)
+			} else {
+				(day.pred(), time < *end)
+			};
+
+			in_time && (days.is_empty() || days.contains(&on_day))
+		})
+	}
+}
+
+async fn run_action(action: &Action, ctx: &Context) -> anyhow::Result<()> {
+	match action {
+		Action::Reply(text) => ctx.reply(text.to_owned()).await,
+		Action::React(tapback) => ctx.react(*tapback).await,
+		Action::Forward(chat) => {
+			let from = ctx.sender().unwrap_or(&ctx.chat);
+			let text = format!("From {}: {}", from, ctx.message.text);
+
+			ctx.client.send_message(chat.to_owned(), Some(text), None, None, None).await
+		},
+	}
+}
+
+fn compile_window(window: &TimeWindow) -> anyhow::Result<(Vec<Weekday>, NaiveTime, NaiveTime)> {
+	let days = window.days.iter()
+		.map(|d| d.parse::<Weekday>().map_err(|_| anyhow::anyhow!("`{}` isn't a day", d)))
+		.collect::<anyhow::Result<_>>()?;
+
+	let time = |t: &Option<String>, default: NaiveTime| match t {
+		Some(t) => NaiveTime::parse_from_str(t, "%H:%M")
+			.map_err(|_| anyhow::anyhow!("`{}` isn't a time like `22:30`", t)),
+		None => Ok(default),
+	};
+
+	let start = time(&window.start, NaiveTime::MIN)?;
+	// the end is exclusive, so the end of the day has to be the last moment of it
+	let end = time(&window.end, NaiveTime::from_hms_nano_opt(23, 59, 59, 1_999_999_999).unwrap_or(NaiveTime::MIN))?;
+
+	Ok((days, start, end))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn engine(toml: &str) -> RuleEngine {
+		RuleEngine::new(toml::from_str(toml).unwrap()).unwrap()
+	}
+
+	fn at(h: u32, m: u32) -> NaiveTime {
+		NaiveTime::from_hms_opt(h, m, 0).unwrap()
+	}
+
+	#[test]
+	fn checks_time_windows() {
+		let engine = engine(r#"
+			[[rules]]
+			name = "overnight"
+			actions = []
+			windows = [{ days = ["fri"], start = "22:00", end = "07:00" }]
+
+			[[rules]]
+			name = "weekend"
+			actions = []
+			windows = [{ days = ["sat", "sun"] }, { start = "12:00", end = "13:00" }]
+		"#);
+
+		let overnight = &engine.rules[0];
+		assert!(!overnight.in_window(Weekday::Fri, at(21, 59)));
+		assert!(overnight.in_window(Weekday::Fri, at(22, 0)));
+		// after midnight, it's still friday's window
+		assert!(overnight.in_window(Weekday::Sat, at(6, 59)));
+		assert!(!overnight.in_window(Weekday::Sat, at(7, 0)));
+		assert!(!overnight.in_window(Weekday::Fri, at(3, 0)));
+		assert!(!overnight.in_window(Weekday::Sat, at(23, 0)));
+
+		let weekend = &engine.rules[1];
+		assert!(weekend.in_window(Weekday::Sun, at(0, 0)));
+		assert!(weekend.in_window(Weekday::Sun, NaiveTime::from_hms_opt(23, 59, 59).unwrap()));
+		assert!(weekend.in_window(Weekday::Mon, at(12, 30)));
+		assert!(!weekend.in_window(Weekday::Mon, at(13, 0)));
+	}
+
+	#[test]
+	fn rejects_bad_rules() {
+		let bad = |toml: &str| RuleEngine::new(toml::from_str(toml).unwrap()).is_err();
+
+		assert!(bad(r#"rules = [{ name = "a", actions = [], windows = [{ days = ["someday"] }] }]"#));
+		assert!(bad(r#"rules = [{ name = "a", actions = [], windows = [{ start = "25:00" }] }]"#));
+		assert!(bad(r#"rules = [{ name = "a", actions = [], pattern = "(" }]"#));
+		assert!(bad(r#"rules = [{ name = "a", actions = [], rate_limit = { count = 0, per_secs = 1 } }]"#));
+	}
+
+	#[test]
+	fn rate_limits_each_chat() {
+		let engine = engine(r#"
+			[[rules]]
+			name = "limited"
+			actions = []
+			rate_limit = { count = 2, per_secs = 60 }
+		"#);
+
+		let rule = &engine.rules[0];
+		let start = Instant::now();
+		let later = |secs| start + Duration::from_secs(secs);
+
+		assert!(engine.allow(0, rule, "a", start));
+		assert!(engine.allow(0, rule, "a", later(10)));
+		assert!(!engine.allow(0, rule, "a", later(20)));
+		// other chats have their own limit
+		assert!(engine.allow(0, rule, "b", later(20)));
+
+		// the first one is more than a minute old now, so there's room for one more
+		assert!(engine.allow(0, rule, "a", later(61)));
+		assert!(!engine.allow(0, rule, "a", later(62)));
+		assert!(engine.allow(0, rule, "a", later(71)));
+	}
+}
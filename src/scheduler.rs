@@ -0,0 +1,452 @@
+use std::{
+	collections::BTreeMap,
+	future::Future,
+	path::PathBuf,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::{
+	api::APIClient,
+	dispatch::Shutdown,
+};
+
+// the longest the scheduler sleeps before checking the clock again, so that
+// jobs still go out at the right time if the system clock changes
+const MAX_SLEEP: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobStatus {
+	Pending,
+	// it's being sent right now
+	Sending,
+	Sent { at: DateTime<Utc> },
+	Failed { at: DateTime<Utc>, error: String },
+	Cancelled,
+}
+
+// a message to be sent with `APIClient::send_message` at `send_at`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ScheduledMessage {
+	pub id: String,
+	pub chat: String,
+	pub text: Option<String>,
+	pub subject: Option<String>,
+	// paths to files on this machine, like the `attachments` of `send_message`
+	pub attachments: Option<Vec<String>>,
+	pub send_at: DateTime<Utc>,
+	pub created_at: DateTime<Utc>,
+	#[serde(flatten)]
+	pub status: JobStatus,
+}
+
+impl ScheduledMessage {
+	pub fn new(chat: impl Into<String>, send_at: DateTime<Utc>) -> ScheduledMessage {
+		ScheduledMessage {
+			id: uuid::Uuid::new_v4().to_string(),
+			chat: chat.into(),
+			text: None,
+			subject: None,
+			attachments: None,
+			send_at,
+			created_at: Utc::now(),
+			status: JobStatus::Pending,
+		}
+	}
+
+	pub fn with_text(mut self, text: impl Into<String>) -> Self {
+		self.text = Some(text.into());
+		self
+	}
+
+	pub fn with_subject(mut self, subject: impl Into<String>) -> Self {
+		self.subject = Some(subject.into());
+		self
+	}
+
+	pub fn with_attachments(mut self, attachments: Vec<String>) -> Self {
+		self.attachments = Some(attachments);
+		self
+	}
+
+	pub fn is_pending(&self) -> bool {
+		self.status == JobStatus::Pending
+	}
+}
+
+// Sends messages at the times they were scheduled for. Every job is saved to
+// `path` whenever it changes, so jobs that were scheduled before the process
+// restarted are still sent (late, if their time passed while it was down).
+// Messages are never retried once sending them fails, since they might have
+// been sent anyways; the job is marked as failed instead.
+pub struct Scheduler {
+	client: Arc<APIClient>,
+	store: Arc<JobStore>,
+	// woken whenever the jobs change, so that the run loop can look at them again
+	changed: tokio::sync::Notify,
+	events: Option<crossbeam_channel::Sender<ScheduledMessage>>,
+	max_lateness: Option<Duration>,
+}
+
+impl Scheduler {
+	// loads the jobs that were saved to `path`, if it exists
+	pub fn new(client: Arc<APIClient>, path: impl Into<PathBuf>) -> anyhow::Result<Scheduler> {
+		Ok(Scheduler {
+			client,
+			store: Arc::new(JobStore::load(path.into())?),
+			changed: tokio::sync::Notify::new(),
+			events: None,
+			max_lateness: None,
+		})
+	}
+
+	// receives each job once it's been sent or has failed
+	pub fn with_events(mut self, sender: crossbeam_channel::Sender<ScheduledMessage>) -> Self {
+		self.events = Some(sender);
+		self
+	}
+
+	// jobs that are more than this late (e.g. because the process wasn't running
+	// when they were due) are marked as failed instead of being sent
+	pub fn with_max_lateness(mut self, lateness: Duration) -> Self {
+		self.max_lateness = Some(lateness);
+		self
+	}
+
+	// returns the id of the job
+	pub fn schedule(&self, job: ScheduledMessage) -> anyhow::Result<String> {
+		let id = job.id.to_owned();
+
+		self.update_jobs(|jobs| {
+			jobs.insert(id.to_owned(), job);
+			Ok(())
+		})?;
+
+		tracing::debug!(%id, "scheduled message");
+		Ok(id)
+	}
+
+	// returns false if the job doesn't exist or was already sent
+	pub fn cancel(&self, id: &str) -> anyhow::Result<bool> {
+		self.update_jobs(|jobs| Ok(match jobs.get_mut(id) {
+			Some(job) if job.is_pending() => {
+				job.status = JobStatus::Cancelled;
+				true
+			},
+			_ => false,
+		}))
+	}
+
+	// changes a job that hasn't been sent yet, e.g. to change its text or time.
+	// Returns false if the job doesn't exist or was already sent.
+	pub fn edit(&self, id: &str, edit: impl FnOnce(&mut ScheduledMessage)) -> anyhow::Result<bool> {
+		self.update_jobs(|jobs| Ok(match jobs.get_mut(id) {
+			Some(job) if job.is_pending() => {
+				edit(job);
+
+				// so that the edit can't change which job this is, or mark it as sent
+				job.id = id.to_owned();
+				job.status = JobStatus::Pending;
+				true
+			},
+			_ => false,
+		}))
+	}
+
+	pub fn job(&self, id: &str) -> Option<ScheduledMessage> {
+		self.store.jobs.lock().ok()?.get(id).cloned()
+	}
+
+	// every job, including the ones that were already sent, failed or were cancelled
+	pub fn jobs(&self) -> Vec<ScheduledMessage> {
+		self.store.jobs.lock()
+			.map(|jobs| jobs.values().cloned().collect())
+			.unwrap_or_default()
+	}
+
+	// forgets the jobs that aren't pending anymore
+	pub fn prune(&self) -> anyhow::Result<()> {
+		self.update_jobs(|jobs| {
+			jobs.retain(|_, job| job.is_pending());
+			Ok(())
+		})
+	}
+
+	// sends the jobs as they become due, until `shutdown` finishes
+	pub async fn run(&self, shutdown: impl Future<Output = ()>) {
+		let mut shutdown = Shutdown::new(shutdown);
+
+		tracing::info!(jobs = self.jobs().len(), "scheduler started");
+
+		loop {
+			for id in self.due_jobs() {
+				self.send(&id).await;
+			}
+
+			let wait = self.next_due()
+				.map(|at| (at - Utc::now()).to_std().unwrap_or_default())
+				.unwrap_or(MAX_SLEEP)
+				.min(MAX_SLEEP);
+
+			// wakes up when the next job is due, or when the jobs change
+			let wake = futures_util::future::select(
+				Box::pin(tokio::time::sleep(wait)),
+				Box::pin(self.changed.notified()),
+			);
+
+			if shutdown.or(wake).await.is_none() {
+				break;
+			}
+		}
+
+		tracing::info!("scheduler stopped");
+	}
+
+	// the ids of the jobs that should be sent now, oldest first
+	fn due_jobs(&self) -> Vec<String> {
+		let now = Utc::now();
+
+		let mut due: Vec<ScheduledMessage> = self.jobs().into_iter()
+			.filter(|job| job.is_pending() && job.send_at <= now)
+			.collect();
+
+		due.sort_by_key(|job| job.send_at);
+		due.into_iter().map(|job| job.id).collect()
+	}
+
+	fn next_due(&self) -> Option<DateTime<Utc>> {
+		self.store.jobs.lock().ok()?
+			.values()
+			.filter(|job| job.is_pending())
+			.map(|job| job.send_at)
+			.min()
+	}
+
+	async fn send(&self, id: &str) {
+		// marked as sending first, so that it can't be cancelled or edited partway
+		// through, and so that it isn't sent again if the process restarts
+		let claim_id = id.to_owned();
+		let claimed = self.update_jobs_async(move |jobs| Ok(match jobs.get_mut(&claim_id) {
+			Some(job) if job.is_pending() && job.send_at <= Utc::now() => {
+				job.status = JobStatus::Sending;
+				Some(job.clone())
+			},
+			_ => None,
+		})).await;
+
+		let job = match claimed {
+			Ok(Some(job)) => job,
+			Ok(None) => return,
+			Err(err) => {
+				tracing::error!(%id, error = %err, "failed to save scheduled messages");
+				return;
+			},
+		};
+
+		let lateness = (Utc::now() - job.send_at).to_std().unwrap_or_default();
+
+		let status = match self.max_lateness {
+			Some(max) if lateness > max => JobStatus::Failed {
+				at: Utc::now(),
+				error: format!("missed its time by {}s", lateness.as_secs()),
+			},
+			_ => match self.client.send_message(
+				job.chat.to_owned(),
+				job.text.to_owned(),
+				job.subject.to_owned(),
+				job.attachments.to_owned(),
+				None
+			).await {
+				Ok(()) => JobStatus::Sent { at: Utc::now() },
+				Err(err) => JobStatus::Failed { at: Utc::now(), error: err.to_string() },
+			},
+		};
+
+		match &status {
+			JobStatus::Failed { error, .. } =>
+				tracing::warn!(id = %job.id, chat = %job.chat, %error, "scheduled message failed"),
+			_ => tracing::info!(id = %job.id, chat = %job.chat, "sent scheduled message"),
+		}
+
+		let job_id = job.id.to_owned();
+		let res = self.update_jobs_async(move |jobs| {
+			Ok(jobs.get_mut(&job_id).map(|j| {
+				j.status = status;
+				j.clone()
+			}))
+		}).await;
+
+		match res {
+			Ok(Some(job)) => if let Some(events) = &self.events {
+				let _ = events.send(job);
+			},
+			Ok(None) => (),
+			Err(err) => tracing::error!(id = %job.id, error = %err, "failed to save scheduled messages"),
+		}
+	}
+
+	// makes a change to the jobs, saves them, and wakes up the run loop
+	fn update_jobs<T>(
+		&self, update: impl FnOnce(&mut BTreeMap<String, ScheduledMessage>) -> anyhow::Result<T>
+	) -> anyhow::Result<T> {
+		let res = self.store.update(update)?;
+		self.changed.notify_one();
+
+		Ok(res)
+	}
+
+	// the same as `update_jobs`, but saves them on the blocking thread pool,
+	// so that the run loop doesn't hold up the runtime while it writes the file
+	async fn update_jobs_async<T: Send + 'static>(
+		&self,
+		update: impl FnOnce(&mut BTreeMap<String, ScheduledMessage>) -> anyhow::Result<T> + Send + 'static
+	) -> anyhow::Result<T> {
+		let store = self.store.clone();
+		let res = tokio::task::spawn_blocking(move || store.update(update)).await??;
+		self.changed.notify_one();
+
+		Ok(res)
+	}
+}
+
+// the jobs, and the file that they're saved to
+struct JobStore {
+	path: PathBuf,
+	jobs: Mutex<BTreeMap<String, ScheduledMessage>>,
+	// held from when a change starts until it's saved, so that two changes
+	// can't both start from the same jobs and one of them be lost. `jobs` is only
+	// locked long enough to copy or replace them, so reading them doesn't have
+	// to wait for the file to be written.
+	saving: Mutex<()>,
+}
+
+impl JobStore {
+	fn load(path: PathBuf) -> anyhow::Result<JobStore> {
+		let jobs: BTreeMap<String, ScheduledMessage> = match path.exists() {
+			true => serde_json::from_slice(&std::fs::read(&path)?)?,
+			false => BTreeMap::new(),
+		};
+
+		let interrupted = jobs.values().any(|j| j.status == JobStatus::Sending);
+		let store = JobStore {
+			path,
+			jobs: Mutex::new(jobs),
+			saving: Mutex::new(()),
+		};
+
+		// if the process stopped while these were being sent, there's no way to
+		// know if they went through, and sending them again could double them up.
+		// This is saved right away, so that the file agrees.
+		if interrupted {
+			store.update(|jobs| {
+				for job in jobs.values_mut().filter(|j| j.status == JobStatus::Sending) {
+					job.status = JobStatus::Failed {
+						at: Utc::now(),
+						error: "interrupted while sending".to_owned(),
+					};
+				}
+
+				Ok(())
+			})?;
+		}
+
+		Ok(store)
+	}
+
+	// makes a change to a copy of the jobs, and only uses the copy once it's
+	// been saved, so that the jobs in memory never get ahead of the file
+	fn update<T>(
+		&self, update: impl FnOnce(&mut BTreeMap<String, ScheduledMessage>) -> anyhow::Result<T>
+	) -> anyhow::Result<T> {
+		let poisoned = || anyhow::anyhow!("scheduled messages lock was poisoned");
+
+		let _saving = self.saving.lock().map_err(|_| poisoned())?;
+		let mut jobs = self.jobs.lock().map_err(|_| poisoned())?.clone();
+
+		let res = update(&mut jobs)?;
+
+		// written to a temporary file first, so that a crash can't lose every job
+		let tmp = self.path.with_extension("tmp");
+		std::fs::write(&tmp, serde_json::to_vec_pretty(&jobs)?)?;
+		std::fs::rename(&tmp, &self.path)?;
+
+		*self.jobs.lock().map_err(|_| poisoned())? = jobs;
+
+		Ok(res)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn store(path: PathBuf) -> JobStore {
+		JobStore {
+			path,
+			jobs: Mutex::new(BTreeMap::new()),
+			saving: Mutex::new(()),
+		}
+	}
+
+	fn ids(store: &JobStore) -> Vec<String> {
+		store.jobs.lock().unwrap().keys().cloned().collect()
+	}
+
+	#[test]
+	fn only_keeps_changes_that_were_saved() {
+		let path = std::env::temp_dir()
+			.join(format!("smserver-scheduled-{}.json", uuid::Uuid::new_v4()));
+		let store = store(path.to_owned());
+
+		let job = ScheduledMessage::new("chat", Utc::now()).with_text("hi");
+		let id = job.id.to_owned();
+
+		store.update(|jobs| {
+			jobs.insert(job.id.to_owned(), job);
+			Ok(())
+		}).unwrap();
+
+		let saved: BTreeMap<String, ScheduledMessage> =
+			serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+		assert_eq!(saved.keys().collect::<Vec<_>>(), vec![&id]);
+
+		// a change that fails partway through is thrown away
+		let res: anyhow::Result<()> = store.update(|jobs| {
+			jobs.clear();
+			anyhow::bail!("changed my mind")
+		});
+		assert!(res.is_err());
+		assert_eq!(ids(&store), vec![id.to_owned()]);
+
+		// and so is one that couldn't be saved
+		let unsaveable = JobStore {
+			path: path.join("not-a-dir").join("jobs.json"),
+			..store
+		};
+		assert!(unsaveable.update(|jobs| { jobs.clear(); Ok(()) }).is_err());
+		assert_eq!(ids(&unsaveable), vec![id]);
+
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn saves_interrupted_jobs_as_failed() {
+		let path = std::env::temp_dir()
+			.join(format!("smserver-scheduled-{}.json", uuid::Uuid::new_v4()));
+
+		let mut job = ScheduledMessage::new("chat", Utc::now()).with_text("hi");
+		job.status = JobStatus::Sending;
+		let jobs = BTreeMap::from([(job.id.to_owned(), job)]);
+		std::fs::write(&path, serde_json::to_vec(&jobs).unwrap()).unwrap();
+
+		JobStore::load(path.to_owned()).unwrap();
+
+		let saved: BTreeMap<String, ScheduledMessage> =
+			serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+		assert!(saved.values().all(|j| matches!(j.status, JobStatus::Failed { .. })));
+
+		std::fs::remove_file(&path).unwrap();
+	}
+}
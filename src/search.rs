@@ -0,0 +1,502 @@
+use std::{
+	ops::Range,
+	sync::Mutex,
+};
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use crate::{
+	api::{APIClient, APIRequest},
+	commands::{APICommand, GetChatsRequest, GetMessagesRequest, SearchMessagesRequest},
+	error::SDKError,
+	models::{apple_date, to_apple_date, Message},
+};
+
+// how many chats or messages are requested at once while scanning the history
+const SCAN_PAGE_SIZE: u32 = 100;
+
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+	pub text: String,
+	// only search this chat, instead of every chat
+	pub chat: Option<String>,
+	pub after: Option<DateTime<Utc>>,
+	pub before: Option<DateTime<Utc>>,
+	pub limit: u32,
+	pub offset: u32,
+}
+
+// a message that matched, along with where in its text and subject the query
+// was found (as byte ranges), so that the matches can be highlighted
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+	pub message: Message,
+	pub text_ranges: Vec<Range<usize>>,
+	pub subject_ranges: Vec<Range<usize>>,
+}
+
+impl SearchQuery {
+	pub fn new(text: impl Into<String>) -> Self {
+		SearchQuery {
+			text: text.into(),
+			chat: None,
+			after: None,
+			before: None,
+			limit: 50,
+			offset: 0,
+		}
+	}
+
+	pub fn with_chat(mut self, chat: impl Into<String>) -> Self {
+		self.chat = Some(chat.into());
+		self
+	}
+
+	pub fn with_after(mut self, after: DateTime<Utc>) -> Self {
+		self.after = Some(after);
+		self
+	}
+
+	pub fn with_before(mut self, before: DateTime<Utc>) -> Self {
+		self.before = Some(before);
+		self
+	}
+
+	pub fn with_limit(mut self, limit: u32) -> Self {
+		self.limit = limit;
+		self
+	}
+
+	pub fn with_offset(mut self, offset: u32) -> Self {
+		self.offset = offset;
+		self
+	}
+
+	fn request(&self) -> SearchMessagesRequest {
+		let mut req = SearchMessagesRequest::new(self.text.to_owned())
+			.with_search_limit(self.limit)
+			.with_search_offset(self.offset);
+
+		if let Some(chat) = &self.chat {
+			req = req.with_search_chat(chat.to_owned());
+		}
+
+		if let Some(after) = self.after {
+			req = req.with_search_after(to_apple_date(after));
+		}
+
+		if let Some(before) = self.before {
+			req = req.with_search_before(to_apple_date(before));
+		}
+
+		req
+	}
+
+	fn in_range(&self, message: &Message) -> bool {
+		let date = match apple_date(message.date) {
+			Some(date) => date,
+			None => return self.after.is_none() && self.before.is_none(),
+		};
+
+		self.after.map(|a| date >= a).unwrap_or(true)
+			&& self.before.map(|b| date < b).unwrap_or(true)
+	}
+
+	// whether this message (and every one before it in the chat) is too old to match
+	fn is_before_range(&self, message: &Message) -> bool {
+		match (self.after, apple_date(message.date)) {
+			(Some(after), Some(date)) => date < after,
+			_ => false,
+		}
+	}
+}
+
+impl SearchHit {
+	fn new(message: Message, query: &str) -> SearchHit {
+		SearchHit {
+			text_ranges: find_matches(&message.text, query),
+			subject_ranges: find_matches(&message.subject, query),
+			message,
+		}
+	}
+
+	fn is_match(&self) -> bool {
+		!self.text_ranges.is_empty() || !self.subject_ranges.is_empty()
+	}
+
+	// the text of the message with each match wrapped in `open` and `close`,
+	// e.g. `hit.highlighted("**", "**")`
+	pub fn highlighted(&self, open: &str, close: &str) -> String {
+		let text = &self.message.text;
+		let mut out = String::with_capacity(text.len());
+		let mut last = 0;
+
+		for range in self.text_ranges.iter() {
+			out.push_str(&text[last..range.start]);
+			out.push_str(open);
+			out.push_str(&text[range.clone()]);
+			out.push_str(close);
+			last = range.end;
+		}
+
+		out.push_str(&text[last..]);
+		out
+	}
+}
+
+// Whether the host can search by itself, which each client finds out with its
+// first search, so that a host which never answers `search-messages` only makes
+// us wait for the timeout once.
+#[derive(Debug, Default)]
+pub struct HostSearch {
+	supported: Mutex<Option<bool>>,
+}
+
+impl HostSearch {
+	// None until the first search on the host finishes
+	pub fn supported(&self) -> Option<bool> {
+		self.supported.lock().ok().and_then(|s| *s)
+	}
+
+	// records what `result` says about the host, and returns whether to fall
+	// back to `search_history`
+	fn falls_back<T>(&self, result: &anyhow::Result<T>) -> bool {
+		let mut supported = match self.supported.lock() {
+			Ok(supported) => supported,
+			Err(_) => return false,
+		};
+
+		match result {
+			Ok(_) => {
+				*supported = Some(true);
+				false
+			},
+			Err(err) if is_unsupported(err, *supported == Some(true)) => {
+				*supported = Some(false);
+				true
+			},
+			Err(_) => false,
+		}
+	}
+}
+
+impl APIClient {
+	// Searches for messages with `search-messages`. If the host can't (see
+	// `is_unsupported`), this falls back to `search_history`, which is much
+	// slower, and keeps doing so for the rest of this client's searches. Once the
+	// host has searched successfully, errors are returned as is, since it's
+	// probably just having trouble; `search_history` can still be called
+	// directly then. Either way, the results are newest first and case-insensitive.
+	pub async fn search(&self, query: &SearchQuery) -> anyhow::Result<Vec<SearchHit>> {
+		if self.host_search.supported() == Some(false) {
+			return self.search_history(query).await;
+		}
+
+		let res = self.search_on_host(query).await;
+
+		if self.host_search.falls_back(&res) {
+			if let Err(err) = &res {
+				tracing::info!(error = %err, "host can't search, scanning history instead");
+			}
+
+			return self.search_history(query).await;
+		}
+
+		Ok(res?.into_iter()
+			.map(|m| SearchHit::new(m, &query.text))
+			.collect())
+	}
+
+	async fn search_on_host(&self, query: &SearchQuery) -> anyhow::Result<Vec<Message>> {
+		let req = query.request();
+
+		if self.uses_rest {
+			return req.execute(self).await;
+		}
+
+		// this is sent by hand (instead of with `execute`) so that we can see
+		// which command the host says that it's responding to
+		let mut reply = self.socket.request_search_messages(
+			&req.search,
+			req.search_chat,
+			req.search_after,
+			req.search_before,
+			req.search_limit,
+			req.search_offset,
+		).await?;
+
+		let msg = reply.recv().await?;
+
+		if let APICommand::Unknown(_) = msg.command {
+			return Err(SDKError::UnknownCommand.into());
+		}
+
+		Ok(serde_json::from_value(msg.data)?)
+	}
+
+	// pages through the history of each chat (or just `query.chat`) and
+	// searches it here, for hosts that can't search themselves
+	pub async fn search_history(&self, query: &SearchQuery) -> anyhow::Result<Vec<SearchHit>> {
+		let chats = match &query.chat {
+			Some(chat) => vec![chat.to_owned()],
+			None => self.all_chat_ids().await?,
+		};
+
+		let results: Vec<anyhow::Result<Vec<SearchHit>>> = futures_util::stream::iter(chats)
+			.map(|chat| async move { self.scan_chat(&chat, query).await })
+			.buffer_unordered(self.batch_concurrency)
+			.collect()
+			.await;
+
+		let mut hits = Vec::new();
+
+		for res in results {
+			hits.extend(res?);
+		}
+
+		hits.sort_by_key(|h| std::cmp::Reverse(h.message.date));
+
+		Ok(hits.into_iter()
+			.skip(query.offset as usize)
+			.take(query.limit as usize)
+			.collect())
+	}
+
+	async fn all_chat_ids(&self) -> anyhow::Result<Vec<String>> {
+		let mut ids = Vec::new();
+
+		loop {
+			let page = GetChatsRequest::new()
+				.with_chats(SCAN_PAGE_SIZE)
+				.with_chats_offset(ids.len() as u32)
+				.execute(self)
+				.await?;
+
+			let done = (page.len() as u32) < SCAN_PAGE_SIZE;
+			ids.extend(page.into_iter().map(|c| c.chat_identifier));
+
+			if done {
+				return Ok(ids);
+			}
+		}
+	}
+
+	async fn scan_chat(&self, chat: &str, query: &SearchQuery) -> anyhow::Result<Vec<SearchHit>> {
+		// the results across all chats are sorted afterwards, so each chat only
+		// has to contribute as many as could end up on the requested page
+		let wanted = query.offset.saturating_add(query.limit) as usize;
+		let mut hits = Vec::new();
+		let mut offset = 0;
+
+		loop {
+			let page = GetMessagesRequest::new(chat)
+				.with_num_messages(SCAN_PAGE_SIZE)
+				.with_messages_offset(offset)
+				.with_read_messages(false)
+				.execute(self)
+				.await?;
+
+			offset += page.len() as u32;
+			let done = (page.len() as u32) < SCAN_PAGE_SIZE
+				|| page.last().map(|m| query.is_before_range(m)).unwrap_or(true);
+
+			for mut message in page.into_iter().filter(|m| query.in_range(m)) {
+				if message.chat_identifier.is_none() {
+					message.chat_identifier = Some(chat.to_owned());
+				}
+
+				let hit = SearchHit::new(message, &query.text);
+
+				if hit.is_match() {
+					hits.push(hit);
+				}
+			}
+
+			if done || hits.len() >= wanted {
+				return Ok(hits);
+			}
+		}
+	}
+}
+
+// whether this error means that the host doesn't know the `search-messages`
+// command. Some say so, and some older ones never answer it over the socket, or
+// answer `/requests?search=` with something other than messages; those are only
+// taken to mean that if the host hasn't searched successfully before.
+fn is_unsupported(err: &anyhow::Error, has_searched: bool) -> bool {
+	if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+		return err.status() == Some(reqwest::StatusCode::NOT_FOUND)
+			|| (err.is_timeout() && !has_searched);
+	}
+
+	match err.downcast_ref::<SDKError>() {
+		Some(SDKError::UnknownCommand) => true,
+		Some(SDKError::TimedOut) => !has_searched,
+		_ => err.is::<serde_json::Error>() && !has_searched,
+	}
+}
+
+// finds every place that `needle` is in `haystack`, ignoring case
+fn find_matches(haystack: &str, needle: &str) -> Vec<Range<usize>> {
+	let mut ranges = Vec::new();
+
+	if needle.is_empty() {
+		return ranges;
+	}
+
+	let mut start = 0;
+
+	while start < haystack.len() {
+		match match_len(&haystack[start..], needle) {
+			Some(len) => {
+				ranges.push(start..start + len);
+				start += len;
+			},
+			None => {
+				start += haystack[start..].chars().next().map(char::len_utf8).unwrap_or(1);
+			},
+		}
+	}
+
+	ranges
+}
+
+// if `text` starts with `needle` (ignoring case), how many bytes of `text` that covers
+fn match_len(text: &str, needle: &str) -> Option<usize> {
+	let mut chars = text.char_indices();
+	let mut end = 0;
+
+	for n in needle.chars() {
+		let (idx, c) = chars.next()?;
+
+		if !c.to_lowercase().eq(n.to_lowercase()) {
+			return None;
+		}
+
+		end = idx + c.len_utf8();
+	}
+
+	Some(end)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::TimeZone;
+
+	fn message(text: &str, date: Option<DateTime<Utc>>) -> Message {
+		let mut message = Message::typing("chat");
+		message.text = text.to_owned();
+		message.date = date.map(to_apple_date).unwrap_or(0);
+		message
+	}
+
+	#[test]
+	fn finds_every_match_ignoring_case() {
+		assert_eq!(find_matches("Hello hello HELLO", "hello"), vec![0..5, 6..11, 12..17]);
+		assert_eq!(find_matches("aaaa", "aa"), vec![0..2, 2..4]);
+		assert!(find_matches("anything", "").is_empty());
+		assert!(find_matches("short", "much longer").is_empty());
+
+		// the ranges are in bytes, and can be different lengths than the query
+		assert_eq!(find_matches("café CAFÉ", "café"), vec![0..5, 6..11]);
+		assert_eq!(find_matches("👋 ÅNGSTRÖM", "ångström"), vec![5..15]);
+	}
+
+	#[test]
+	fn highlights_matches() {
+		let hit = SearchHit::new(message("Dinner? dinner!", None), "DINNER");
+
+		assert!(hit.is_match());
+		assert_eq!(hit.highlighted("**", "**"), "**Dinner**? **dinner**!");
+		assert!(!SearchHit::new(message("lunch", None), "dinner").is_match());
+	}
+
+	#[test]
+	fn filters_by_date() {
+		let day = |d| Utc.with_ymd_and_hms(2022, 3, d, 0, 0, 0).unwrap();
+		let query = SearchQuery::new("x").with_after(day(2)).with_before(day(4));
+
+		assert!(!query.in_range(&message("x", Some(day(1)))));
+		assert!(query.in_range(&message("x", Some(day(2)))));
+		assert!(!query.in_range(&message("x", Some(day(4)))));
+		// no date can't be in a range
+		assert!(!query.in_range(&message("x", None)));
+		assert!(SearchQuery::new("x").in_range(&message("x", None)));
+
+		assert!(query.is_before_range(&message("x", Some(day(1)))));
+		assert!(!query.is_before_range(&message("x", Some(day(3)))));
+	}
+
+	#[test]
+	fn encodes_the_query_for_rest() {
+		use std::io::{BufRead, Write};
+
+		// answers one request with no results, and sends back the path it asked for
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let server = std::thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			let mut reader = std::io::BufReader::new(stream);
+			let mut line = String::new();
+			reader.read_line(&mut line).unwrap();
+
+			let mut stream = reader.into_inner();
+			stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n[]").unwrap();
+			line
+		});
+
+		let config = crate::config::SDKConfig::default().with_rest_url(format!("http://127.0.0.1:{}", port));
+		let rest = crate::rest_api::RestAPIClient::new(config);
+		rest.authenticated.store(true, std::sync::atomic::Ordering::SeqCst);
+
+		let req = SearchQuery::new("a&b #1").with_chat("+1 555%").with_limit(5).request();
+		let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+		let found = rt.block_on(rest.search_messages(
+			&req.search, req.search_chat, req.search_after, req.search_before, req.search_limit, req.search_offset
+		)).unwrap();
+		assert!(found.is_empty());
+
+		let line = server.join().unwrap();
+		assert!(
+			line.starts_with("GET /requests?search=a%26b%20%231&search_chat=%2B1%20555%25&search_after&search_before&search_limit=5&search_offset=0 "),
+			"{}", line
+		);
+	}
+
+	fn not_messages() -> anyhow::Error {
+		serde_json::from_str::<Vec<Message>>("{}").unwrap_err().into()
+	}
+
+	#[test]
+	fn falls_back_when_the_host_never_answers() {
+		let host = HostSearch::default();
+
+		assert!(host.falls_back::<()>(&Err(SDKError::TimedOut.into())));
+		assert_eq!(host.supported(), Some(false));
+
+		// but once it has answered, a timeout is just a timeout
+		let host = HostSearch::default();
+
+		assert!(!host.falls_back(&Ok(())));
+		assert!(!host.falls_back::<()>(&Err(SDKError::TimedOut.into())));
+		assert_eq!(host.supported(), Some(true));
+	}
+
+	#[test]
+	fn falls_back_when_the_host_doesnt_send_messages() {
+		let host = HostSearch::default();
+
+		assert!(host.falls_back::<()>(&Err(not_messages())));
+		assert_eq!(host.supported(), Some(false));
+
+		let host = HostSearch::default();
+		host.falls_back(&Ok(()));
+
+		assert!(!host.falls_back::<()>(&Err(not_messages())));
+		assert!(!host.falls_back::<()>(&Err(SDKError::MangledReceive.into())));
+		// a host that says it doesn't know the command always falls back
+		assert!(host.falls_back::<()>(&Err(SDKError::UnknownCommand.into())));
+		assert_eq!(host.supported(), Some(false));
+	}
+}
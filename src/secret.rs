@@ -0,0 +1,99 @@
+use std::{
+	fmt,
+	path::PathBuf,
+	sync::Arc,
+};
+use zeroize::Zeroize;
+
+const REDACTED: &str = "[redacted]";
+
+// a string that's never shown in `Debug` or `Display` (and so never ends up
+// in logs or errors by accident), and is wiped from memory once it's dropped.
+// The only way to get the actual value is through `expose`.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+	pub fn new(secret: impl Into<String>) -> Secret {
+		Secret(secret.into())
+	}
+
+	pub fn expose(&self) -> &str {
+		&self.0
+	}
+}
+
+impl From<String> for Secret {
+	fn from(secret: String) -> Secret {
+		Secret(secret)
+	}
+}
+
+impl From<&str> for Secret {
+	fn from(secret: &str) -> Secret {
+		Secret(secret.to_owned())
+	}
+}
+
+impl Drop for Secret {
+	fn drop(&mut self) {
+		self.0.zeroize();
+	}
+}
+
+impl fmt::Debug for Secret {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Secret({})", REDACTED)
+	}
+}
+
+impl fmt::Display for Secret {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(REDACTED)
+	}
+}
+
+pub type PasswordProvider = Arc<dyn Fn() -> anyhow::Result<Secret> + Send + Sync>;
+
+// where the password for SMServer comes from. Files and providers are read every
+// time the password is needed, so the password can change while the SDK is running.
+#[derive(Clone)]
+pub enum Password {
+	Secret(Secret),
+	File(PathBuf),
+	Provider(PasswordProvider),
+}
+
+impl Password {
+	pub fn resolve(&self) -> anyhow::Result<Secret> {
+		match self {
+			Password::Secret(secret) => Ok(secret.clone()),
+			Password::File(path) => {
+				let mut contents = std::fs::read_to_string(path)?;
+
+				// files almost always end with a newline, which isn't part of the password
+				let secret = Secret::new(contents.trim_end_matches(&['\n', '\r'][..]));
+				contents.zeroize();
+
+				Ok(secret)
+			},
+			Password::Provider(provider) => provider(),
+		}
+	}
+}
+
+impl Default for Password {
+	fn default() -> Password {
+		Password::Secret(Secret::new("toor"))
+	}
+}
+
+impl fmt::Debug for Password {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Password::Secret(secret) => write!(f, "Password::Secret({:?})", secret),
+			Password::File(path) => write!(f, "Password::File({:?})", path),
+			Password::Provider(_) => f.write_str("Password::Provider"),
+		}
+	}
+}
@@ -0,0 +1,197 @@
+use std::{
+	collections::HashMap,
+	io::Write,
+	path::PathBuf,
+	sync::Mutex,
+};
+use reqwest::header::HeaderValue;
+
+// The cookies that SMServer uses to remember that we've authenticated. Since
+// the SDK only ever talks to one host, they're just stored by name. If a `path`
+// is given, they're loaded from it and saved to it whenever they change, so
+// that the session can be reused the next time the SDK starts.
+pub struct SessionStore {
+	cookies: Mutex<HashMap<String, String>>,
+	path: Option<PathBuf>,
+}
+
+impl SessionStore {
+	pub fn new(path: Option<PathBuf>) -> SessionStore {
+		// if the file is missing or mangled, we'll just authenticate again
+		let cookies = path.as_ref()
+			.and_then(|p| std::fs::read(p).ok())
+			.and_then(|data| serde_json::from_slice(&data).ok())
+			.unwrap_or_default();
+
+		SessionStore {
+			cookies: Mutex::new(cookies),
+			path,
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.cookies.lock()
+			.map(|c| c.is_empty())
+			.unwrap_or(true)
+	}
+
+	// forgets the session, e.g. once the host has rejected it
+	pub fn clear(&self) {
+		if let Ok(mut cookies) = self.cookies.lock() {
+			cookies.clear();
+		}
+
+		self.save();
+	}
+
+	fn save(&self) {
+		let path = match &self.path {
+			Some(path) => path,
+			None => return,
+		};
+
+		let data = match self.cookies.lock() {
+			Ok(cookies) => serde_json::to_vec(&*cookies),
+			Err(_) => return,
+		};
+
+		if let Err(err) = data.map_err(Into::into).and_then(|d| write_private(path, &d)) {
+			tracing::warn!(path = ?path, error = %err, "failed to save session");
+		}
+	}
+}
+
+// the session is as good as a password, so only the current user can read it
+fn write_private(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+	let mut options = std::fs::OpenOptions::new();
+	options.write(true).create(true).truncate(true);
+
+	#[cfg(unix)]
+	std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+	options.open(path)?.write_all(data)
+}
+
+impl reqwest::cookie::CookieStore for SessionStore {
+	fn set_cookies(
+		&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, _url: &url::Url
+	) {
+		let mut changed = false;
+
+		if let Ok(mut cookies) = self.cookies.lock() {
+			for header in cookie_headers.filter_map(|h| h.to_str().ok()) {
+				// e.g. `session=abc123; Path=/; Max-Age=3600`
+				let mut parts = header.split(';');
+
+				let (name, value) = match parts.next().and_then(|c| c.split_once('=')) {
+					Some((name, value)) => (name.trim(), value.trim()),
+					None => continue,
+				};
+
+				let expired = parts.any(|attr|
+					attr.trim().eq_ignore_ascii_case("max-age=0")
+				);
+
+				if expired || value.is_empty() {
+					changed |= cookies.remove(name).is_some();
+				} else if cookies.get(name).map(String::as_str) != Some(value) {
+					cookies.insert(name.to_owned(), value.to_owned());
+					changed = true;
+				}
+			}
+		}
+
+		if changed {
+			self.save();
+		}
+	}
+
+	fn cookies(&self, _url: &url::Url) -> Option<HeaderValue> {
+		let cookies = self.cookies.lock().ok()?;
+
+		if cookies.is_empty() {
+			return None;
+		}
+
+		let header = cookies.iter()
+			.map(|(name, value)| format!("{}={}", name, value))
+			.collect::<Vec<String>>()
+			.join("; ");
+
+		HeaderValue::from_str(&header).ok()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use reqwest::cookie::CookieStore;
+
+	fn url() -> url::Url {
+		url::Url::parse("https://192.168.0.2:8741/requests").unwrap()
+	}
+
+	fn set(store: &SessionStore, headers: &[&'static str]) {
+		let headers: Vec<HeaderValue> = headers.iter()
+			.map(|h| HeaderValue::from_static(h))
+			.collect();
+
+		store.set_cookies(&mut headers.iter(), &url());
+	}
+
+	fn header(store: &SessionStore) -> Option<String> {
+		store.cookies(&url()).map(|h| h.to_str().unwrap().to_owned())
+	}
+
+	#[test]
+	fn parses_set_cookie_headers() {
+		let store = SessionStore::new(None);
+		assert!(store.is_empty());
+		assert_eq!(header(&store), None);
+
+		set(&store, &["session = abc123 ; Path=/; Max-Age=3600", "no-equals-sign"]);
+		assert_eq!(header(&store).as_deref(), Some("session=abc123"));
+
+		// replaced, then expired
+		set(&store, &["session=def456; HttpOnly"]);
+		assert_eq!(header(&store).as_deref(), Some("session=def456"));
+
+		set(&store, &["session=def456; max-age=0"]);
+		assert!(store.is_empty());
+
+		set(&store, &["session=abc", "other=1"]);
+		set(&store, &["other="]);
+		assert_eq!(header(&store).as_deref(), Some("session=abc"));
+
+		store.clear();
+		assert!(store.is_empty());
+	}
+
+	#[test]
+	fn persists_to_its_file() {
+		let path = std::env::temp_dir()
+			.join(format!("smserver-session-{}.json", uuid::Uuid::new_v4()));
+
+		let store = SessionStore::new(Some(path.to_owned()));
+		set(&store, &["session=abc123"]);
+
+		let reloaded = SessionStore::new(Some(path.to_owned()));
+		assert_eq!(header(&reloaded).as_deref(), Some("session=abc123"));
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+			assert_eq!(mode & 0o777, 0o600);
+		}
+
+		reloaded.clear();
+		assert!(SessionStore::new(Some(path.to_owned())).is_empty());
+
+		// a mangled file just means authenticating again
+		std::fs::write(&path, "not json").unwrap();
+		assert!(SessionStore::new(Some(path.to_owned())).is_empty());
+
+		std::fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,119 @@
+use std::{
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+use dashmap::DashMap;
+use futures_util::SinkExt;
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::Message;
+use crate::socket::{SocketResponse, SocketSink};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+	// the socket is being opened
+	Connecting,
+	// the host has responded recently
+	Connected,
+	// the host didn't respond to the last ping, but we haven't given up yet
+	Degraded,
+	// the socket was closed, or the host stopped responding for too long
+	Disconnected,
+}
+
+// everything that the receiver and the keepalive tasks share, so that they
+// can both tell when the host was last heard from and update the state
+#[derive(Clone)]
+pub struct ConnectionHealth {
+	pub state: Arc<watch::Sender<ConnectionState>>,
+	pub last_seen: Arc<Mutex<Instant>>,
+}
+
+impl ConnectionHealth {
+	pub fn new() -> (ConnectionHealth, watch::Receiver<ConnectionState>) {
+		let (sender, receiver) = watch::channel(ConnectionState::Connecting);
+
+		let health = ConnectionHealth {
+			state: Arc::new(sender),
+			last_seen: Arc::new(Mutex::new(Instant::now())),
+		};
+
+		(health, receiver)
+	}
+
+	// only notifies the watchers if the state actually changed
+	pub fn set_state(&self, new: ConnectionState) {
+		let changed = self.state.send_if_modified(|state| {
+			let changed = *state != new;
+			*state = new;
+			changed
+		});
+
+		if changed {
+			tracing::info!(state = ?new, "connection state changed");
+		}
+	}
+
+	// called whenever anything comes in through the socket
+	pub fn seen(&self) {
+		if let Ok(mut last) = self.last_seen.lock() {
+			*last = Instant::now();
+		}
+
+		if *self.state.borrow() == ConnectionState::Degraded {
+			self.set_state(ConnectionState::Connected);
+		}
+	}
+
+	pub fn since_seen(&self) -> Duration {
+		self.last_seen.lock()
+			.map(|last| last.elapsed())
+			.unwrap_or_default()
+	}
+
+	// marks the connection as dead and drops the senders for all the
+	// requests that are still waiting, so they return an error instead of hanging
+	pub fn disconnected(
+		&self, sock_msgs: &DashMap<String, tokio::sync::mpsc::UnboundedSender<SocketResponse>>
+	) {
+		self.set_state(ConnectionState::Disconnected);
+		sock_msgs.clear();
+	}
+}
+
+// sends a ping every `interval`, and marks the connection as degraded if the
+// host didn't respond to the last one, or disconnected if it hasn't responded
+// to anything for `timeout`.
+pub fn spawn_keepalive(
+	sink: SocketSink,
+	health: ConnectionHealth,
+	sock_msgs: Arc<DashMap<String, tokio::sync::mpsc::UnboundedSender<SocketResponse>>>,
+	interval: Duration,
+	timeout: Duration,
+) -> tokio::task::JoinHandle<()> {
+	tokio::spawn(async move {
+		loop {
+			tokio::time::sleep(interval).await;
+
+			if *health.state.borrow() == ConnectionState::Disconnected {
+				break;
+			}
+
+			let since = health.since_seen();
+
+			if since >= timeout {
+				tracing::warn!(secs = since.as_secs(), "host stopped responding, closing socket");
+				health.disconnected(&sock_msgs);
+				let _ = sink.lock().await.close().await;
+				break;
+			} else if since >= interval {
+				health.set_state(ConnectionState::Degraded);
+			}
+
+			if let Err(err) = sink.lock().await.send(Message::Ping(Vec::new())).await {
+				tracing::warn!(error = %err, "failed to send ping");
+				health.disconnected(&sock_msgs);
+				break;
+			}
+		}
+	})
+}
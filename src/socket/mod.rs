@@ -1,5 +1,11 @@
 pub use socket_handler::*;
 pub use socket_response::*;
+pub use keepalive::ConnectionState;
+pub use pending_reply::PendingReply;
+pub use notifications::Notifications;
 
 mod socket_handler;
+mod keepalive;
+mod pending_reply;
+mod notifications;
 pub mod socket_response;
@@ -0,0 +1,74 @@
+use tokio::sync::mpsc;
+use crate::socket::SocketResponse;
+
+// The notifications from the crossbeam_channel::Receiver for the sender that was
+// passed to `APIClient::new`, moved over to an async channel so that they can be
+// awaited. That receiver blocks, so it's read on its own thread, which stops as
+// soon as this is dropped instead of waiting for the next notification to come in.
+pub struct Notifications {
+	receiver: mpsc::UnboundedReceiver<SocketResponse>,
+	// never sent on; the thread stops once it's dropped
+	_stop: crossbeam_channel::Sender<()>,
+}
+
+impl Notifications {
+	pub fn new(notifications: crossbeam_channel::Receiver<SocketResponse>) -> Notifications {
+		let (sender, receiver) = mpsc::unbounded_channel();
+		let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+
+		std::thread::spawn(move || loop {
+			crossbeam_channel::select! {
+				recv(notifications) -> notif => match notif.map(|n| sender.send(n)) {
+					Ok(Ok(())) => (),
+					// the client or this was dropped
+					_ => break,
+				},
+				recv(stopped) -> _ => break,
+			}
+		});
+
+		Notifications {
+			receiver,
+			_stop: stop,
+		}
+	}
+
+	// the next notification, or None once the client that sends them is gone
+	pub async fn recv(&mut self) -> Option<SocketResponse> {
+		self.receiver.recv().await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::{Duration, Instant};
+	use crate::commands::APICommand;
+
+	#[test]
+	fn stops_reading_once_dropped() {
+		let (sender, receiver) = crossbeam_channel::unbounded();
+		let mut notifications = Notifications::new(receiver);
+
+		let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+		sender.send(SocketResponse {
+			id: String::new(),
+			last: true,
+			command: APICommand::Typing,
+			data: serde_json::Value::Null,
+		}).unwrap();
+
+		let notif = rt.block_on(notifications.recv()).unwrap();
+		assert!(matches!(notif.command, APICommand::Typing));
+
+		// the thread lets go of the receiver, without anything else being sent
+		drop(notifications);
+		let started = Instant::now();
+
+		while sender.send(notif.clone()).is_ok() {
+			assert!(started.elapsed() < Duration::from_secs(5), "the thread never stopped");
+			std::thread::sleep(Duration::from_millis(5));
+		}
+	}
+}
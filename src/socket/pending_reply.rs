@@ -0,0 +1,106 @@
+use std::{
+	sync::Arc,
+	time::Duration,
+};
+use dashmap::DashMap;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use crate::{
+	error::SDKError,
+	socket::SocketResponse,
+};
+
+// The responses to one command sent over the socket. It's registered in
+// `sock_msgs` before the command is sent, so that the response can't come in
+// before anybody's waiting for it, and it takes itself back out when it's
+// dropped, so that nothing's left behind if the caller gives up on it (e.g.
+// because it timed out or the future was cancelled).
+pub struct PendingReply {
+	pub id: String,
+	receiver: UnboundedReceiver<SocketResponse>,
+	sock_msgs: Arc<DashMap<String, UnboundedSender<SocketResponse>>>,
+	// how long to wait for each response before giving up
+	timeout: Duration,
+}
+
+impl PendingReply {
+	pub(crate) fn register(
+		id: String,
+		sock_msgs: Arc<DashMap<String, UnboundedSender<SocketResponse>>>,
+		timeout: Duration,
+	) -> PendingReply {
+		let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+		sock_msgs.insert(id.to_owned(), sender);
+
+		PendingReply {
+			id,
+			receiver,
+			sock_msgs,
+			timeout,
+		}
+	}
+
+	// waits for the next response to this command. Commands that return data
+	// send a few of them, one for each chunk.
+	pub async fn recv(&mut self) -> anyhow::Result<SocketResponse> {
+		match tokio::time::timeout(self.timeout, self.receiver.recv()).await {
+			Ok(Some(msg)) => Ok(msg),
+			// the socket closed, or was reconnected, while we were waiting
+			Ok(None) => Err(SDKError::MangledReceive.into()),
+			Err(_) => {
+				tracing::debug!(id = %self.id, "timed out waiting for response");
+				Err(SDKError::TimedOut.into())
+			}
+		}
+	}
+}
+
+impl Drop for PendingReply {
+	fn drop(&mut self) {
+		self.sock_msgs.remove(&self.id);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::commands::APICommand;
+
+	#[test]
+	fn cleans_up_after_itself() {
+		let rt = tokio::runtime::Builder::new_current_thread()
+			.enable_time()
+			.build()
+			.unwrap();
+
+		let sock_msgs = Arc::new(DashMap::new());
+		let timeout = Duration::from_millis(20);
+
+		rt.block_on(async {
+			let mut reply = PendingReply::register("a".to_owned(), sock_msgs.clone(), timeout);
+			assert!(sock_msgs.contains_key("a"));
+
+			// e.g. what the socket receiver does when the response comes in
+			sock_msgs.get("a").unwrap().send(SocketResponse {
+				id: "a".to_owned(),
+				last: true,
+				command: APICommand::GetName,
+				data: serde_json::json!("Jane"),
+			}).unwrap();
+
+			assert_eq!(reply.recv().await.unwrap().data, "Jane");
+
+			let err = reply.recv().await.unwrap_err();
+			assert!(matches!(err.downcast_ref(), Some(SDKError::TimedOut)));
+
+			drop(reply);
+			assert!(sock_msgs.is_empty());
+
+			// the socket closed, so the sender is gone
+			let mut reply = PendingReply::register("b".to_owned(), sock_msgs.clone(), timeout);
+			sock_msgs.clear();
+
+			let err = reply.recv().await.unwrap_err();
+			assert!(matches!(err.downcast_ref(), Some(SDKError::MangledReceive)));
+		});
+	}
+}
@@ -1,5 +1,9 @@
-use std::sync::Arc;
+use std::{
+	sync::Arc,
+	time::Duration,
+};
 use dashmap::DashMap;
+use tokio::sync::watch;
 use tokio_tungstenite::{
 	WebSocketStream,
 	tungstenite::{
@@ -23,43 +27,160 @@ use serde_json::{
 };
 use crate::{
 	commands::*,
-	socket::SocketResponse,
+	socket::{
+		PendingReply,
+		SocketResponse,
+		keepalive::*,
+	},
 };
 
+// the sink is shared with the keepalive task, which sends pings through it
+pub type SocketSink = Arc<tokio::sync::Mutex<
+	SplitSink<WebSocketStream<TlsStream<TcpStream>>, Message>
+>>;
+
 pub struct SocketHandler {
-	pub sender: SplitSink<WebSocketStream<TlsStream<TcpStream>>, Message>,
+	pub sender: SocketSink,
+	pub state: watch::Receiver<ConnectionState>,
+	url: url::Url,
+	channel_sender: crossbeam_channel::Sender<SocketResponse>,
+	sock_msgs: Arc<DashMap<String, tokio::sync::mpsc::UnboundedSender<SocketResponse>>>,
+	health: ConnectionHealth,
+	// how often to ping the host (if at all), and how long it can go without
+	// responding before we decide the connection is dead
+	ping_interval: Option<Duration>,
+	ping_timeout: Duration,
+	// how long to wait for the response to a command
+	reply_timeout: Duration,
+	// the receiver & keepalive tasks for the current connection
+	tasks: Vec<tokio::task::JoinHandle<()>>,
 }
 
 impl SocketHandler {
 	pub async fn new(
 		url: url::Url,
 		channel_sender: crossbeam_channel::Sender<SocketResponse>,
-		sock_msgs: Arc<DashMap<String, crossbeam_channel::Sender<SocketResponse>>>
+		sock_msgs: Arc<DashMap<String, tokio::sync::mpsc::UnboundedSender<SocketResponse>>>,
+		ping_interval: Option<Duration>,
+		ping_timeout: Duration,
+		reply_timeout: Duration,
 	) -> anyhow::Result<SocketHandler> {
-		let sock_res = SocketHandler::get_self_signed_socket(url).await?;
+		let (health, state) = ConnectionHealth::new();
+		let (sender, receiver) = SocketHandler::connect(&url, &health).await?;
 
-		let (sender, receiver) = sock_res.split();
+		let mut handler = SocketHandler {
+			sender,
+			state,
+			url,
+			channel_sender,
+			sock_msgs,
+			health,
+			ping_interval,
+			ping_timeout,
+			reply_timeout,
+			tasks: Vec::new(),
+		};
 
-		SocketHandler::spawn_receiver(receiver, channel_sender, sock_msgs);
+		handler.spawn_tasks(receiver);
 
-		Ok(SocketHandler { sender })
+		Ok(handler)
+	}
+
+	// opens a new connection, e.g. once the `state` says we've been disconnected.
+	// Any requests that were still waiting on the old connection return an error.
+	pub async fn reconnect(&mut self) -> anyhow::Result<()> {
+		for task in self.tasks.drain(..) {
+			task.abort();
+		}
+
+		let _ = self.sender.lock().await.close().await;
+		self.sock_msgs.clear();
+
+		let (sender, receiver) = SocketHandler::connect(&self.url, &self.health).await?;
+
+		self.sender = sender;
+		self.spawn_tasks(receiver);
+
+		Ok(())
+	}
+
+	async fn connect(
+		url: &url::Url, health: &ConnectionHealth
+	) -> anyhow::Result<(SocketSink, SplitStream<WebSocketStream<TlsStream<TcpStream>>>)> {
+		health.set_state(ConnectionState::Connecting);
+
+		tracing::info!(host = ?url.host_str(), "connecting to socket");
+
+		match SocketHandler::get_self_signed_socket(url.to_owned()).await {
+			Ok(sock) => {
+				health.seen();
+				health.set_state(ConnectionState::Connected);
+
+				let (sender, receiver) = sock.split();
+
+				Ok((Arc::new(tokio::sync::Mutex::new(sender)), receiver))
+			},
+			Err(err) => {
+				tracing::warn!(error = %err, "failed to connect to socket");
+				health.set_state(ConnectionState::Disconnected);
+				Err(err)
+			}
+		}
+	}
+
+	fn spawn_tasks(
+		&mut self, receiver: SplitStream<WebSocketStream<TlsStream<TcpStream>>>
+	) {
+		self.tasks.push(SocketHandler::spawn_receiver(
+			receiver,
+			self.channel_sender.clone(),
+			self.sock_msgs.clone(),
+			self.health.clone(),
+		));
+
+		if let Some(interval) = self.ping_interval {
+			self.tasks.push(spawn_keepalive(
+				self.sender.clone(),
+				self.health.clone(),
+				self.sock_msgs.clone(),
+				interval,
+				self.ping_timeout,
+			));
+		}
 	}
 
 	pub fn spawn_receiver(
 		receiver: SplitStream<WebSocketStream<TlsStream<TcpStream>>>,
 		channel_sender: crossbeam_channel::Sender<SocketResponse>,
-		sock_msgs: Arc<DashMap<String, crossbeam_channel::Sender<SocketResponse>>>
-	) {
+		sock_msgs: Arc<DashMap<String, tokio::sync::mpsc::UnboundedSender<SocketResponse>>>,
+		health: ConnectionHealth,
+	) -> tokio::task::JoinHandle<()> {
 		tokio::spawn(async move {
 			let mut rec = receiver;
 
 			while let Some(msg_res) = rec.next().await {
+				// anything at all (including pongs) means the host is still there
+				if msg_res.is_ok() {
+					health.seen();
+				}
+
 				let res: SocketResponse = match msg_res {
 					Ok(Message::Text(txt)) => match serde_json::from_str(&txt) {
 						Ok(res) => res,
-						_ => continue,
+						Err(err) => {
+							tracing::warn!(error = %err, len = txt.len(), "dropping unparsable socket frame");
+							continue;
+						}
+					},
+					Ok(Message::Binary(bin)) => {
+						tracing::warn!(len = bin.len(), "dropping binary socket frame");
+						continue;
 					},
-					_ => continue,
+					Ok(_) => continue,
+					Err(err) => {
+						tracing::warn!(error = %err, "failed to receive from socket");
+						continue;
+					}
 				};
 
 				let id = res.id.to_owned();
@@ -67,14 +188,18 @@ impl SocketHandler {
 				let should_remove = if let Some(id_send) = sock_msgs.get(&id) {
 					let last = res.last;
 
-					if let Err(_err) = id_send.send(res) {
-						// should probably do some error handling?
+					tracing::trace!(id = %id, last, "received response");
+
+					if id_send.send(res).is_err() {
+						tracing::debug!(id = %id, "dropping response, nobody is waiting for it anymore");
 					}
 
 					last
 				} else {
-					if let Err(_err) = channel_sender.send(res) {
-						// should also handle here as well
+					tracing::debug!(command = %res.command.command_string(), "received notification");
+
+					if channel_sender.send(res).is_err() {
+						tracing::warn!("dropping notification, the notification receiver was dropped");
 					}
 
 					false
@@ -87,7 +212,11 @@ impl SocketHandler {
 					sock_msgs.remove(&id);
 				}
 			}
-		});
+
+			// the stream only ends once the connection is closed
+			tracing::info!("socket closed");
+			health.disconnected(&sock_msgs);
+		})
 	}
 
 	pub async fn get_self_signed_socket(
@@ -126,20 +255,52 @@ impl SocketHandler {
 	// custom `Command` derive macro, which is run on the enum `APICommand`
 
 	pub async fn send_command(
-		&mut self, cmd: APICommand, params: Value
+		&self, cmd: APICommand, params: Value
 	) -> Result<String, Error> {
-		// this returns the `id` that it generates, so that it can
-		// later be used to grab the response when it comes back in
+		// this returns the `id` that it generates, in case the caller wants to
+		// match it up with something. To wait for the response, use `request`.
 		let id = uuid::Uuid::new_v4().to_string();
 
+		self.send_with_id(&id, cmd, params).await?;
+
+		Ok(id)
+	}
+
+	// sends a command that the host will respond to, and returns what to wait on
+	// for the response. If the command couldn't be sent, nothing's left waiting.
+	pub async fn request(
+		&self, cmd: APICommand, params: Value
+	) -> Result<PendingReply, Error> {
+		let reply = PendingReply::register(
+			uuid::Uuid::new_v4().to_string(), self.sock_msgs.clone(), self.reply_timeout
+		);
+
+		self.send_with_id(&reply.id, cmd, params).await?;
+
+		Ok(reply)
+	}
+
+	async fn send_with_id(
+		&self, id: &str, cmd: APICommand, params: Value
+	) -> Result<(), Error> {
+		let command = cmd.command_string();
+
+		tracing::debug!(id = %id, command = %command, "sending command");
+
 		let payload = json!({
 			"id": id,
-			"command": cmd.command_string(),
+			"command": command,
 			"params": params
 		});
 
-		self.sender.send(Message::Text(payload.to_string())).await?;
+		self.sender.lock().await.send(Message::Text(payload.to_string())).await
+	}
+}
 
-		Ok(id)
+impl Drop for SocketHandler {
+	fn drop(&mut self) {
+		for task in self.tasks.iter() {
+			task.abort();
+		}
 	}
 }
@@ -0,0 +1,277 @@
+use std::{
+	collections::HashMap,
+	future::Future,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+use futures_util::Stream;
+use tokio::sync::{broadcast, mpsc};
+use crate::{
+	api::APIClient,
+	commands::{APICommand, TypingNotification},
+	dispatch::{NotificationHandler, Shutdown},
+	socket::SocketResponse,
+};
+
+enum Signal {
+	Keystroke,
+	Stop,
+}
+
+// Keeps the typing indicator showing in a chat while the user is composing a
+// message. Call `keystroke` whenever they type; the indicator is turned on with
+// the first one, kept alive while they keep typing, and turned off once they
+// haven't typed for a while (or `stop` is called, or this is dropped), so that
+// it's never left on by accident. Keystrokes in between don't send anything.
+//
+// Has to be created inside a tokio runtime, since it sends the indicators from
+// its own task.
+pub struct TypingIndicator {
+	signals: mpsc::UnboundedSender<Signal>,
+}
+
+impl TypingIndicator {
+	pub fn new(client: Arc<APIClient>, chat: impl Into<String>) -> TypingIndicator {
+		TypingIndicator::with_timings(client, chat, Duration::from_secs(5), Duration::from_secs(30))
+	}
+
+	// `idle` is how long after the last keystroke the indicator is turned off,
+	// and `refresh` is how often it's sent again while the user keeps typing, so
+	// that the other side doesn't time it out
+	pub fn with_timings(
+		client: Arc<APIClient>, chat: impl Into<String>, idle: Duration, refresh: Duration
+	) -> TypingIndicator {
+		let (signals, receiver) = mpsc::unbounded_channel();
+		tokio::spawn(indicate(client, chat.into(), receiver, idle, refresh));
+
+		TypingIndicator { signals }
+	}
+
+	pub fn keystroke(&self) {
+		let _ = self.signals.send(Signal::Keystroke);
+	}
+
+	// turns the indicator off right away, e.g. when the message is sent
+	pub fn stop(&self) {
+		let _ = self.signals.send(Signal::Stop);
+	}
+}
+
+async fn indicate(
+	client: Arc<APIClient>,
+	chat: String,
+	mut signals: mpsc::UnboundedReceiver<Signal>,
+	idle: Duration,
+	refresh: Duration,
+) {
+	let send = |active: bool| {
+		let (client, chat) = (client.clone(), chat.to_owned());
+		async move {
+			if let Err(err) = client.send_typing(&chat, active).await {
+				tracing::warn!(%chat, active, error = %err, "couldn't send typing indicator");
+			}
+		}
+	};
+
+	// when the last keystroke and the last `true` were, while the indicator is on
+	let mut active: Option<(Instant, Instant)> = None;
+
+	loop {
+		let signal = match active {
+			Some((last_key, _)) => {
+				let left = idle.saturating_sub(last_key.elapsed());
+
+				match tokio::time::timeout(left, signals.recv()).await {
+					Ok(signal) => signal,
+					Err(_) => {
+						send(false).await;
+						active = None;
+						continue;
+					},
+				}
+			},
+			None => signals.recv().await,
+		};
+
+		match (signal, active) {
+			(Some(Signal::Keystroke), None) => {
+				send(true).await;
+				active = Some((Instant::now(), Instant::now()));
+			},
+			(Some(Signal::Keystroke), Some((_, last_sent))) => {
+				let last_sent = match last_sent.elapsed() >= refresh {
+					true => {
+						send(true).await;
+						Instant::now()
+					},
+					false => last_sent,
+				};
+
+				active = Some((Instant::now(), last_sent));
+			},
+			(Some(Signal::Stop), Some(_)) => {
+				send(false).await;
+				active = None;
+			},
+			(Some(Signal::Stop), None) => (),
+			// the `TypingIndicator` was dropped
+			(None, active) => {
+				if active.is_some() {
+					send(false).await;
+				}
+
+				return;
+			},
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypingEvent {
+	pub chat: String,
+	pub active: bool,
+}
+
+struct TrackerInner {
+	// when each chat that's typing last said it was
+	chats: Mutex<HashMap<String, Instant>>,
+	events: broadcast::Sender<TypingEvent>,
+	expiry: Duration,
+}
+
+// Tracks who's typing in which chats, from the `Typing` notifications. The host
+// doesn't always send the notification for when someone stops typing (e.g. if
+// they close the app), so chats are also considered done typing after a while
+// without hearing from them.
+//
+// It can be cloned to share it between tasks.
+#[derive(Clone)]
+pub struct TypingTracker {
+	inner: Arc<TrackerInner>,
+}
+
+impl Default for TypingTracker {
+	fn default() -> TypingTracker {
+		TypingTracker::new()
+	}
+}
+
+impl TypingTracker {
+	pub fn new() -> TypingTracker {
+		TypingTracker::with_expiry(Duration::from_secs(60))
+	}
+
+	// how long a chat is considered typing after the last notification that said it was
+	pub fn with_expiry(expiry: Duration) -> TypingTracker {
+		let (events, _) = broadcast::channel(256);
+
+		TypingTracker {
+			inner: Arc::new(TrackerInner {
+				chats: Mutex::new(HashMap::new()),
+				events,
+				expiry,
+			})
+		}
+	}
+
+	pub fn update(&self, typing: TypingNotification) {
+		self.expire();
+
+		let changed = match self.inner.chats.lock() {
+			Ok(mut chats) => match typing.active {
+				true => chats.insert(typing.chat.to_owned(), Instant::now()).is_none(),
+				false => chats.remove(&typing.chat).is_some(),
+			},
+			Err(_) => return,
+		};
+
+		if changed {
+			let _ = self.inner.events.send(TypingEvent { chat: typing.chat, active: typing.active });
+		}
+	}
+
+	pub fn is_typing(&self, chat: &str) -> bool {
+		self.inner.chats.lock()
+			.map(|chats| chats.get(chat).map(|at| at.elapsed() < self.inner.expiry).unwrap_or(false))
+			.unwrap_or(false)
+	}
+
+	// the chats that someone is typing in right now
+	pub fn typing_chats(&self) -> Vec<String> {
+		self.inner.chats.lock()
+			.map(|chats| chats.iter()
+				.filter(|(_, at)| at.elapsed() < self.inner.expiry)
+				.map(|(chat, _)| chat.to_owned())
+				.collect())
+			.unwrap_or_default()
+	}
+
+	// Every time a chat starts or stops typing. Chats that time out only show up
+	// here once `expire` notices them, which `run` and `update` both do.
+	// If the stream falls too far behind, the events that it missed are skipped.
+	pub fn events(&self) -> impl Stream<Item = TypingEvent> {
+		futures_util::stream::unfold(self.inner.events.subscribe(), |mut receiver| async move {
+			loop {
+				match receiver.recv().await {
+					Ok(event) => return Some((event, receiver)),
+					Err(broadcast::error::RecvError::Lagged(_)) => continue,
+					Err(broadcast::error::RecvError::Closed) => return None,
+				}
+			}
+		})
+	}
+
+	// forgets the chats that haven't said they were typing in a while, and sends
+	// an event for each one
+	pub fn expire(&self) {
+		let expired: Vec<String> = match self.inner.chats.lock() {
+			Ok(mut chats) => {
+				let expired: Vec<String> = chats.iter()
+					.filter(|(_, at)| at.elapsed() >= self.inner.expiry)
+					.map(|(chat, _)| chat.to_owned())
+					.collect();
+
+				for chat in expired.iter() {
+					chats.remove(chat);
+				}
+
+				expired
+			},
+			Err(_) => return,
+		};
+
+		for chat in expired {
+			tracing::debug!(%chat, "typing state expired");
+			let _ = self.inner.events.send(TypingEvent { chat, active: false });
+		}
+	}
+
+	// expires stale chats as soon as they go stale, until `shutdown` finishes
+	pub async fn run(&self, shutdown: impl Future<Output = ()>) {
+		let mut shutdown = Shutdown::new(shutdown);
+
+		loop {
+			self.expire();
+
+			let wait = self.inner.chats.lock().ok()
+				.and_then(|chats| chats.values().min().map(|at| self.inner.expiry.saturating_sub(at.elapsed())))
+				.unwrap_or(self.inner.expiry);
+
+			if shutdown.or(tokio::time::sleep(wait)).await.is_none() {
+				break;
+			}
+		}
+	}
+}
+
+// updates the state from the `Typing` notifications
+impl NotificationHandler for TypingTracker {
+	fn handles(&self, command: &APICommand) -> bool {
+		matches!(command, APICommand::Typing)
+	}
+
+	fn notify(&self, notif: SocketResponse) -> anyhow::Result<()> {
+		self.update(notif.typing_data()?);
+		Ok(())
+	}
+}